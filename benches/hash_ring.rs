@@ -0,0 +1,67 @@
+// Lookups and membership changes on rings with thousands of virtual nodes
+//
+// run with: cargo bench --bench hash_ring
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use pandas_pouch::hash_ring::{HashRing, NodeInfo};
+
+const NODES: u16 = 100;
+const LOOKUPS: usize = 1_000;
+
+fn ring(virtual_nodes: isize) -> HashRing<NodeInfo> {
+    HashRing::new((0..NODES).map(|i| NodeInfo::new("10.0.0.1", 7000 + i)).collect(), virtual_nodes)
+}
+
+fn lookups(c: &mut Criterion) {
+    let keys: Vec<String> = (0..LOOKUPS).map(|i| format!("key{}", i)).collect();
+    let mut group = c.benchmark_group("hash_ring_lookup");
+    group.throughput(Throughput::Elements(LOOKUPS as u64));
+    for virtual_nodes in [10, 100, 1000] {
+        let ring = ring(virtual_nodes);
+        let size = ring.sorted_keys.len();
+        group.bench_with_input(BenchmarkId::new("get_node", size), &ring, |b, ring| {
+            b.iter(|| {
+                for key in &keys {
+                    criterion::black_box(ring.get_node(key));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("get_nodes_3", size), &ring, |b, ring| {
+            b.iter(|| {
+                for key in &keys {
+                    criterion::black_box(ring.get_nodes(key, 3));
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn membership(c: &mut Criterion) {
+    let joining = NodeInfo::new("10.0.0.2", 7000);
+    let mut group = c.benchmark_group("hash_ring_membership");
+    for virtual_nodes in [10, 100, 1000] {
+        // the rings are returned, so that dropping them is not measured
+        let ring = ring(virtual_nodes);
+        let size = ring.sorted_keys.len();
+        group.bench_with_input(BenchmarkId::new("add_node", size), &ring, |b, ring| {
+            b.iter_batched(|| ring.clone(), |mut ring| {
+                ring.add_node(&joining);
+                ring
+            }, BatchSize::LargeInput)
+        });
+
+        let mut joined = ring.clone();
+        joined.add_node(&joining);
+        group.bench_with_input(BenchmarkId::new("remove_node", size), &joined, |b, ring| {
+            b.iter_batched(|| ring.clone(), |mut ring| {
+                ring.remove_node(&joining);
+                ring
+            }, BatchSize::LargeInput)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookups, membership);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+// Throughput of the cache under concurrent access, sharded against a single segment
+//
+// run with: cargo bench --bench lru_concurrent
+
+use std::thread;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use pandas_pouch::eviction::EvictionPolicyKind;
+use pandas_pouch::lru::LRUCache;
+
+const CAPACITY: usize = 10_000;
+const OPS_PER_THREAD: usize = 10_000;
+const KEY_SPACE: u64 = 20_000;
+
+// a mix of 80% reads and 20% writes over a key space twice the capacity
+fn run(cache: &LRUCache<u64, u64>, threads: usize) {
+    thread::scope(|scope| {
+        for t in 0..threads {
+            scope.spawn(move || {
+                let mut key = t as u64 + 1;
+                for i in 0..OPS_PER_THREAD {
+                    // xorshift, cheap and good enough to spread the keys
+                    key ^= key << 13;
+                    key ^= key >> 7;
+                    key ^= key << 17;
+                    let k = key % KEY_SPACE;
+                    if i % 5 == 0 {
+                        cache.put(k, k);
+                    } else {
+                        criterion::black_box(cache.get(&k));
+                    }
+                }
+            });
+        }
+    });
+}
+
+fn concurrent(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lru_concurrent");
+    for threads in [1, 2, 4, 8] {
+        group.throughput(Throughput::Elements((threads * OPS_PER_THREAD) as u64));
+
+        let sharded = LRUCache::with_segments(CAPACITY, None, 64, EvictionPolicyKind::Lru);
+        group.bench_with_input(BenchmarkId::new("sharded", threads), &threads, |b, &threads| {
+            b.iter(|| run(&sharded, threads))
+        });
+
+        let single = LRUCache::with_segments(CAPACITY, None, 1, EvictionPolicyKind::Lru);
+        group.bench_with_input(BenchmarkId::new("single_segment", threads), &threads, |b, &threads| {
+            b.iter(|| run(&single, threads))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent);
+criterion_main!(benches);
@@ -3,7 +3,7 @@ use std::thread;
 use std::time::Duration;
 
 fn main() {
-    let mut cache = LRUCache::new(2, Some(Duration::from_secs(2)));
+    let cache = LRUCache::new(2, Some(Duration::from_secs(2)));
 
     println!("LRUCache created with capacity 2 and expiry of 2 seconds");
 
@@ -1,28 +1,51 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use tonic::transport::Channel;
+use tonic::Status;
 
-use pandas_pouch::pandas_pouch_cache_service_client::PandasPouchCacheServiceClient;
-use pandas_pouch::{GetRequest, PutRequest};
-
-pub mod pandas_pouch {
-    tonic::include_proto!("pandas_pouch");
-}
+use crate::cluster::{PeerClient, DEFAULT_CONNECT_TIMEOUT, DEFAULT_REQUEST_TIMEOUT};
+use crate::codec::{Codec, CodecError, Json};
+use crate::error::PouchError;
+use crate::hash_ring::{HashRing, NodeInfo};
+use crate::pandas_pouch::pandas_pouch_cache_service_client::PandasPouchCacheServiceClient;
+use crate::pandas_pouch::{
+    DeleteRequest, GetRequest, KeyValuePair, ListNodesRequest, MultiGetRequest, MultiPutRequest, PersistenceMode,
+    PutRequest, StatsRequest, StatsResponse,
+};
 
 #[allow(dead_code)]
 pub struct Client {
     client: PandasPouchCacheServiceClient<Channel>,
+    persistence: PersistenceMode,
 }
 
 #[allow(dead_code)]
 impl Client {
-    pub async fn new(host: &str, port: u16) -> Result<Self, Box<dyn std::error::Error>> {
+    pub async fn new(host: &str, port: u16) -> Result<Self, PouchError> {
         let addr = format!("http://{}:{}", host, port);
-        let channel = Channel::from_shared(addr)?.connect().await?;
+        let channel = Channel::from_shared(addr).map_err(|e| PouchError::Transport(e.to_string()))?.connect().await?;
         let client = PandasPouchCacheServiceClient::new(channel);
-        Ok(Client { client })
+        Ok(Client { client, persistence: PersistenceMode::PersistenceDefault })
+    }
+
+    // overrides the persistence mode of the server for the requests of this client
+    pub fn with_persistence(mut self, persistence: PersistenceMode) -> Self {
+        self.persistence = persistence;
+        self
     }
 
-    pub async fn get(&mut self, key: String) -> Result<Option<String>, Box<dyn std::error::Error>> {
-        let request = tonic::Request::new(GetRequest { key });
+    // gets a value as text, failing when it is not valid UTF-8
+    pub async fn get(&mut self, key: String) -> Result<Option<String>, PouchError> {
+        text(self.get_bytes(key).await?)
+    }
+
+    pub async fn get_bytes(&mut self, key: String) -> Result<Option<Bytes>, PouchError> {
+        let request = tonic::Request::new(GetRequest { key, persistence: self.persistence.into() });
         let response = self.client.get(request).await?.into_inner();
         if response.found {
             Ok(Some(response.value))
@@ -31,9 +54,387 @@ impl Client {
         }
     }
 
-    pub async fn put(&mut self, key: String, value: String) -> Result<bool, Box<dyn std::error::Error>> {
-        let request = tonic::Request::new(PutRequest { key, value });
+    pub async fn put(&mut self, key: String, value: impl Into<Bytes>) -> Result<bool, PouchError> {
+        self.put_with_ttl(key, value, None).await
+    }
+
+    // puts a value that expires after the ttl, instead of the default expiry of the cache
+    pub async fn put_with_ttl(&mut self, key: String, value: impl Into<Bytes>, ttl: Option<Duration>) -> Result<bool, PouchError> {
+        let ttl_ms = ttl.map(|ttl| ttl.as_millis() as u64);
+        let request = tonic::Request::new(PutRequest { key, value: value.into(), ttl_ms, expires_at_ms: None, persistence: self.persistence.into() });
         let response = self.client.put(request).await?.into_inner();
         Ok(response.success)
     }
+
+    // returns whether the key existed
+    pub async fn delete(&mut self, key: String) -> Result<bool, PouchError> {
+        let request = tonic::Request::new(DeleteRequest { key, persistence: self.persistence.into() });
+        let response = self.client.delete(request).await?.into_inner();
+        Ok(response.found)
+    }
+
+    // gets many keys in a single request as text, the values are in the order of the keys
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>, PouchError> {
+        self.get_many_bytes(keys).await?.into_iter().map(text).collect()
+    }
+
+    pub async fn get_many_bytes(&mut self, keys: Vec<String>) -> Result<Vec<Option<Bytes>>, PouchError> {
+        let request = tonic::Request::new(MultiGetRequest { keys, persistence: self.persistence.into() });
+        let response = self.client.multi_get(request).await?.into_inner();
+        Ok(response.values.into_iter().map(|value| value.found.then_some(value.value)).collect())
+    }
+
+    pub async fn put_many(&mut self, entries: Vec<(String, impl Into<Bytes>)>) -> Result<bool, PouchError> {
+        self.put_many_with_ttl(entries, None).await
+    }
+
+    // puts many values in a single request, all of them expiring after the ttl
+    pub async fn put_many_with_ttl(&mut self, entries: Vec<(String, impl Into<Bytes>)>, ttl: Option<Duration>) -> Result<bool, PouchError> {
+        let request = tonic::Request::new(MultiPutRequest { entries: batch(entries, ttl), persistence: self.persistence.into() });
+        let response = self.client.multi_put(request).await?.into_inner();
+        Ok(response.success)
+    }
+
+    // counters and size of the cache of the node this client is connected to
+    pub async fn stats(&mut self) -> Result<StatsResponse, PouchError> {
+        let request = tonic::Request::new(StatsRequest {});
+        Ok(self.client.stats(request).await?.into_inner())
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(&mut self, key: String) -> Result<Option<T>, PouchError> {
+        self.get_with(&Json, key).await
+    }
+
+    pub async fn put_json<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<bool, PouchError> {
+        self.put_with(&Json, key, value, None).await
+    }
+
+    // gets a value decoded with the codec
+    pub async fn get_with<T: DeserializeOwned>(&mut self, codec: &impl Codec, key: String) -> Result<Option<T>, PouchError> {
+        match self.get_bytes(key).await? {
+            Some(value) => Ok(Some(codec.decode(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // puts a value encoded with the codec, expiring after the ttl if there is one
+    pub async fn put_with<T: Serialize + ?Sized>(&mut self, codec: &impl Codec, key: String, value: &T, ttl: Option<Duration>) -> Result<bool, PouchError> {
+        let value = codec.encode(value)?;
+        self.put_with_ttl(key, value, ttl).await
+    }
+
+    // a client encoding and decoding every value with the codec
+    pub fn with_codec<C: Codec>(self, codec: C) -> TypedClient<C> {
+        TypedClient { client: self, codec }
+    }
+}
+
+// a client whose values are typed, encoded with its codec, JSON unless another one is given
+pub struct TypedClient<C = Json> {
+    client: Client,
+    codec: C,
+}
+
+impl<C: Codec> TypedClient<C> {
+    pub async fn get<T: DeserializeOwned>(&mut self, key: String) -> Result<Option<T>, PouchError> {
+        self.client.get_with(&self.codec, key).await
+    }
+
+    pub async fn put<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<bool, PouchError> {
+        self.client.put_with(&self.codec, key, value, None).await
+    }
+
+    // puts a value that expires after the ttl, instead of the default expiry of the cache
+    pub async fn put_with_ttl<T: Serialize + ?Sized>(&mut self, key: String, value: &T, ttl: Option<Duration>) -> Result<bool, PouchError> {
+        self.client.put_with(&self.codec, key, value, ttl).await
+    }
+
+    // returns whether the key existed
+    pub async fn delete(&mut self, key: String) -> Result<bool, PouchError> {
+        self.client.delete(key).await
+    }
+
+    // the untyped client, for the requests that do not carry values
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+}
+
+fn batch(entries: Vec<(String, impl Into<Bytes>)>, ttl: Option<Duration>) -> Vec<KeyValuePair> {
+    let ttl_ms = ttl.map(|ttl| ttl.as_millis() as u64);
+    entries.into_iter().map(|(key, value)| KeyValuePair { key, value: value.into(), ttl_ms }).collect()
+}
+
+fn text(value: Option<Bytes>) -> Result<Option<String>, PouchError> {
+    value.map(|value| String::from_utf8(value.into()))
+        .transpose()
+        .map_err(|e| PouchError::Codec(CodecError::Decode(e.into())))
+}
+
+// a client routing every key straight to its owner, instead of relying on the node it is connected to to forward it.
+// the ring is built from the topology listed by the cluster, and rebuilt when it is stale or a node could not be
+// reached, in which case the request is retried on the next replica of the key
+pub struct ClusterClient {
+    seeds: Vec<NodeInfo>,
+    ring: HashRing<NodeInfo>,
+    replication_factor: usize,
+    clients: HashMap<NodeInfo, PeerClient>,
+    persistence: PersistenceMode,
+    refresh_interval: Option<Duration>,
+    refreshed_at: Instant,
+    stale: bool,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+}
+
+impl ClusterClient {
+    // lists the topology from the first seed that answers
+    pub async fn connect(seeds: Vec<NodeInfo>) -> Result<Self, PouchError> {
+        let mut client = ClusterClient {
+            seeds,
+            ring: HashRing::new(Vec::new(), 0),
+            replication_factor: 1,
+            clients: HashMap::new(),
+            persistence: PersistenceMode::PersistenceDefault,
+            refresh_interval: None,
+            refreshed_at: Instant::now(),
+            stale: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        };
+        client.refresh().await?;
+        Ok(client)
+    }
+
+    // overrides the persistence mode of the servers for the requests of this client
+    pub fn with_persistence(mut self, persistence: PersistenceMode) -> Self {
+        self.persistence = persistence;
+        self
+    }
+
+    // lists the topology again before a request once the interval has passed since the last refresh
+    pub fn with_refresh_interval(mut self, interval: Duration) -> Self {
+        self.refresh_interval = Some(interval);
+        self
+    }
+
+    // bounds the time to connect to a node and the time a request to it may take, a node that does not answer in
+    // time is skipped for the next replica of the key. the channels already open are opened again with them
+    pub fn with_timeouts(mut self, connect_timeout: Duration, request_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self.request_timeout = request_timeout;
+        for node in self.nodes() {
+            self.clients.remove(&node);
+            self.client(&node).expect("the address of a node was valid when it was first connected");
+        }
+        self
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.clients.keys().cloned().collect();
+        nodes.sort();
+        nodes
+    }
+
+    // the nodes a key is sent to, its owner first
+    pub fn replicas(&self, key: &str) -> Vec<NodeInfo> {
+        self.ring.get_nodes(key, self.replication_factor).into_iter().cloned().collect()
+    }
+
+    // rebuilds the ring from the topology listed by the first node that answers, the known members first and then
+    // the seeds
+    pub async fn refresh(&mut self) -> Result<(), PouchError> {
+        let mut candidates = self.nodes();
+        candidates.extend(self.seeds.iter().filter(|seed| !self.clients.contains_key(*seed)).cloned());
+
+        let mut last_error = PouchError::NodeUnavailable("No seed node to list the cluster from".to_string());
+        for node in candidates {
+            let mut client = match self.client(&node) {
+                Ok(client) => client,
+                Err(e) => {
+                    last_error = e;
+                    continue;
+                },
+            };
+            let topology = match client.list_nodes(tonic::Request::new(ListNodesRequest {})).await {
+                Ok(response) => response.into_inner(),
+                Err(status) => {
+                    warn!("Could not list the cluster from {}: {}", node, status.message());
+                    last_error = status.into();
+                    continue;
+                },
+            };
+
+            let nodes = topology.nodes.into_iter().map(NodeInfo::try_from).collect::<Result<Vec<_>, Status>>()?;
+            debug!("Cluster listed by {}: {:?}", node, nodes);
+            self.clients.retain(|member, _| nodes.contains(member));
+            for node in &nodes {
+                self.client(node)?;
+            }
+            self.ring = HashRing::new(nodes, topology.virtual_nodes as isize);
+            self.replication_factor = (topology.replication_factor as usize).max(1);
+            self.refreshed_at = Instant::now();
+            self.stale = false;
+            return Ok(());
+        }
+        Err(last_error)
+    }
+
+    // gets a value as text, failing when it is not valid UTF-8
+    pub async fn get(&mut self, key: String) -> Result<Option<String>, PouchError> {
+        text(self.get_bytes(key).await?)
+    }
+
+    pub async fn get_bytes(&mut self, key: String) -> Result<Option<Bytes>, PouchError> {
+        let request = GetRequest { key: key.clone(), persistence: self.persistence.into() };
+        let response = self.route(&key, move |mut client| {
+            let request = request.clone();
+            async move { client.get(request).await }
+        }).await?;
+        Ok(response.found.then_some(response.value))
+    }
+
+    pub async fn put(&mut self, key: String, value: impl Into<Bytes>) -> Result<bool, PouchError> {
+        self.put_with_ttl(key, value, None).await
+    }
+
+    // puts a value that expires after the ttl, instead of the default expiry of the cache
+    pub async fn put_with_ttl(&mut self, key: String, value: impl Into<Bytes>, ttl: Option<Duration>) -> Result<bool, PouchError> {
+        let ttl_ms = ttl.map(|ttl| ttl.as_millis() as u64);
+        let request = PutRequest { key: key.clone(), value: value.into(), ttl_ms, expires_at_ms: None, persistence: self.persistence.into() };
+        let response = self.route(&key, move |mut client| {
+            let request = request.clone();
+            async move { client.put(request).await }
+        }).await?;
+        Ok(response.success)
+    }
+
+    // returns whether the key existed
+    pub async fn delete(&mut self, key: String) -> Result<bool, PouchError> {
+        let request = DeleteRequest { key: key.clone(), persistence: self.persistence.into() };
+        let response = self.route(&key, move |mut client| {
+            let request = request.clone();
+            async move { client.delete(request).await }
+        }).await?;
+        Ok(response.found)
+    }
+
+    // gets many keys as text, the values are in the order of the keys
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>, PouchError> {
+        self.get_many_bytes(keys).await?.into_iter().map(text).collect()
+    }
+
+    // gets many keys with a single request to each of their owners, the values are in the order of the keys. the keys
+    // of an owner that can not be reached are read one by one from their next replicas
+    pub async fn get_many_bytes(&mut self, keys: Vec<String>) -> Result<Vec<Option<Bytes>>, PouchError> {
+        self.refresh_if_stale().await;
+        let mut values: Vec<Option<Bytes>> = vec![None; keys.len()];
+        for (owner, positions) in self.by_owner(keys.iter())? {
+            let owned: Vec<String> = positions.iter().map(|i| keys[*i].clone()).collect();
+            let request = MultiGetRequest { keys: owned, persistence: self.persistence.into() };
+            match self.client(&owner)?.multi_get(request).await.map_err(PouchError::from) {
+                Ok(response) => {
+                    for (i, value) in positions.into_iter().zip(response.into_inner().values) {
+                        values[i] = value.found.then_some(value.value);
+                    }
+                },
+                Err(e) if e.is_retryable() => {
+                    warn!("Node {} is unreachable for {} keys, trying their next replicas: {}", owner, positions.len(), e);
+                    self.stale = true;
+                    for i in positions {
+                        values[i] = self.get_bytes(keys[i].clone()).await?;
+                    }
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(values)
+    }
+
+    pub async fn put_many(&mut self, entries: Vec<(String, impl Into<Bytes>)>) -> Result<bool, PouchError> {
+        self.put_many_with_ttl(entries, None).await
+    }
+
+    // puts many values with a single request to each of their owners, all of them expiring after the ttl
+    pub async fn put_many_with_ttl(&mut self, entries: Vec<(String, impl Into<Bytes>)>, ttl: Option<Duration>) -> Result<bool, PouchError> {
+        self.refresh_if_stale().await;
+        let entries = batch(entries, ttl);
+        let mut success = true;
+        for (owner, positions) in self.by_owner(entries.iter().map(|entry| &entry.key))? {
+            let owned: Vec<KeyValuePair> = positions.iter().map(|i| entries[*i].clone()).collect();
+            let request = MultiPutRequest { entries: owned, persistence: self.persistence.into() };
+            match self.client(&owner)?.multi_put(request).await.map_err(PouchError::from) {
+                Ok(response) => success &= response.into_inner().success,
+                Err(e) if e.is_retryable() => {
+                    warn!("Node {} is unreachable for {} keys, trying their next replicas: {}", owner, positions.len(), e);
+                    self.stale = true;
+                    for i in positions {
+                        let KeyValuePair { key, value, .. } = entries[i].clone();
+                        success &= self.put_with_ttl(key, value, ttl).await?;
+                    }
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(success)
+    }
+
+    // the positions of the keys, grouped by their owner
+    fn by_owner<'a>(&self, keys: impl Iterator<Item = &'a String>) -> Result<HashMap<NodeInfo, Vec<usize>>, PouchError> {
+        let mut owners: HashMap<NodeInfo, Vec<usize>> = HashMap::new();
+        for (i, key) in keys.enumerate() {
+            let owner = self.ring.get_node(key).ok_or_else(|| PouchError::NodeUnavailable("No node in the cluster".to_string()))?;
+            owners.entry(owner.clone()).or_default().push(i);
+        }
+        Ok(owners)
+    }
+
+    async fn refresh_if_stale(&mut self) {
+        let expired = self.refresh_interval.is_some_and(|interval| self.refreshed_at.elapsed() >= interval);
+        if self.stale || expired {
+            if let Err(e) = self.refresh().await {
+                warn!("Could not refresh the cluster topology: {}", e);
+            }
+        }
+    }
+
+    // sends a request to the owner of the key, and to its next replicas while the nodes can not be reached
+    async fn route<Res, Fut>(
+        &mut self,
+        key: &str,
+        call: impl Fn(PeerClient) -> Fut,
+    ) -> Result<Res, PouchError>
+    where
+        Fut: Future<Output = Result<tonic::Response<Res>, Status>>,
+    {
+        self.refresh_if_stale().await;
+        let mut last_error = PouchError::NodeUnavailable("No node in the cluster".to_string());
+        for node in self.replicas(key) {
+            match call(self.client(&node)?).await.map_err(PouchError::from) {
+                Ok(response) => return Ok(response.into_inner()),
+                Err(e) if e.is_retryable() => {
+                    warn!("Node {} is unreachable for key {}, trying the next replica: {}", node, key, e);
+                    self.stale = true;
+                    last_error = e;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error)
+    }
+
+    // the client of a node, connecting lazily so that an unreachable node fails its requests instead
+    fn client(&mut self, node: &NodeInfo) -> Result<PeerClient, PouchError> {
+        if let Some(client) = self.clients.get(node) {
+            return Ok(client.clone());
+        }
+        let channel = Channel::from_shared(node.uri())
+            .map_err(|e| PouchError::Transport(e.to_string()))?
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .connect_lazy();
+        let client = PandasPouchCacheServiceClient::new(channel);
+        self.clients.insert(node.clone(), client.clone());
+        Ok(client)
+    }
 }
@@ -0,0 +1,345 @@
+// Cluster view of a node: the members, the hash ring over them and the gRPC channels to the peers
+
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use log::{debug, info, warn};
+use parking_lot::RwLock;
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tonic::transport::Channel;
+use tonic::Status;
+
+use crate::error::is_retryable;
+use crate::hash_ring::{HashRing, NodeInfo};
+use crate::pandas_pouch;
+use crate::pandas_pouch::pandas_pouch_cache_service_client::PandasPouchCacheServiceClient;
+use crate::pandas_pouch::{JoinClusterRequest, LeaveClusterRequest};
+
+pub type PeerClient = PandasPouchCacheServiceClient<Channel>;
+
+// how long after a topology change the previous owner of a key is asked for it on a miss,
+// while the rebalancing moves the entries to their new owners
+pub const HANDOFF_WINDOW: Duration = Duration::from_secs(60);
+
+// how long connecting to a peer, and a request to it, may take before the peer is taken as unreachable
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Membership {
+    members: BTreeSet<NodeInfo>,
+    ring: HashRing<NodeInfo>,
+    previous: Option<(HashRing<NodeInfo>, Instant)>,
+}
+
+pub struct Cluster {
+    self_node: NodeInfo,
+    replication_factor: usize,      // nodes every key is written to
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    membership: RwLock<Membership>,
+    peers: DashMap<NodeInfo, PeerClient>,
+    ring_updates: watch::Sender<HashRing<NodeInfo>>,
+}
+
+impl Cluster {
+    pub fn new(self_node: NodeInfo, nodes: Vec<NodeInfo>, virtual_nodes: isize) -> Cluster {
+        let mut members: BTreeSet<NodeInfo> = nodes.into_iter().collect();
+        members.insert(self_node.clone());
+        info!("Building hash ring for {} with members {:?}", self_node, members);
+
+        let ring = HashRing::new(members.iter().cloned().collect(), virtual_nodes);
+        let (ring_updates, _) = watch::channel(ring.clone());
+        Cluster {
+            self_node,
+            replication_factor: 1,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            membership: RwLock::new(Membership { members, ring, previous: None }),
+            peers: DashMap::new(),
+            ring_updates,
+        }
+    }
+
+    // writes every key to the given number of distinct nodes, following its owner on the ring
+    pub fn with_replication_factor(mut self, replication_factor: usize) -> Self {
+        self.replication_factor = replication_factor.max(1);
+        self
+    }
+
+    // bounds the time to connect to a peer and the time a request to it may take, so that a peer that stopped
+    // answering fails the requests sent to it instead of holding them
+    pub fn with_timeouts(mut self, connect_timeout: Duration, request_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    pub fn self_node(&self) -> &NodeInfo {
+        &self.self_node
+    }
+
+    pub fn replication_factor(&self) -> usize {
+        self.replication_factor
+    }
+
+    pub fn members(&self) -> Vec<NodeInfo> {
+        self.membership.read().members.iter().cloned().collect()
+    }
+
+    pub fn virtual_nodes(&self) -> isize {
+        self.membership.read().ring.virtual_nodes()
+    }
+
+    pub fn ring(&self) -> HashRing<NodeInfo> {
+        self.membership.read().ring.clone()
+    }
+
+    // the ring as it will be once this node has left the cluster
+    pub fn ring_without_self(&self) -> HashRing<NodeInfo> {
+        let mut ring = self.ring();
+        ring.remove_node(&self.self_node);
+        ring
+    }
+
+    // receives the new ring on every topology change
+    pub fn watch_ring(&self) -> watch::Receiver<HashRing<NodeInfo>> {
+        self.ring_updates.subscribe()
+    }
+
+    // returns the nodes the key is replicated on, its owner first. there are fewer of them than the replication
+    // factor when the cluster has fewer members
+    pub fn replicas(&self, key: &str) -> Vec<NodeInfo> {
+        let membership = self.membership.read();
+        membership.ring.get_nodes(key, self.replication_factor).into_iter().cloned().collect()
+    }
+
+    // returns the node that owned the key before the last topology change, if the change is recent
+    // and the key moved since. it may be this node, when it has not handed the key off yet
+    pub fn previous_owner(&self, key: &str) -> Option<NodeInfo> {
+        let membership = self.membership.read();
+        let (previous, changed_at) = membership.previous.as_ref()?;
+        if changed_at.elapsed() > HANDOFF_WINDOW {
+            return None;
+        }
+
+        let owner = membership.ring.get_node(key);
+        match previous.get_node(key) {
+            Some(previous_owner) if Some(previous_owner) != owner => Some(previous_owner.clone()),
+            _ => None,
+        }
+    }
+
+    // whether the topology changed recently enough for the entries to still be moving to their new owners
+    pub fn handing_off(&self) -> bool {
+        let membership = self.membership.read();
+        membership.previous.as_ref().is_some_and(|(_, changed_at)| changed_at.elapsed() <= HANDOFF_WINDOW)
+    }
+
+    // adds a node to the members and the ring, returns false if it was already a member
+    pub fn add_member(&self, node: NodeInfo) -> bool {
+        let mut membership = self.membership.write();
+        if membership.members.contains(&node) {
+            return false;
+        }
+        info!("Node {} joined the cluster", node);
+        let previous = membership.ring.clone();
+        membership.ring.add_node(&node);
+        membership.members.insert(node);
+        self.ring_changed(&mut membership, previous);
+        true
+    }
+
+    // removes a node from the members and the ring, returns false if it was not a member
+    pub fn remove_member(&self, node: &NodeInfo) -> bool {
+        if *node == self.self_node {
+            return false;
+        }
+
+        let mut membership = self.membership.write();
+        if !membership.members.remove(node) {
+            return false;
+        }
+        info!("Node {} left the cluster", node);
+        let previous = membership.ring.clone();
+        membership.ring.remove_node(node);
+        self.ring_changed(&mut membership, previous);
+        drop(membership);
+
+        self.peers.remove(node);
+        true
+    }
+
+    fn ring_changed(&self, membership: &mut Membership, previous: HashRing<NodeInfo>) {
+        membership.previous = Some((previous, Instant::now()));
+        self.ring_updates.send_replace(membership.ring.clone());
+    }
+
+    // gets a client for a peer, channels are connected lazily and reused between requests
+    pub fn client(&self, node: &NodeInfo) -> Result<PeerClient, Status> {
+        if let Some(client) = self.peers.get(node) {
+            return Ok(client.clone());
+        }
+
+        debug!("Opening channel to peer {}", node);
+        let channel = Channel::from_shared(node.uri())
+            .map_err(|e| Status::internal(format!("Invalid peer address {}: {}", node, e)))?
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout)
+            .connect_lazy();
+        let client = PandasPouchCacheServiceClient::new(channel);
+        self.peers.insert(node.clone(), client.clone());
+        Ok(client)
+    }
+
+    // joins the cluster through the first seed that answers, merging its members into ours.
+    // returns false when no seed could be reached, in which case this node starts a cluster of its own
+    pub async fn join(&self, seeds: &[NodeInfo]) -> bool {
+        let alone = self.membership.read().members.len() == 1;
+        for seed in seeds.iter().filter(|seed| **seed != self.self_node) {
+            let request = JoinClusterRequest {
+                joining_node: Some((&self.self_node).into()),
+                forwarded: false,
+            };
+
+            let response = match self.client(seed) {
+                Ok(mut client) => client.join_cluster(request).await,
+                Err(e) => Err(e),
+            };
+            match response {
+                Ok(response) => {
+                    for node in response.into_inner().current_nodes {
+                        match NodeInfo::try_from(node) {
+                            Ok(node) => { self.add_member(node); },
+                            Err(e) => warn!("Ignoring member from seed {}: {}", seed, e.message()),
+                        }
+                    }
+                    debug!("Joined the cluster through seed {}", seed);
+
+                    // the keys this node now owns were owned by the rest of the cluster, not by the ring
+                    // this node had on its own
+                    if alone {
+                        let previous = self.ring_without_self();
+                        self.membership.write().previous = Some((previous, Instant::now()));
+                    }
+                    return true;
+                },
+                Err(e) => warn!("Could not join the cluster through seed {}: {}", seed, e.message()),
+            }
+        }
+        false
+    }
+
+    // relays a join to every other member, so that all the rings stay the same. the members that can not be reached
+    // are dropped, they join again through their seeds once they are back
+    pub async fn broadcast_join(&self, joined: &NodeInfo) {
+        let unreachable = self.broadcast(joined, "joined", |mut client, node| async move {
+            let request = JoinClusterRequest { joining_node: Some(node), forwarded: true };
+            client.join_cluster(request).await.map(|_| ())
+        }).await;
+        for member in unreachable {
+            if self.remove_member(&member) {
+                warn!("Dropped {} from the cluster, it did not answer the join of {}", member, joined);
+            }
+        }
+    }
+
+    // relays a leave to every other member
+    pub async fn broadcast_leave(&self, left: &NodeInfo) {
+        self.broadcast(left, "left", |mut client, node| async move {
+            let request = LeaveClusterRequest { leaving_node: Some(node), forwarded: true };
+            client.leave_cluster(request).await.map(|_| ())
+        }).await;
+    }
+
+    // sends the change of a node to every other member concurrently, waiting for them no longer than the request
+    // timeout. returns the members that could not be reached or did not answer in time
+    async fn broadcast<Fut>(
+        &self,
+        node: &NodeInfo,
+        change: &str,
+        send: impl Fn(PeerClient, pandas_pouch::NodeInfo) -> Fut,
+    ) -> Vec<NodeInfo>
+    where
+        Fut: Future<Output = Result<(), Status>> + Send + 'static,
+    {
+        let mut pending: BTreeSet<NodeInfo> = self.peer_members().into_iter().filter(|member| member != node).collect();
+        let mut sends = JoinSet::new();
+        for member in pending.iter().cloned() {
+            match self.client(&member) {
+                Ok(client) => {
+                    let sent = send(client, node.into());
+                    sends.spawn(async move { (member, sent.await) });
+                },
+                Err(e) => { sends.spawn(async move { (member, Err(e)) }); },
+            }
+        }
+
+        let mut unreachable = Vec::new();
+        let answers = async {
+            while let Some(sent) = sends.join_next().await {
+                let Ok((member, result)) = sent else { continue };
+                pending.remove(&member);
+                if let Err(e) = result {
+                    warn!("Could not tell {} that {} {}: {}", member, node, change, e.message());
+                    if is_retryable(&e) {
+                        unreachable.push(member);
+                    }
+                }
+            }
+        };
+        if tokio::time::timeout(self.request_timeout, answers).await.is_err() {
+            warn!("{} members did not answer in time that {} {}", pending.len(), node, change);
+        }
+        unreachable.extend(pending);
+        unreachable
+    }
+
+    // announces to every member that this node is leaving the cluster
+    pub async fn leave(&self) {
+        info!("{} is leaving the cluster", self.self_node);
+        self.broadcast_leave(&self.self_node).await;
+    }
+
+    // periodically joins the seeds again. joining is idempotent, and it lets a restarted seed
+    // (or a member that missed a broadcast) learn the membership back from the rest of the cluster
+    pub fn spawn_rejoin(self: &Arc<Self>, seeds: Vec<NodeInfo>, interval: Duration) -> JoinHandle<()> {
+        let cluster = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                cluster.join(&seeds).await;
+            }
+        })
+    }
+
+    fn peer_members(&self) -> Vec<NodeInfo> {
+        self.members().into_iter().filter(|member| *member != self.self_node).collect()
+    }
+}
+
+impl From<&NodeInfo> for pandas_pouch::NodeInfo {
+    fn from(node: &NodeInfo) -> Self {
+        pandas_pouch::NodeInfo {
+            host: node.host.clone(),
+            port: node.port as i32,
+        }
+    }
+}
+
+impl TryFrom<pandas_pouch::NodeInfo> for NodeInfo {
+    type Error = Status;
+
+    fn try_from(node: pandas_pouch::NodeInfo) -> Result<Self, Self::Error> {
+        let port = u16::try_from(node.port)
+            .map_err(|_| Status::invalid_argument(format!("Invalid port {} for node {}", node.port, node.host)))?;
+        if node.host.is_empty() {
+            return Err(Status::invalid_argument("Node host can not be empty"));
+        }
+        Ok(NodeInfo::new(node.host, port))
+    }
+}
@@ -1,52 +1,229 @@
-use std::env;
-use config::{Config, ConfigError, Environment, File};
-use log::info;
-use serde::Deserialize;
-
-#[derive(Debug, Deserialize)]
-pub struct Settings {
-    pub local_addr: String,
-    pub local_port: u16,
-    pub database: DatabaseSettings,
-    pub rust_log: String,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct DatabaseSettings {
-    pub host: String,
-    pub username: String,
-    pub password: String,
-    pub name: String,
-}
-
-impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
-        let run_mode = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
-        info!("Run mode: {}", run_mode);
-
-        let s = Config::builder()
-            .add_source(File::with_name("config/default.toml"))
-            .add_source(File::with_name(&format!("config/{}.toml", run_mode)).required(false))
-            .add_source(File::with_name("config/local.toml").required(false))
-            .add_source(Environment::with_prefix("APP"))
-            .build()?;
-
-        info!("Configuration build successfully!");
-
-        let settings: Settings = s.try_deserialize()?;
-        env::set_var("RUST_LOG", &settings.rust_log);
-
-        info!("connected to database: {:?}", settings.database_url());
-        
-        Ok(settings)
-    }
-
-    pub fn database_url(&self) -> String {
-        let url = format!(
-            "postgresql://{}:{}@{}/{}",
-            self.database.username, self.database.password, self.database.host, self.database.name
-        );
-        log::debug!("Database URL: {}", url);
-        url
-    }
-}
+use std::env;
+use config::{Config, ConfigError, Environment, File};
+use log::info;
+use serde::Deserialize;
+
+use crate::eviction::EvictionPolicyKind;
+use crate::hash_ring::NodeInfo;
+
+#[derive(Debug, Deserialize)]
+pub struct Settings {
+    pub local_addr: String,
+    pub local_port: u16,
+    #[serde(default)]
+    pub database: DatabaseSettings,
+    pub rust_log: String,
+    #[serde(default)]
+    pub cluster: ClusterSettings,
+    #[serde(default)]
+    pub cache: CacheSettings,
+    #[serde(default)]
+    pub metrics: MetricsSettings,
+    #[serde(default)]
+    pub persistence: PersistenceSettings,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct DatabaseSettings {
+    pub backend: StorageBackend,            // postgres, sqlite or memory
+    pub host: String,
+    pub username: String,
+    pub password: String,
+    pub name: String,
+    pub path: String,                       // database file of the sqlite backend
+}
+
+impl Default for DatabaseSettings {
+    fn default() -> Self {
+        DatabaseSettings {
+            backend: StorageBackend::Postgres,
+            host: String::new(),
+            username: String::new(),
+            password: String::new(),
+            name: String::new(),
+            path: "pandas_pouch.db".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageBackend {
+    #[default]
+    Postgres,
+    Sqlite,
+    Memory,                                 // entries are only kept in the process, for tests and throwaway nodes
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ClusterSettings {
+    pub advertise_host: Option<String>,     // host the other nodes reach this node on, defaults to local_addr
+    pub virtual_nodes: isize,
+    pub seeds: Vec<String>,                 // nodes to join the cluster through, as host:port
+    pub rejoin_interval_secs: u64,
+    pub replication_factor: usize,          // nodes every key is written to
+    pub connect_timeout_ms: u64,            // how long connecting to a peer may take
+    pub request_timeout_ms: u64,            // how long a request to a peer may take, before the next replica is tried
+}
+
+impl Default for ClusterSettings {
+    fn default() -> Self {
+        ClusterSettings {
+            advertise_host: None,
+            virtual_nodes: 10,
+            seeds: Vec::new(),
+            rejoin_interval_secs: 30,
+            replication_factor: 1,
+            connect_timeout_ms: 1000,
+            request_timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct CacheSettings {
+    pub capacity: usize,                    // maximum size of the cache, in capacity_unit
+    pub capacity_unit: CapacityUnit,
+    pub sweep_interval_ms: u64,             // how often expired entries are reclaimed in the background
+    pub policy: EvictionPolicyKind,         // lru, lfu, arc or w-tinylfu
+    pub max_key_bytes: usize,               // longer keys are rejected as invalid
+    pub max_value_bytes: usize,             // larger values are rejected
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CapacityUnit {
+    Entries,                                // every entry counts as 1
+    #[default]
+    Bytes,                                  // entries weigh the length of their key and value
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            capacity: 64 * 1024 * 1024,
+            capacity_unit: CapacityUnit::Bytes,
+            sweep_interval_ms: 1000,
+            policy: EvictionPolicyKind::Lru,
+            max_key_bytes: 1024,
+            max_value_bytes: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MetricsSettings {
+    pub enabled: bool,                      // serves Prometheus metrics on http://local_addr:port/metrics
+    pub port: u16,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        MetricsSettings {
+            enabled: false,
+            port: 9091,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct PersistenceSettings {
+    pub mode: PersistenceMode,              // none, read-through, write-through, write-behind or write-around
+    pub queue_capacity: usize,              // writes queued by write-behind before puts wait for room
+    pub batch_size: usize,                  // writes flushed to the storage at a time
+    pub flush_interval_ms: u64,             // how often queued writes are flushed, when no batch fills up
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PersistenceMode {
+    #[serde(alias = "cache-aside")]
+    None,                                   // the cache only, clients load and store the values themselves
+    ReadThrough,                            // misses are loaded from the storage, writes only go to the cache
+    #[default]
+    WriteThrough,                           // misses are loaded, writes return once the storage has them
+    WriteBehind,                            // misses are loaded, writes return once they are queued for the storage
+    WriteAround,                            // misses are loaded, writes go to the storage and evict the key from the cache
+}
+
+impl PersistenceMode {
+    // whether misses are loaded from the storage
+    pub fn reads_storage(&self) -> bool {
+        *self != PersistenceMode::None
+    }
+}
+
+impl Default for PersistenceSettings {
+    fn default() -> Self {
+        PersistenceSettings {
+            mode: PersistenceMode::WriteThrough,
+            queue_capacity: 10_000,
+            batch_size: 100,
+            flush_interval_ms: 100,
+        }
+    }
+}
+
+impl Settings {
+    pub fn new() -> Result<Self, ConfigError> {
+        let run_mode = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
+        info!("Run mode: {}", run_mode);
+
+        let s = Config::builder()
+            .add_source(File::with_name("config/default.toml"))
+            .add_source(File::with_name(&format!("config/{}.toml", run_mode)).required(false))
+            .add_source(File::with_name("config/local.toml").required(false))
+            .add_source(Environment::with_prefix("APP"))
+            .build()?;
+
+        info!("Configuration build successfully!");
+
+        let settings: Settings = s.try_deserialize()?;
+        settings.validate()?;
+        env::set_var("RUST_LOG", &settings.rust_log);
+
+        info!("Storage backend: {:?}", settings.database.backend);
+        
+        Ok(settings)
+    }
+
+    // rejects the settings the server could not start with, as intervals or timeouts of zero
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let intervals = [
+            ("cluster.rejoin_interval_secs", self.cluster.rejoin_interval_secs),
+            ("cluster.connect_timeout_ms", self.cluster.connect_timeout_ms),
+            ("cluster.request_timeout_ms", self.cluster.request_timeout_ms),
+            ("cache.sweep_interval_ms", self.cache.sweep_interval_ms),
+            ("persistence.flush_interval_ms", self.persistence.flush_interval_ms),
+        ];
+        for (name, interval) in intervals {
+            if interval == 0 {
+                return Err(ConfigError::Message(format!("{} must be at least 1", name)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn database_url(&self) -> String {
+        let url = format!(
+            "postgresql://{}:{}@{}/{}",
+            self.database.username, self.database.password, self.database.host, self.database.name
+        );
+        log::debug!("Database URL: {}", url);
+        url
+    }
+
+    pub fn self_node(&self) -> NodeInfo {
+        let host = self.cluster.advertise_host.clone().unwrap_or_else(|| self.local_addr.clone());
+        NodeInfo::new(host, self.local_port)
+    }
+
+    pub fn cluster_seeds(&self) -> Result<Vec<NodeInfo>, String> {
+        self.cluster.seeds.iter().map(|node| node.parse()).collect()
+    }
+}
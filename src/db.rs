@@ -1,53 +1,441 @@
-use sqlx::postgres::{PgPool, PgPoolOptions};
-
-pub struct Database {
-    pool: PgPool,
-}
-
-impl Database {
-    pub async fn new(database_url: &str) -> Result<Self, sqlx::Error> {
-        let pool = PgPoolOptions::new()
-            .max_connections(5)
-            .connect(database_url)
-            .await?;
-
-        Ok(Database {
-            pool,
-        })
-    }
-
-    pub async fn get(&self, key: &str) -> Result<Option<String>, sqlx::Error> {
-        sqlx::query_scalar(
-            "SELECT value FROM cache WHERE key = $1"
-        )
-            .bind(key)
-            .fetch_optional(&self.pool)
-            .await
-    }
-
-    pub async fn put(&self, key: &str, value: &str) -> Result<(), sqlx::Error> {
-        sqlx::query(
-            "INSERT INTO cache (key, value) VALUES ($1, $2)\
-            ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value",
-        )
-            .bind(key)
-            .bind(value)
-            .execute(&self.pool)
-            .await?;
-
-        Ok(())
-    }
-
-    pub async fn create_table_if_not_exists(&self) -> Result<(), sqlx::Error> {
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS cache (\
-                    key TEXT PRIMARY KEY,\
-                    value TEXT NOT NULL\
-            )",
-        )
-            .execute(&self.pool)
-            .await?;
-
-        Ok(())
-    }
-}
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use parking_lot::RwLock;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use tonic::async_trait;
+
+use crate::config::{Settings, StorageBackend};
+use crate::error::PouchError;
+
+// current unix time in milliseconds, as stored in the expires_at column
+pub fn unix_time_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+// time a row stored with the given expires_at has left to live
+fn remaining_ttl(expires_at: Option<i64>, now: i64) -> Option<Duration> {
+    expires_at.map(|expires_at| Duration::from_millis(expires_at.saturating_sub(now).max(0) as u64))
+}
+
+fn is_live(expires_at: Option<i64>, now: i64) -> bool {
+    expires_at.is_none_or(|expires_at| expires_at > now)
+}
+
+// a ttl too long to be stored as milliseconds means the row never expires
+fn expires_at(ttl: Option<Duration>) -> Option<i64> {
+    let ttl_ms = i64::try_from(ttl?.as_millis()).ok()?;
+    Some(unix_time_ms().saturating_add(ttl_ms))
+}
+
+// durable store behind the cache, that misses fall back to and puts are written through to
+#[async_trait]
+pub trait Storage: Send + Sync {
+    // gets a live value, along with the time it has left to live if it expires
+    async fn get(&self, key: &str) -> Result<Option<(Vec<u8>, Option<Duration>)>, PouchError>;
+
+    // gets the live values of many keys, by key, the keys missing from the storage are left out
+    async fn get_many(&self, keys: &[String]) -> Result<HashMap<String, (Vec<u8>, Option<Duration>)>, PouchError> {
+        let mut found = HashMap::new();
+        for key in keys {
+            if let Some(value) = self.get(key).await? {
+                found.insert(key.clone(), value);
+            }
+        }
+        Ok(found)
+    }
+
+    // puts the value, which never expires in the storage when there is no ttl
+    async fn put(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<(), PouchError>;
+
+    // puts many values, all of them or none. the last one wins when a key is put twice. the backends that can not
+    // write them at once put them one by one, and may leave some of them written when one fails
+    async fn put_many(&self, entries: &[(&str, &[u8], Option<Duration>)]) -> Result<(), PouchError> {
+        for (key, value, ttl) in entries {
+            self.put(key, value, *ttl).await?;
+        }
+        Ok(())
+    }
+
+    // returns whether a live entry was deleted
+    async fn delete(&self, key: &str) -> Result<bool, PouchError>;
+
+    // live entries in key order, starting after the given key, with the time they have left to live
+    async fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, Vec<u8>, Option<Duration>)>, PouchError>;
+}
+
+// connects to the storage backend picked in the settings, and creates its table
+pub async fn connect(settings: &Settings) -> Result<Arc<dyn Storage>, sqlx::Error> {
+    match settings.database.backend {
+        StorageBackend::Postgres => {
+            let storage = PostgresStorage::new(&settings.database_url()).await?;
+            storage.create_table_if_not_exists().await?;
+            Ok(Arc::new(storage))
+        },
+        StorageBackend::Sqlite => {
+            let storage = SqliteStorage::new(&settings.database.path).await?;
+            storage.create_table_if_not_exists().await?;
+            Ok(Arc::new(storage))
+        },
+        StorageBackend::Memory => Ok(Arc::new(MemoryStorage::new())),
+    }
+}
+
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    pub async fn new(database_url: &str) -> Result<Self, sqlx::Error> {
+        let pool = PgPoolOptions::new()
+            .max_connections(5)
+            .connect(database_url)
+            .await?;
+
+        Ok(PostgresStorage {
+            pool,
+        })
+    }
+
+    pub async fn create_table_if_not_exists(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS cache (\
+                    key TEXT PRIMARY KEY,\
+                    value BYTEA NOT NULL,\
+                    expires_at BIGINT\
+            )",
+        )
+            .execute(&self.pool)
+            .await?;
+
+        // tables created before entries could expire
+        sqlx::query(
+            "ALTER TABLE cache ADD COLUMN IF NOT EXISTS expires_at BIGINT",
+        )
+            .execute(&self.pool)
+            .await?;
+
+        // tables created while values were text, their values are kept as their UTF-8 bytes
+        sqlx::query(
+            "DO $$ BEGIN \
+                IF EXISTS (SELECT 1 FROM information_schema.columns \
+                    WHERE table_name = 'cache' AND column_name = 'value' AND data_type = 'text') THEN \
+                    ALTER TABLE cache ALTER COLUMN value TYPE BYTEA USING convert_to(value, 'UTF8'); \
+                END IF; \
+            END $$",
+        )
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn get(&self, key: &str) -> Result<Option<(Vec<u8>, Option<Duration>)>, PouchError> {
+        let now = unix_time_ms();
+        let row: Option<(Vec<u8>, Option<i64>)> = sqlx::query_as(
+            "SELECT value, expires_at FROM cache WHERE key = $1 AND (expires_at IS NULL OR expires_at > $2)"
+        )
+            .bind(key)
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|(value, expires_at)| (value, remaining_ttl(expires_at, now))))
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<HashMap<String, (Vec<u8>, Option<Duration>)>, PouchError> {
+        let now = unix_time_ms();
+        let rows: Vec<(String, Vec<u8>, Option<i64>)> = sqlx::query_as(
+            "SELECT key, value, expires_at FROM cache WHERE key = ANY($1) AND (expires_at IS NULL OR expires_at > $2)"
+        )
+            .bind(keys)
+            .bind(now)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|(key, value, expires_at)| (key, (value, remaining_ttl(expires_at, now)))).collect())
+    }
+
+    async fn put(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<(), PouchError> {
+        sqlx::query(
+            "INSERT INTO cache (key, value, expires_at) VALUES ($1, $2, $3)\
+            ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value, expires_at = EXCLUDED.expires_at",
+        )
+            .bind(key)
+            .bind(value)
+            .bind(expires_at(ttl))
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // a single statement over arrays of the columns, a key must not be upserted twice by the same statement
+    async fn put_many(&self, entries: &[(&str, &[u8], Option<Duration>)]) -> Result<(), PouchError> {
+        let entries = last_puts(entries);
+        let keys: Vec<&str> = entries.iter().map(|(key, _, _)| *key).collect();
+        let values: Vec<&[u8]> = entries.iter().map(|(_, value, _)| *value).collect();
+        let expiries: Vec<Option<i64>> = entries.iter().map(|(_, _, ttl)| expires_at(*ttl)).collect();
+        sqlx::query(
+            "INSERT INTO cache (key, value, expires_at) \
+            SELECT * FROM UNNEST($1::TEXT[], $2::BYTEA[], $3::BIGINT[]) \
+            ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value, expires_at = EXCLUDED.expires_at",
+        )
+            .bind(keys)
+            .bind(values)
+            .bind(expiries)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, PouchError> {
+        let live: Option<bool> = sqlx::query_scalar(
+            "DELETE FROM cache WHERE key = $1 RETURNING (expires_at IS NULL OR expires_at > $2)"
+        )
+            .bind(key)
+            .bind(unix_time_ms())
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(live.unwrap_or(false))
+    }
+
+    async fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, Vec<u8>, Option<Duration>)>, PouchError> {
+        let now = unix_time_ms();
+        let rows: Vec<(String, Vec<u8>, Option<i64>)> = sqlx::query_as(
+            "SELECT key, value, expires_at FROM cache \
+            WHERE ($1::TEXT IS NULL OR key > $1) AND (expires_at IS NULL OR expires_at > $2) \
+            ORDER BY key LIMIT $3"
+        )
+            .bind(after)
+            .bind(now)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|(key, value, expires_at)| (key, value, remaining_ttl(expires_at, now))).collect())
+    }
+}
+
+// keys looked up per query by a SQLite get_many, and rows written per query by a put_many, well under the default
+// limit of 32766 parameters
+const SQLITE_KEYS_PER_QUERY: usize = 500;
+const SQLITE_ROWS_PER_QUERY: usize = 500;
+
+// the entries with the last value put to each key, in their order
+fn last_puts<'a>(entries: &[(&'a str, &'a [u8], Option<Duration>)]) -> Vec<(&'a str, &'a [u8], Option<Duration>)> {
+    let mut seen = HashSet::new();
+    let mut last: Vec<_> = entries.iter().rev().filter(|(key, _, _)| seen.insert(*key)).copied().collect();
+    last.reverse();
+    last
+}
+
+// stores the entries in a single SQLite file, for nodes that run without a database server
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub async fn new(path: &str) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await?;
+
+        Ok(SqliteStorage {
+            pool,
+        })
+    }
+
+    pub async fn create_table_if_not_exists(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS cache (\
+                    key TEXT PRIMARY KEY,\
+                    value BLOB NOT NULL,\
+                    expires_at INTEGER\
+            )",
+        )
+            .execute(&self.pool)
+            .await?;
+
+        // tables created while values were text need no migration, SQLite reads their text back as bytes
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get(&self, key: &str) -> Result<Option<(Vec<u8>, Option<Duration>)>, PouchError> {
+        let now = unix_time_ms();
+        let row: Option<(Vec<u8>, Option<i64>)> = sqlx::query_as(
+            "SELECT value, expires_at FROM cache WHERE key = ?1 AND (expires_at IS NULL OR expires_at > ?2)"
+        )
+            .bind(key)
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|(value, expires_at)| (value, remaining_ttl(expires_at, now))))
+    }
+
+    // SQLite has no arrays, so the keys are bound to an IN list, in chunks that stay under its limit of parameters
+    async fn get_many(&self, keys: &[String]) -> Result<HashMap<String, (Vec<u8>, Option<Duration>)>, PouchError> {
+        let now = unix_time_ms();
+        let mut found = HashMap::new();
+        for chunk in keys.chunks(SQLITE_KEYS_PER_QUERY) {
+            let placeholders: Vec<String> = (0..chunk.len()).map(|i| format!("?{}", i + 2)).collect();
+            let sql = format!(
+                "SELECT key, value, expires_at FROM cache WHERE (expires_at IS NULL OR expires_at > ?1) AND key IN ({})",
+                placeholders.join(", "),
+            );
+            let mut query = sqlx::query_as::<_, (String, Vec<u8>, Option<i64>)>(&sql).bind(now);
+            for key in chunk {
+                query = query.bind(key);
+            }
+            for (key, value, expires_at) in query.fetch_all(&self.pool).await? {
+                found.insert(key, (value, remaining_ttl(expires_at, now)));
+            }
+        }
+        Ok(found)
+    }
+
+    async fn put(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<(), PouchError> {
+        sqlx::query(
+            "INSERT INTO cache (key, value, expires_at) VALUES (?1, ?2, ?3)\
+            ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+        )
+            .bind(key)
+            .bind(value)
+            .bind(expires_at(ttl))
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // multi-row inserts in chunks, all in a single transaction
+    async fn put_many(&self, entries: &[(&str, &[u8], Option<Duration>)]) -> Result<(), PouchError> {
+        let mut transaction = self.pool.begin().await?;
+        for chunk in last_puts(entries).chunks(SQLITE_ROWS_PER_QUERY) {
+            let rows: Vec<String> = (0..chunk.len()).map(|i| format!("(?{}, ?{}, ?{})", 3 * i + 1, 3 * i + 2, 3 * i + 3)).collect();
+            let sql = format!(
+                "INSERT INTO cache (key, value, expires_at) VALUES {} \
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value, expires_at = excluded.expires_at",
+                rows.join(", "),
+            );
+            let mut query = sqlx::query(&sql);
+            for (key, value, ttl) in chunk {
+                query = query.bind(*key).bind(*value).bind(expires_at(*ttl));
+            }
+            query.execute(&mut *transaction).await?;
+        }
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, PouchError> {
+        let live: Option<bool> = sqlx::query_scalar(
+            "DELETE FROM cache WHERE key = ?1 RETURNING (expires_at IS NULL OR expires_at > ?2)"
+        )
+            .bind(key)
+            .bind(unix_time_ms())
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(live.unwrap_or(false))
+    }
+
+    async fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, Vec<u8>, Option<Duration>)>, PouchError> {
+        let now = unix_time_ms();
+        let rows: Vec<(String, Vec<u8>, Option<i64>)> = sqlx::query_as(
+            "SELECT key, value, expires_at FROM cache \
+            WHERE (?1 IS NULL OR key > ?1) AND (expires_at IS NULL OR expires_at > ?2) \
+            ORDER BY key LIMIT ?3"
+        )
+            .bind(after)
+            .bind(now)
+            .bind(limit as i64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(rows.into_iter().map(|(key, value, expires_at)| (key, value, remaining_ttl(expires_at, now))).collect())
+    }
+}
+
+// value and expires_at of an entry of the memory storage
+type MemoryEntry = (Vec<u8>, Option<i64>);
+
+// keeps the entries in process memory, they are lost when the node stops
+#[derive(Default)]
+pub struct MemoryStorage {
+    entries: RwLock<BTreeMap<String, MemoryEntry>>,     // by key
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get(&self, key: &str) -> Result<Option<(Vec<u8>, Option<Duration>)>, PouchError> {
+        let now = unix_time_ms();
+        let entries = self.entries.read();
+        Ok(entries.get(key)
+            .filter(|(_, expires_at)| is_live(*expires_at, now))
+            .map(|(value, expires_at)| (value.clone(), remaining_ttl(*expires_at, now))))
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<HashMap<String, (Vec<u8>, Option<Duration>)>, PouchError> {
+        let now = unix_time_ms();
+        let entries = self.entries.read();
+        Ok(keys.iter()
+            .filter_map(|key| {
+                let (value, expires_at) = entries.get(key).filter(|(_, expires_at)| is_live(*expires_at, now))?;
+                Some((key.clone(), (value.clone(), remaining_ttl(*expires_at, now))))
+            })
+            .collect())
+    }
+
+    async fn put(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<(), PouchError> {
+        self.entries.write().insert(key.to_string(), (value.to_vec(), expires_at(ttl)));
+        Ok(())
+    }
+
+    async fn put_many(&self, entries: &[(&str, &[u8], Option<Duration>)]) -> Result<(), PouchError> {
+        let mut stored = self.entries.write();
+        for (key, value, ttl) in entries {
+            stored.insert(key.to_string(), (value.to_vec(), expires_at(*ttl)));
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, PouchError> {
+        let now = unix_time_ms();
+        let removed = self.entries.write().remove(key);
+        Ok(removed.is_some_and(|(_, expires_at)| is_live(expires_at, now)))
+    }
+
+    async fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, Vec<u8>, Option<Duration>)>, PouchError> {
+        let now = unix_time_ms();
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        let entries = self.entries.read();
+        Ok(entries.range::<str, _>((start, Bound::Unbounded))
+            .filter(|(_, (_, expires_at))| is_live(*expires_at, now))
+            .take(limit)
+            .map(|(key, (value, expires_at))| (key.clone(), value.clone(), remaining_ttl(*expires_at, now)))
+            .collect())
+    }
+}
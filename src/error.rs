@@ -0,0 +1,186 @@
+// errors of the cache, shared by the server and the clients. every kind maps to a gRPC status code, and is named in
+// the metadata of the status as well, so that the client gets the same kind back even when two kinds share a code
+
+use std::error::Error;
+use std::fmt;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Status};
+
+use crate::codec::CodecError;
+
+// metadata naming the kind of error of a status, and the size and limit of a value that was too large
+const KIND_HEADER: &str = "pouch-error";
+const VALUE_SIZE_HEADER: &str = "pouch-value-size";
+const VALUE_LIMIT_HEADER: &str = "pouch-value-limit";
+
+#[derive(Debug)]
+pub enum PouchError {
+    NotFound(String),                       // never sent by the nodes, which answer a missing key with found: false
+    Database(String),
+    Transport(String),                      // the connection to a node could not be made
+    Timeout(String),
+    NodeUnavailable(String),                // no node that holds the key could be reached
+    ValueTooLarge { size: usize, limit: usize },
+    InvalidKey(String),
+    InvalidPersistence(String),             // the request asked for a persistence mode the server does not know
+    NotConfigured(String),                  // the request needs a database or queue the node runs without
+    Codec(CodecError),                      // raised by the client, a typed value could not be encoded or decoded
+    Other(Status),                          // any other status returned by a node
+}
+
+impl PouchError {
+    pub fn code(&self) -> Code {
+        match self {
+            PouchError::NotFound(_) => Code::NotFound,
+            PouchError::Database(_) | PouchError::Codec(_) => Code::Internal,
+            PouchError::Transport(_) | PouchError::NodeUnavailable(_) => Code::Unavailable,
+            PouchError::Timeout(_) => Code::DeadlineExceeded,
+            PouchError::ValueTooLarge { .. } => Code::ResourceExhausted,
+            PouchError::InvalidKey(_) | PouchError::InvalidPersistence(_) => Code::InvalidArgument,
+            PouchError::NotConfigured(_) => Code::FailedPrecondition,
+            PouchError::Other(status) => status.code(),
+        }
+    }
+
+    // whether the request may succeed on another node
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, PouchError::Transport(_) | PouchError::Timeout(_) | PouchError::NodeUnavailable(_))
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            PouchError::NotFound(_) => "not-found",
+            PouchError::Database(_) => "database",
+            PouchError::Transport(_) => "transport",
+            PouchError::Timeout(_) => "timeout",
+            PouchError::NodeUnavailable(_) => "node-unavailable",
+            PouchError::ValueTooLarge { .. } => "value-too-large",
+            PouchError::InvalidKey(_) => "invalid-key",
+            PouchError::InvalidPersistence(_) => "invalid-persistence",
+            PouchError::NotConfigured(_) => "not-configured",
+            PouchError::Codec(_) => "codec",
+            PouchError::Other(_) => "other",
+        }
+    }
+}
+
+// whether a request failed because the node could not be reached or did not answer in time, rather than because of
+// the request
+pub(crate) fn is_retryable(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::DeadlineExceeded) || is_timeout(status)
+}
+
+// tonic cancels a request that outlives the timeout of its channel, with the expiry as the source of the status
+fn is_timeout(status: &Status) -> bool {
+    let mut source = status.source();
+    while let Some(error) = source {
+        if error.is::<tonic::TimeoutExpired>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+impl fmt::Display for PouchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PouchError::NotFound(message) => write!(f, "Not found: {}", message),
+            PouchError::Database(message) => write!(f, "Database error: {}", message),
+            PouchError::Transport(message) => write!(f, "Transport error: {}", message),
+            PouchError::Timeout(message) => write!(f, "Timed out: {}", message),
+            PouchError::NodeUnavailable(message) => write!(f, "Node unavailable: {}", message),
+            PouchError::ValueTooLarge { size, limit } => write!(f, "Value of {} bytes is over the limit of {} bytes", size, limit),
+            PouchError::InvalidKey(message) => write!(f, "Invalid key: {}", message),
+            PouchError::InvalidPersistence(message) => write!(f, "Invalid persistence: {}", message),
+            PouchError::NotConfigured(message) => write!(f, "Not configured: {}", message),
+            PouchError::Codec(e) => write!(f, "{}", e),
+            PouchError::Other(status) => write!(f, "{:?}: {}", status.code(), status.message()),
+        }
+    }
+}
+
+impl Error for PouchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PouchError::Codec(e) => Some(e),
+            PouchError::Other(status) => Some(status),
+            _ => None,
+        }
+    }
+}
+
+impl From<PouchError> for Status {
+    fn from(error: PouchError) -> Self {
+        let error = match error {
+            PouchError::Other(status) => return status,
+            error => error,
+        };
+
+        let message = match &error {
+            PouchError::NotFound(message)
+            | PouchError::Database(message)
+            | PouchError::Transport(message)
+            | PouchError::Timeout(message)
+            | PouchError::NodeUnavailable(message)
+            | PouchError::InvalidKey(message)
+            | PouchError::InvalidPersistence(message)
+            | PouchError::NotConfigured(message) => message.clone(),
+            _ => error.to_string(),
+        };
+        let mut status = Status::new(error.code(), message);
+        let metadata = status.metadata_mut();
+        metadata.insert(KIND_HEADER, MetadataValue::from_static(error.kind()));
+        if let PouchError::ValueTooLarge { size, limit } = error {
+            metadata.insert(VALUE_SIZE_HEADER, size.into());
+            metadata.insert(VALUE_LIMIT_HEADER, limit.into());
+        }
+        status
+    }
+}
+
+// the kind named in the metadata of the status, or the one its code stands for when it has none, as for the statuses
+// made by tonic itself
+impl From<Status> for PouchError {
+    fn from(status: Status) -> Self {
+        let kind = status.metadata().get(KIND_HEADER).and_then(|kind| kind.to_str().ok());
+        let number = |header: &str| status.metadata().get(header)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        let message = status.message().to_string();
+        match (kind, status.code()) {
+            (Some("not-found"), _) | (None, Code::NotFound) => PouchError::NotFound(message),
+            (Some("database"), _) => PouchError::Database(message),
+            (Some("transport"), _) => PouchError::Transport(message),
+            (Some("timeout"), _) | (None, Code::DeadlineExceeded) => PouchError::Timeout(message),
+            (None, Code::Cancelled) if is_timeout(&status) => PouchError::Timeout(message),
+            (Some("node-unavailable"), _) | (None, Code::Unavailable) => PouchError::NodeUnavailable(message),
+            (Some("value-too-large"), _) => match (number(VALUE_SIZE_HEADER), number(VALUE_LIMIT_HEADER)) {
+                (Some(size), Some(limit)) => PouchError::ValueTooLarge { size, limit },
+                _ => PouchError::Other(status),
+            },
+            (Some("invalid-key"), _) => PouchError::InvalidKey(message),
+            (Some("invalid-persistence"), _) => PouchError::InvalidPersistence(message),
+            (Some("not-configured"), _) => PouchError::NotConfigured(message),
+            _ => PouchError::Other(status),
+        }
+    }
+}
+
+impl From<sqlx::Error> for PouchError {
+    fn from(e: sqlx::Error) -> Self {
+        PouchError::Database(e.to_string())
+    }
+}
+
+impl From<tonic::transport::Error> for PouchError {
+    fn from(e: tonic::transport::Error) -> Self {
+        PouchError::Transport(e.to_string())
+    }
+}
+
+impl From<CodecError> for PouchError {
+    fn from(e: CodecError) -> Self {
+        PouchError::Codec(e)
+    }
+}
@@ -0,0 +1,602 @@
+// Eviction policies: decide which keys make room when the entries of a segment of the cache outweigh its capacity
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use serde::Deserialize;
+use twox_hash::XxHash64;
+
+// tracks the keys of a segment and their weights, the cache itself keeps the values.
+// a policy keeps the total weight of its keys within the capacity it was built with
+pub trait EvictionPolicy<K>: Send {
+    // a cached key was read or overwritten, with its current weight. returns the keys to evict
+    // when the key grew past the capacity
+    fn on_access(&mut self, key: &K, weight: usize) -> Vec<K>;
+
+    // a key not in the cache is being added, returns the keys to evict to make room for it.
+    // the evicted keys may include the added key itself, when the policy does not admit it
+    fn on_insert(&mut self, key: &K, weight: usize) -> Vec<K>;
+
+    // a cached key was removed, by a delete or because it expired
+    fn on_remove(&mut self, key: &K);
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EvictionPolicyKind {
+    #[default]
+    Lru,
+    Lfu,
+    Arc,
+    #[serde(rename = "w-tinylfu", alias = "tinylfu")]
+    TinyLfu,
+}
+
+impl EvictionPolicyKind {
+    pub fn build<K: Eq + Hash + Clone + Send + 'static>(self, capacity: usize) -> Box<dyn EvictionPolicy<K>> {
+        match self {
+            EvictionPolicyKind::Lru => Box::new(LruPolicy::new(capacity)),
+            EvictionPolicyKind::Lfu => Box::new(LfuPolicy::new(capacity)),
+            EvictionPolicyKind::Arc => Box::new(ArcPolicy::new(capacity)),
+            EvictionPolicyKind::TinyLfu => Box::new(TinyLfuPolicy::new(capacity)),
+        }
+    }
+}
+
+struct ListNode<K> {
+    key: K,
+    weight: usize,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+// a doubly linked list of weighted keys, front to back. nodes are kept in a slab and linked by index,
+// so moving a key does not allocate
+pub struct KeyList<K> {
+    index: HashMap<K, usize>,
+    nodes: Vec<Option<ListNode<K>>>,
+    free: Vec<usize>,
+    head: Option<usize>,
+    tail: Option<usize>,
+    weight: usize,
+}
+
+impl<K: Eq + Hash + Clone> KeyList<K> {
+    pub fn new() -> KeyList<K> {
+        KeyList {
+            index: HashMap::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: None,
+            tail: None,
+            weight: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    // total weight of the keys in the list
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn back(&self) -> Option<&K> {
+        self.tail.map(|tail| &self.node(tail).key)
+    }
+
+    // adds the key at the front, or moves it there with its new weight if it is in the list already
+    pub fn push_front(&mut self, key: K, weight: usize) {
+        if self.set_weight(&key, weight) {
+            self.move_to_front(&key);
+            return;
+        }
+        let node = ListNode { key: key.clone(), weight, prev: None, next: None };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = Some(node);
+                index
+            },
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            },
+        };
+        self.attach_at_head(index);
+        self.index.insert(key, index);
+        self.weight += weight;
+    }
+
+    pub fn pop_back(&mut self) -> Option<(K, usize)> {
+        let key = self.back()?.clone();
+        let weight = self.remove(&key)?;
+        Some((key, weight))
+    }
+
+    // returns the weight of the key, if it was in the list
+    pub fn remove(&mut self, key: &K) -> Option<usize> {
+        let index = self.index.remove(key)?;
+        self.detach_node(index);
+        let node = self.nodes[index].take().expect("linked node is in the slab");
+        self.free.push(index);
+        self.weight -= node.weight;
+        Some(node.weight)
+    }
+
+    // returns whether the key was in the list
+    pub fn move_to_front(&mut self, key: &K) -> bool {
+        let Some(&index) = self.index.get(key) else {
+            return false;
+        };
+        if self.head != Some(index) {
+            self.detach_node(index);
+            self.attach_at_head(index);
+        }
+        true
+    }
+
+    // returns whether the key was in the list
+    pub fn set_weight(&mut self, key: &K, weight: usize) -> bool {
+        let Some(&index) = self.index.get(key) else {
+            return false;
+        };
+        let node = self.node_mut(index);
+        let old_weight = std::mem::replace(&mut node.weight, weight);
+        self.weight = self.weight - old_weight + weight;
+        true
+    }
+
+    fn node(&self, index: usize) -> &ListNode<K> {
+        self.nodes[index].as_ref().expect("linked node is in the slab")
+    }
+
+    fn node_mut(&mut self, index: usize) -> &mut ListNode<K> {
+        self.nodes[index].as_mut().expect("linked node is in the slab")
+    }
+
+    fn detach_node(&mut self, index: usize) {
+        let node = self.node_mut(index);
+        let prev = node.prev.take();
+        let next = node.next.take();
+
+        if let Some(prev) = prev {
+            self.node_mut(prev).next = next;
+        } else {
+            // node is head of the list, update the head
+            self.head = next;
+        }
+
+        if let Some(next) = next {
+            self.node_mut(next).prev = prev;
+        } else {
+            // node is in tail, update tail
+            self.tail = prev;
+        }
+    }
+
+    fn attach_at_head(&mut self, index: usize) {
+        let head = self.head;
+        let node = self.node_mut(index);
+        node.next = head;
+        node.prev = None;
+
+        if let Some(head) = head {
+            self.node_mut(head).prev = Some(index);
+        } else {
+            // list is empty, both head and tail to the node
+            self.tail = Some(index);
+        }
+        self.head = Some(index);
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for KeyList<K> {
+    fn default() -> Self {
+        KeyList::new()
+    }
+}
+
+// evicts the least recently used keys
+pub struct LruPolicy<K> {
+    capacity: usize,
+    keys: KeyList<K>,           // most recently used at the front
+}
+
+impl<K: Eq + Hash + Clone> LruPolicy<K> {
+    pub fn new(capacity: usize) -> LruPolicy<K> {
+        LruPolicy { capacity, keys: KeyList::new() }
+    }
+
+    fn evict(&mut self) -> Vec<K> {
+        let mut evicted = Vec::new();
+        while self.keys.weight() > self.capacity {
+            match self.keys.pop_back() {
+                Some((key, _)) => evicted.push(key),
+                None => break,
+            }
+        }
+        evicted
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send> EvictionPolicy<K> for LruPolicy<K> {
+    fn on_access(&mut self, key: &K, weight: usize) -> Vec<K> {
+        if self.keys.set_weight(key, weight) {
+            self.keys.move_to_front(key);
+        }
+        self.evict()
+    }
+
+    fn on_insert(&mut self, key: &K, weight: usize) -> Vec<K> {
+        self.keys.push_front(key.clone(), weight);
+        self.evict()
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        self.keys.remove(key);
+    }
+}
+
+struct LfuEntry {
+    frequency: u64,
+    seq: u64,
+    weight: usize,
+}
+
+// evicts the least frequently used keys, the least recently used ones among keys used as often
+pub struct LfuPolicy<K> {
+    capacity: usize,
+    weight: usize,
+    entries: HashMap<K, LfuEntry>,
+    order: BTreeMap<(u64, u64), K>,         // keys by (frequency, sequence)
+    next_seq: u64,
+}
+
+impl<K: Eq + Hash + Clone> LfuPolicy<K> {
+    pub fn new(capacity: usize) -> LfuPolicy<K> {
+        LfuPolicy {
+            capacity,
+            weight: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    fn track(&mut self, key: &K, frequency: u64, weight: usize) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.entries.insert(key.clone(), LfuEntry { frequency, seq, weight });
+        self.order.insert((frequency, seq), key.clone());
+        self.weight += weight;
+    }
+
+    fn untrack(&mut self, key: &K) -> Option<LfuEntry> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&(entry.frequency, entry.seq));
+        self.weight -= entry.weight;
+        Some(entry)
+    }
+
+    // evicts until `incoming` more weight fits
+    fn evict(&mut self, incoming: usize) -> Vec<K> {
+        let mut evicted = Vec::new();
+        while self.weight + incoming > self.capacity {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.weight -= entry.weight;
+            }
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send> EvictionPolicy<K> for LfuPolicy<K> {
+    fn on_access(&mut self, key: &K, weight: usize) -> Vec<K> {
+        if let Some(entry) = self.untrack(key) {
+            self.track(key, entry.frequency + 1, weight);
+        }
+        self.evict(0)
+    }
+
+    fn on_insert(&mut self, key: &K, weight: usize) -> Vec<K> {
+        if weight > self.capacity {
+            return vec![key.clone()];
+        }
+        // evicting before adding the key, or a full cache of frequent keys would never admit a new one
+        let evicted = self.evict(weight);
+        self.track(key, 1, weight);
+        evicted
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        self.untrack(key);
+    }
+}
+
+// Adaptive Replacement Cache (Megiddo and Modha). keys seen once and keys seen more than once are kept in
+// separate lists, and the split of the capacity between them adapts to the hits on recently evicted keys.
+// sizes are weights here, instead of the entry counts of the paper
+pub struct ArcPolicy<K> {
+    capacity: usize,
+    target: usize,              // target weight of t1, p in the paper
+    t1: KeyList<K>,             // cached keys seen once recently
+    t2: KeyList<K>,             // cached keys seen at least twice recently
+    b1: KeyList<K>,             // ghosts of the keys evicted from t1
+    b2: KeyList<K>,             // ghosts of the keys evicted from t2
+}
+
+impl<K: Eq + Hash + Clone> ArcPolicy<K> {
+    pub fn new(capacity: usize) -> ArcPolicy<K> {
+        ArcPolicy {
+            capacity,
+            target: 0,
+            t1: KeyList::new(),
+            t2: KeyList::new(),
+            b1: KeyList::new(),
+            b2: KeyList::new(),
+        }
+    }
+
+    // evicts from t1 or t2 into their ghost lists, until `incoming` more weight fits
+    fn replace(&mut self, incoming: usize, in_b2: bool) -> Vec<K> {
+        let mut evicted = Vec::new();
+        while self.t1.weight() + self.t2.weight() + incoming > self.capacity {
+            let t1_weight = self.t1.weight();
+            let from_t1 = !self.t1.is_empty()
+                && (t1_weight > self.target || (in_b2 && t1_weight == self.target) || self.t2.is_empty());
+            let (list, ghosts) = if from_t1 { (&mut self.t1, &mut self.b1) } else { (&mut self.t2, &mut self.b2) };
+            let Some((key, weight)) = list.pop_back() else {
+                break;
+            };
+            ghosts.push_front(key.clone(), weight);
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send> EvictionPolicy<K> for ArcPolicy<K> {
+    fn on_access(&mut self, key: &K, weight: usize) -> Vec<K> {
+        if self.t1.remove(key).is_some() || self.t2.contains(key) {
+            self.t2.push_front(key.clone(), weight);
+        }
+        self.replace(0, false)
+    }
+
+    fn on_insert(&mut self, key: &K, weight: usize) -> Vec<K> {
+        if weight > self.capacity {
+            return vec![key.clone()];
+        }
+
+        // a recently evicted key is back, so the list it was evicted from deserves more room
+        if self.b1.contains(key) {
+            let delta = (self.b2.weight() / self.b1.weight().max(1)).max(1) * weight.max(1);
+            self.target = (self.target + delta).min(self.capacity);
+            self.b1.remove(key);
+            let evicted = self.replace(weight, false);
+            self.t2.push_front(key.clone(), weight);
+            return evicted;
+        }
+        if self.b2.contains(key) {
+            let delta = (self.b1.weight() / self.b2.weight().max(1)).max(1) * weight.max(1);
+            self.target = self.target.saturating_sub(delta);
+            self.b2.remove(key);
+            let evicted = self.replace(weight, true);
+            self.t2.push_front(key.clone(), weight);
+            return evicted;
+        }
+
+        // keeping the ghosts within the capacity for t1 and b1, and within twice the capacity overall
+        while self.t1.weight() + self.b1.weight() + weight > self.capacity && self.b1.pop_back().is_some() {}
+        let total = |arc: &Self| arc.t1.weight() + arc.t2.weight() + arc.b1.weight() + arc.b2.weight();
+        while total(self) + weight > 2 * self.capacity && self.b2.pop_back().is_some() {}
+
+        let evicted = self.replace(weight, false);
+        self.t1.push_front(key.clone(), weight);
+        evicted
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if self.t1.remove(key).is_none() {
+            self.t2.remove(key);
+        }
+    }
+}
+
+const SKETCH_DEPTH: usize = 4;
+const MAX_FREQUENCY: u8 = 15;
+const MAX_SKETCH_WIDTH: usize = 1 << 20;
+
+// count-min sketch of how often keys were seen, halved periodically so old popularity fades away
+struct FrequencySketch {
+    table: Vec<u8>,
+    width: usize,
+    max_width: usize,
+    additions: usize,
+    sample_size: usize,
+}
+
+impl FrequencySketch {
+    fn new(capacity: usize) -> FrequencySketch {
+        // the capacity may be a weight much larger than the number of keys, so the width starts small and follows
+        // the number of keys, up to the capacity
+        FrequencySketch {
+            table: vec![0; 16 * SKETCH_DEPTH],
+            width: 16,
+            max_width: capacity.clamp(16, MAX_SKETCH_WIDTH).next_power_of_two(),
+            additions: 0,
+            sample_size: 16 * 10,
+        }
+    }
+
+    // widens the rows to a counter per key. the counts start over, which only happens each time the keys double
+    fn ensure_capacity(&mut self, keys: usize) {
+        let width = keys.next_power_of_two().min(self.max_width);
+        if width > self.width {
+            self.table = vec![0; width * SKETCH_DEPTH];
+            self.width = width;
+            self.additions = 0;
+            self.sample_size = width * 10;
+        }
+    }
+
+    fn indexes<K: Hash>(&self, key: &K) -> [usize; SKETCH_DEPTH] {
+        let mut indexes = [0; SKETCH_DEPTH];
+        for (row, index) in indexes.iter_mut().enumerate() {
+            let mut hasher = XxHash64::with_seed(row as u64);
+            key.hash(&mut hasher);
+            *index = row * self.width + (hasher.finish() as usize & (self.width - 1));
+        }
+        indexes
+    }
+
+    fn increment<K: Hash>(&mut self, key: &K) {
+        for index in self.indexes(key) {
+            if self.table[index] < MAX_FREQUENCY {
+                self.table[index] += 1;
+            }
+        }
+
+        self.additions += 1;
+        if self.additions >= self.sample_size {
+            self.table.iter_mut().for_each(|counter| *counter /= 2);
+            self.additions /= 2;
+        }
+    }
+
+    fn frequency<K: Hash>(&self, key: &K) -> u8 {
+        self.indexes(key).iter().map(|index| self.table[*index]).min().unwrap_or(0)
+    }
+}
+
+// Window TinyLFU (Einziger, Friedman and Manes). new keys enter a small LRU window, and leave it for the
+// main segmented LRU only if they were seen more often than the keys they would evict from there
+pub struct TinyLfuPolicy<K> {
+    window: KeyList<K>,
+    probation: KeyList<K>,      // main keys seen once since they were admitted
+    protected: KeyList<K>,      // main keys seen again while in probation
+    window_capacity: usize,
+    main_capacity: usize,
+    protected_capacity: usize,
+    sketch: FrequencySketch,
+}
+
+impl<K: Eq + Hash + Clone> TinyLfuPolicy<K> {
+    pub fn new(capacity: usize) -> TinyLfuPolicy<K> {
+        // 1% of the capacity for the window, and 80% of the main space for the protected keys
+        let window_capacity = (capacity / 100).max(1);
+        let main_capacity = capacity.saturating_sub(window_capacity);
+        TinyLfuPolicy {
+            window: KeyList::new(),
+            probation: KeyList::new(),
+            protected: KeyList::new(),
+            window_capacity,
+            main_capacity,
+            protected_capacity: main_capacity * 4 / 5,
+            sketch: FrequencySketch::new(capacity),
+        }
+    }
+
+    // number of counters of the frequency sketch
+    pub fn sketch_size(&self) -> usize {
+        self.sketch.table.len()
+    }
+
+    // moves the keys overflowing the window to the main space, if they are admitted
+    fn drain_window(&mut self, evicted: &mut Vec<K>) {
+        while self.window.weight() > self.window_capacity {
+            let Some((candidate, weight)) = self.window.pop_back() else {
+                break;
+            };
+            self.admit(candidate, weight, evicted);
+        }
+    }
+
+    fn admit(&mut self, candidate: K, weight: usize, evicted: &mut Vec<K>) {
+        if weight > self.main_capacity {
+            evicted.push(candidate);
+            return;
+        }
+        while self.probation.weight() + self.protected.weight() + weight > self.main_capacity {
+            let Some(victim) = self.probation.back().or(self.protected.back()).cloned() else {
+                break;
+            };
+            if self.sketch.frequency(&candidate) <= self.sketch.frequency(&victim) {
+                evicted.push(candidate);
+                return;
+            }
+            if self.probation.remove(&victim).is_none() {
+                self.protected.remove(&victim);
+            }
+            evicted.push(victim);
+        }
+        self.probation.push_front(candidate, weight);
+    }
+
+    // demotes the keys overflowing the protected space to probation
+    fn demote(&mut self) {
+        while self.protected.weight() > self.protected_capacity {
+            let Some((key, weight)) = self.protected.pop_back() else {
+                break;
+            };
+            self.probation.push_front(key, weight);
+        }
+    }
+
+    // evicts from the main space when a key grew past its capacity
+    fn shrink_main(&mut self, evicted: &mut Vec<K>) {
+        while self.probation.weight() + self.protected.weight() > self.main_capacity {
+            let popped = match self.probation.pop_back() {
+                Some(popped) => Some(popped),
+                None => self.protected.pop_back(),
+            };
+            let Some((key, _)) = popped else {
+                break;
+            };
+            evicted.push(key);
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone + Send> EvictionPolicy<K> for TinyLfuPolicy<K> {
+    fn on_access(&mut self, key: &K, weight: usize) -> Vec<K> {
+        self.sketch.increment(key);
+        let mut evicted = Vec::new();
+        if self.window.contains(key) {
+            self.window.push_front(key.clone(), weight);
+            self.drain_window(&mut evicted);
+        } else if self.probation.remove(key).is_some() || self.protected.contains(key) {
+            self.protected.push_front(key.clone(), weight);
+            self.demote();
+            self.shrink_main(&mut evicted);
+        }
+        evicted
+    }
+
+    fn on_insert(&mut self, key: &K, weight: usize) -> Vec<K> {
+        self.sketch.ensure_capacity(self.window.len() + self.probation.len() + self.protected.len() + 1);
+        self.sketch.increment(key);
+        self.window.push_front(key.clone(), weight);
+        let mut evicted = Vec::new();
+        self.drain_window(&mut evicted);
+        evicted
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        if self.window.remove(key).is_none() && self.probation.remove(key).is_none() {
+            self.protected.remove(key);
+        }
+    }
+}
@@ -1,111 +1,321 @@
-// consistent hashing implementation
-
-use std::collections::{BinaryHeap, HashMap};
-use std::fmt;
-use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
-use twox_hash::XxHash64;
-
-#[derive(Clone, Debug, PartialEq, Hash, Eq)]
-pub struct NodeInfo {
-    pub host: &'static str,
-    pub port: u16,
-}
-
-impl fmt::Display for NodeInfo {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{}", self.host, self.port)
-    }
-}
-
-type XxHash64Hasher = BuildHasherDefault<XxHash64>;
-
-// HashRing
-pub struct HashRing<T, S = XxHash64Hasher> {
-    replicas: isize,                // replicas -> number of virtual nodes each node making a better distribution
-    pub ring: HashMap<u64, T>,
-    pub sorted_keys: Vec<u64>,
-    hash_builder: S,
-}
-
-impl<T: ToString + Clone> HashRing<T, XxHash64Hasher> {
-    pub fn new(nodes: Vec<T>, replicas: isize) -> HashRing<T, XxHash64Hasher> {
-        HashRing::with_hasher(nodes, replicas, XxHash64Hasher::default())
-    }
-}
-
-impl<T, S> HashRing<T, S>
-where
-    T: ToString + Clone,
-    S: BuildHasher,
-{
-    pub fn with_hasher(nodes: Vec<T>, replicas: isize, hash_builder: S) -> HashRing<T, S> {
-        let mut new_hash_ring: HashRing<T, S> = HashRing {
-            replicas,
-            ring: HashMap::new(),
-            sorted_keys: Vec::new(),
-            hash_builder,
-        };
-
-        for n in &nodes {
-            new_hash_ring.add_node(n);
-        }
-        new_hash_ring
-    }
-
-    // add node
-    pub fn add_node(&mut self, node: &T) {
-        for i in 0..self.replicas {
-            let key = self.gen_key(format!("{}:{}", node.to_string(), i));
-            self.ring.insert(key, (*node).clone());
-            self.sorted_keys.push(key);
-        }
-
-        self.sorted_keys = BinaryHeap::from(self.sorted_keys.clone()).into_sorted_vec();
-    }
-
-    // delete node
-    pub fn remove_node(&mut self, node: &T) {
-        for i in 0..self.replicas {
-            let key = self.gen_key(format!("{}:{}", node.to_string(), i));
-            if !self.ring.contains_key(&key) {
-                return;
-            }
-            self.ring.remove(&key);
-            let mut index = 0;
-            for j in 0..self.sorted_keys.len() {
-                if self.sorted_keys[j] == key {
-                    index = j;
-                    break;
-                }
-            }
-            self.sorted_keys.remove(index);
-        }
-    }
-
-    // gets the node a key belong to
-    pub fn get_node(&self, key: String) -> Option<&T> {
-        if self.sorted_keys.is_empty() {
-            return None;
-        }
-
-        let generated_key  = self.gen_key(key);
-        let nodes = self.sorted_keys.clone();
-
-        for node in &nodes {
-            if generated_key <= *node {
-                return Some(self.ring.get(node).unwrap());
-            }
-        }
-
-        let node = &nodes[0];
-
-        Some(self.ring.get(node).unwrap())
-    }
-
-    // generates a key from a string value
-    fn gen_key(&self, key: String) -> u64 {
-        let mut hasher = self.hash_builder.build_hasher();
-        hasher.write(key.as_bytes());
-        hasher.finish()
-    }
-}
+// consistent hashing implementation
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, BuildHasherDefault, Hasher};
+use std::str::FromStr;
+use twox_hash::XxHash64;
+
+use crate::placement::Placement;
+
+#[derive(Clone, Debug, PartialEq, Hash, Eq, PartialOrd, Ord)]
+pub struct NodeInfo {
+    pub host: String,
+    pub port: u16,
+}
+
+impl NodeInfo {
+    pub fn new(host: impl Into<String>, port: u16) -> NodeInfo {
+        NodeInfo { host: host.into(), port }
+    }
+
+    // gRPC endpoint of the node
+    pub fn uri(&self) -> String {
+        format!("http://{}:{}", self.host, self.port)
+    }
+}
+
+impl fmt::Display for NodeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+// parses a `host:port` pair, as used in the cluster settings
+impl FromStr for NodeInfo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Invalid node address {:?}, expected host:port", s))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|e| format!("Invalid port in node address {:?}: {}", s, e))?;
+        Ok(NodeInfo::new(host, port))
+    }
+}
+
+pub type XxHash64Hasher = BuildHasherDefault<XxHash64>;
+
+// largest weight of a node, so that a node never has more than replicas * MAX_WEIGHT virtual nodes
+pub const MAX_WEIGHT: usize = 1024;
+
+// a snapshot of the load of each node, as reported by the callers, for the bounded-load lookups
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeLoads {
+    loads: HashMap<String, u64>,
+}
+
+impl NodeLoads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set<T: ToString>(&mut self, node: &T, load: u64) {
+        self.loads.insert(node.to_string(), load);
+    }
+
+    // adds to the load of a node, as when a key is placed on it
+    pub fn add<T: ToString>(&mut self, node: &T, load: u64) {
+        *self.loads.entry(node.to_string()).or_default() += load;
+    }
+
+    pub fn get<T: ToString>(&self, node: &T) -> u64 {
+        self.loads.get(&node.to_string()).copied().unwrap_or(0)
+    }
+}
+
+// HashRing
+#[derive(Clone)]
+pub struct HashRing<T, S = XxHash64Hasher> {
+    replicas: isize,                // replicas -> number of virtual nodes each node making a better distribution
+    weights: HashMap<String, usize>, // node -> weight, a node has replicas * weight virtual nodes
+    pub ring: HashMap<u64, T>,
+    pub sorted_keys: Vec<u64>,      // positions of the virtual nodes, sorted and distinct
+    hash_builder: S,
+}
+
+impl<T: ToString + Clone> HashRing<T, XxHash64Hasher> {
+    pub fn new(nodes: Vec<T>, replicas: isize) -> HashRing<T, XxHash64Hasher> {
+        HashRing::with_hasher(nodes, replicas, XxHash64Hasher::default())
+    }
+}
+
+impl<T, S> HashRing<T, S>
+where
+    T: ToString + Clone,
+    S: BuildHasher,
+{
+    pub fn with_hasher(nodes: Vec<T>, replicas: isize, hash_builder: S) -> HashRing<T, S> {
+        let mut new_hash_ring: HashRing<T, S> = HashRing {
+            replicas,
+            weights: HashMap::new(),
+            ring: HashMap::new(),
+            sorted_keys: Vec::new(),
+            hash_builder,
+        };
+
+        for n in &nodes {
+            new_hash_ring.add_node(n);
+        }
+        new_hash_ring
+    }
+
+    // add node
+    pub fn add_node(&mut self, node: &T) {
+        self.add_node_with_weight(node, 1);
+    }
+
+    // add node owning about weight times the keyspace of a node of weight 1, returns false, leaving the ring as it
+    // was, if the weight is over MAX_WEIGHT. a node already on the ring is added again with the new weight
+    pub fn add_node_with_weight(&mut self, node: &T, weight: usize) -> bool {
+        if weight > MAX_WEIGHT {
+            return false;
+        }
+        self.remove_node(node);
+
+        let name = node.to_string();
+        let mut added: Vec<u64> = Vec::new();
+        for i in 0..self.virtual_node_count(weight) {
+            let key = self.gen_key(&format!("{}:{}", name, i));
+            if self.ring.insert(key, (*node).clone()).is_none() {
+                added.push(key);
+            }
+        }
+        self.weights.insert(name, weight);
+
+        // merging the new positions into the sorted ones, in a single pass
+        added.sort_unstable();
+        added.dedup();
+        let mut merged = Vec::with_capacity(self.sorted_keys.len() + added.len());
+        let mut added = added.into_iter().peekable();
+        for key in self.sorted_keys.drain(..) {
+            while let Some(new) = added.next_if(|new| *new < key) {
+                merged.push(new);
+            }
+            merged.push(key);
+        }
+        merged.extend(added);
+        self.sorted_keys = merged;
+        true
+    }
+
+    // changes the weight of a node, returns false if it is not on the ring or the weight is over MAX_WEIGHT. the
+    // virtual nodes it keeps are the same, so only the keys of the virtual nodes added or removed move
+    pub fn set_weight(&mut self, node: &T, weight: usize) -> bool {
+        if !self.weights.contains_key(&node.to_string()) {
+            return false;
+        }
+        self.add_node_with_weight(node, weight)
+    }
+
+    // virtual nodes of a node of weight 1
+    pub fn virtual_nodes(&self) -> isize {
+        self.replicas
+    }
+
+    // weight of a node, None if it is not on the ring
+    pub fn weight(&self, node: &T) -> Option<usize> {
+        self.weights.get(&node.to_string()).copied()
+    }
+
+    // delete node
+    pub fn remove_node(&mut self, node: &T) {
+        let name = node.to_string();
+        let Some(weight) = self.weights.remove(&name) else {
+            return;
+        };
+        let mut removed: Vec<u64> = Vec::new();
+        for i in 0..self.virtual_node_count(weight) {
+            let key = self.gen_key(&format!("{}:{}", name, i));
+            if self.ring.remove(&key).is_some() {
+                removed.push(key);
+            }
+        }
+
+        removed.sort_unstable();
+        self.sorted_keys.retain(|key| removed.binary_search(key).is_err());
+    }
+
+    // gets the node a key belong to
+    pub fn get_node(&self, key: impl AsRef<str>) -> Option<&T> {
+        self.node_at(self.gen_key(key.as_ref()))
+    }
+
+    // gets the n distinct nodes a key is replicated on, its owner first
+    pub fn get_nodes(&self, key: &str, n: usize) -> Vec<&T>
+    where
+        T: PartialEq,
+    {
+        self.nodes_at(self.gen_key(key), n)
+    }
+
+    // gets the node a key belongs to with bounded loads: a node is skipped, for the next distinct node on the ring,
+    // while its load is at its capacity of (1 + epsilon) times the average load, scaled by its weight. the average
+    // counts the key being placed, so a node always has room when epsilon is positive
+    pub fn get_node_bounded(&self, key: impl AsRef<str>, loads: &NodeLoads, epsilon: f64) -> Option<&T> {
+        if self.sorted_keys.is_empty() {
+            return None;
+        }
+
+        let total_weight: usize = self.weights.values().sum();
+        let total_load: u64 = self.weights.keys().map(|node| loads.loads.get(node).copied().unwrap_or(0)).sum();
+        let capacity = |weight: usize| {
+            ((1.0 + epsilon.max(0.0)) * (total_load + 1) as f64 * weight as f64 / total_weight as f64).ceil()
+        };
+
+        let start = self.successor(self.gen_key(key.as_ref()));
+        let mut visited: Vec<&str> = Vec::new();
+        for i in 0..self.sorted_keys.len() {
+            let node = self.ring.get(&self.sorted_keys[(start + i) % self.sorted_keys.len()]).unwrap();
+            let name = node.to_string();
+            let Some((name, weight)) = self.weights.get_key_value(&name) else {
+                continue;
+            };
+            if visited.contains(&name.as_str()) {
+                continue;
+            }
+            if (loads.loads.get(name).copied().unwrap_or(0) as f64) < capacity(*weight) {
+                return Some(node);
+            }
+            visited.push(name);
+        }
+
+        // every node is full, as with a zero epsilon: the owner takes the key
+        self.node_at(self.sorted_keys[start])
+    }
+
+    // position of a key on the ring
+    pub fn position(&self, key: &str) -> u64 {
+        self.gen_key(key)
+    }
+
+    // gets the node owning a position on the ring: the first virtual node at or after it, wrapping around
+    pub fn node_at(&self, position: u64) -> Option<&T> {
+        if self.sorted_keys.is_empty() {
+            return None;
+        }
+
+        let node = self.sorted_keys[self.successor(position)];
+        self.ring.get(&node)
+    }
+
+    // gets the n distinct nodes following a position on the ring, its owner first. the virtual nodes of the nodes
+    // already picked are skipped, so fewer than n are returned only when the ring has fewer nodes
+    pub fn nodes_at(&self, position: u64, n: usize) -> Vec<&T>
+    where
+        T: PartialEq,
+    {
+        let n = n.min(self.weights.len());
+        let mut nodes: Vec<&T> = Vec::with_capacity(n);
+        if n == 0 || self.sorted_keys.is_empty() {
+            return nodes;
+        }
+
+        let start = self.successor(position);
+        for i in 0..self.sorted_keys.len() {
+            let node = self.ring.get(&self.sorted_keys[(start + i) % self.sorted_keys.len()]).unwrap();
+            if !nodes.contains(&node) {
+                nodes.push(node);
+                if nodes.len() == n {
+                    break;
+                }
+            }
+        }
+        nodes
+    }
+
+    // index of the first virtual node at or after a position, wrapping around. the ring must not be empty
+    fn successor(&self, position: u64) -> usize {
+        let index = self.sorted_keys.partition_point(|node| *node < position);
+        if index == self.sorted_keys.len() { 0 } else { index }
+    }
+
+    fn virtual_node_count(&self, weight: usize) -> isize {
+        self.replicas.saturating_mul(weight as isize)
+    }
+
+    // generates a key from a string value
+    fn gen_key(&self, key: &str) -> u64 {
+        let mut hasher = self.hash_builder.build_hasher();
+        hasher.write(key.as_bytes());
+        hasher.finish()
+    }
+}
+
+impl<T, S> Placement<T> for HashRing<T, S>
+where
+    T: ToString + Clone + PartialEq,
+    S: BuildHasher,
+{
+    fn add_node(&mut self, node: &T) {
+        HashRing::add_node(self, node);
+    }
+
+    fn remove_node(&mut self, node: &T) {
+        HashRing::remove_node(self, node);
+    }
+
+    fn get_node(&self, key: &str) -> Option<&T> {
+        HashRing::get_node(self, key)
+    }
+
+    fn get_nodes(&self, key: &str, n: usize) -> Vec<&T> {
+        HashRing::get_nodes(self, key, n)
+    }
+
+    fn node_count(&self) -> usize {
+        self.weights.len()
+    }
+}
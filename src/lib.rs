@@ -1,10 +1,20 @@
-pub mod client;
-pub mod lru;
-pub mod server;
-pub mod db;
-pub mod config;
-pub mod hash_ring;
-
-pub mod pandas_pouch {
-    tonic::include_proto!("pandas_pouch");
-}
+#![allow(clippy::result_large_err)]      // tonic::Status is the error type of every handler
+
+pub mod client;
+pub mod codec;
+pub mod error;
+pub mod lru;
+pub mod eviction;
+pub mod server;
+pub mod db;
+pub mod config;
+pub mod hash_ring;
+pub mod placement;
+pub mod cluster;
+pub mod rebalance;
+pub mod metrics;
+pub mod write_behind;
+
+pub mod pandas_pouch {
+    tonic::include_proto!("pandas_pouch");
+}
@@ -1,195 +1,414 @@
-// Least Recently Used Implementation for Caching
-
-use std::fmt::Display;
-use std::hash::Hash;
-use std::time::{Duration, Instant};
-use parking_lot::Mutex;
-use dashmap::DashMap;
-use std::sync::Arc;
-use log::{debug, info, warn};
-
-pub type Link<K, V> = Option<Arc<Mutex<Node<K, V>>>>;
-
-pub struct Node<K, V> {
-    key: K,
-    value: V,
-    expires_at: Instant,
-    prev: Link<K, V>,
-    next: Link<K, V>,
-}
-
-pub struct LRUCache<K: Eq + Hash, V> {
-    map: DashMap<K, Link<K, V>>,
-    expires: Duration,
-    head: Link<K, V>,
-    tail: Link<K, V>,
-    capacity: usize,
-}
-
-impl<K: Eq + Hash + Clone + Display, V: Clone + Display> LRUCache<K, V> {
-    pub fn new(capacity: usize, expires: Option<Duration>) -> LRUCache<K, V> {
-        let expires = expires.unwrap_or(Duration::from_secs(3600));
-        info!("Adding new element to panda's pouch with capacity {} and expiry {:?}", capacity, expires);
-        LRUCache {
-            map: DashMap::new(),
-            expires,
-            head: None,
-            tail: None,
-            capacity,
-        }
-    }
-
-    pub fn get(&mut self, key: &K) -> Option<V> {
-        info!("Trying to get cache value for key: {}", key);
-        let node_ref = self.map.get(key)?.as_ref()?.clone();
-        let node = node_ref.lock();
-        if node.expires_at < Instant::now() {
-            warn!("Cache entry for key {} has expired", key);
-            drop(node);
-            self.remove(key.clone());
-            None
-        } else {
-            let value = node.value.clone();
-            drop(node);
-            self.move_to_head(node_ref);
-            debug!("Cache hit for key: {}", key);
-            Some(value)
-        }
-    }
-
-    pub fn put(&mut self, key: K, value: V) {
-        info!("Adding the {key}:{value} to the cache");
-        if let Some(node_ref) = self.map.get(&key).and_then(|r| r.value().clone()) {
-            let mut node = node_ref.lock();
-            node.value = value;
-            node.expires_at = Instant::now() + self.expires;
-            drop(node);
-            self.move_to_head(node_ref);
-            debug!("Updated existing entry for key: {}", key);
-        } else {
-            let new_node = Arc::new(Mutex::new(Node {
-                key: key.clone(),
-                value,
-                expires_at: Instant::now() + self.expires,
-                prev: None,
-                next: self.head.clone(),
-            }));
-
-            if let Some(head) = &self.head {
-                let mut head = head.lock();
-                head.prev = Some(Arc::clone(&new_node));
-            } else {
-                self.tail = Some(Arc::clone(&new_node));
-            }
-            self.head = Some(Arc::clone(&new_node));
-
-            debug!("Added new entry to the pouch with key: {}", key);
-            if self.map.len() >= self.capacity {
-                warn!("Panda's pouch is full. Removing least recently used item.");
-                if let Some(tail) = self.tail.clone() {
-                    let tail = tail.lock();
-                    let prev = tail.prev.clone();
-                    let key_to_remove = tail.key.clone();
-                    drop(tail);
-                    self.map.remove(&key_to_remove);
-                    self.tail = prev;
-                    if let Some(new_tail) = &self.tail {
-                        new_tail.lock().next = None;
-                    }
-                }
-            }
-
-            self.map.insert(key, Some(new_node));
-        }
-    }
-
-    pub fn print(&mut self) -> Vec<(K, V)> {
-        info!("Printing all elements from pouch.");
-        let mut current = self.head.clone();
-        let mut get_all = Vec::new();
-        while let Some(node) = current {
-            let node_lock = node.lock();
-            let key = node_lock.key.clone();
-            let value = node_lock.value.clone();
-            let expires_at = node_lock.expires_at;
-            
-            current = node_lock.next.clone();
-            drop(node_lock);
-
-            if expires_at < Instant::now() {
-                warn!("Removing expired entries from key: {}", key);
-                self.remove(key);
-            } else {
-                debug!("Valid entry: {} -> {}", key, value);
-                get_all.push((key, value));
-            }
-        }
-        info!("Total valid entries from pouch: {}", get_all.len());
-        get_all
-    }
-
-    fn detach_node(&mut self, node_ref: Arc<Mutex<Node<K, V>>>) {
-        let mut node = node_ref.lock();
-        let prev = node.prev.clone();
-        let next = node.next.clone();
-
-        if let Some(prev_node_ref) = &prev {
-            prev_node_ref.lock().next = next.clone();
-        } else {
-            // node is head of LRUCache DLL, update the head
-            self.head = next.clone();
-        }
-
-        if let Some(next_node_ref) = &next {
-            next_node_ref.lock().prev = prev;
-        } else {
-            // node is in tail, update tail
-            self.tail = prev;
-        }
-
-        node.prev = None;
-        node.next = None;
-    }
-
-    fn remove(&mut self, key: K) -> Option<(K, V)> {
-        if let Some((_, node_link)) = self.map.remove(&key) {
-            if let Some(node_ref) = node_link {
-                // unlink/detaching node from DLL
-                self.detach_node(node_ref.clone());
-                let node = node_ref.lock();
-                return Some((node.key.clone(), node.value.clone()));
-            }
-        }
-        None
-    }
-
-    fn move_to_head(&mut self, node_ref: Arc<Mutex<Node<K, V>>>) {
-        // unlinking/detaching node from the DLL
-        self.detach_node(node_ref.clone());
-
-        // inserting at head
-        let mut node = node_ref.lock();
-        node.next = self.head.clone();
-        node.prev = None;
-        drop(node);
-
-        if let Some(head_ref) = &self.head {
-            head_ref.lock().prev = Some(node_ref.clone());
-        } else {
-            // DLL is empty, both head and tail to the node
-            self.tail = Some(node_ref.clone());
-        }
-
-        self.head = Some(node_ref);
-    }
-}
-
-impl<K: Eq + Hash, V> Drop for LRUCache<K, V> {
-    fn drop(&mut self) {
-        // Clear the map to break potential circular references
-        self.map.clear();
-        // Set head and tail to None to break the linked list
-        self.head = None;
-        self.tail = None;
-    }
-}
+// Least Recently Used Implementation for Caching
+//
+// The cache is split in segments, each one with its own lock and its own eviction policy, least recently used
+// unless another one is picked. A key always lives in the same segment, so operations on keys of different
+// segments do not contend with each other.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+use log::{debug, info, warn};
+
+use crate::eviction::{EvictionPolicy, EvictionPolicyKind};
+
+// below this capacity per segment, recency is tracked over too few entries to be meaningful
+const MIN_SEGMENT_CAPACITY: usize = 64;
+const MAX_SEGMENTS: usize = 64;
+// longest time to live of an entry, a longer one, as far as the instant overflows, means the entry never expires
+const MAX_TTL: Duration = Duration::from_secs(100 * 365 * 24 * 3600);
+
+// weighs an entry against the capacity of the cache
+pub type Weigher<K, V> = Box<dyn Fn(&K, &V) -> usize + Send + Sync>;
+
+struct Entry<V> {
+    value: V,
+    weight: usize,
+    expires_at: Instant,
+    seq: u64,                   // tells apart the entries expiring at the same instant in the expiry index
+}
+
+// the entries of a segment, with the policy deciding which of them to evict when it is full
+struct Segment<K, V> {
+    map: HashMap<K, Entry<V>>,
+    policy: Box<dyn EvictionPolicy<K>>,
+    capacity: usize,
+    weight: usize,              // total weight of the entries
+    expiry_index: BTreeMap<(Instant, u64), K>,      // keys ordered by expiry, for the active expiration
+    next_seq: u64,
+}
+
+pub struct LRUCache<K, V> {
+    segments: Box<[Mutex<Segment<K, V>>]>,
+    hash_builder: RandomState,
+    weigher: Weigher<K, V>,
+    expires: Duration,
+    capacity: usize,            // maximum total weight of the entries, every entry weighs 1 unless a weigher is set
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expired: AtomicU64,
+}
+
+// a snapshot of the counters and the size of the cache
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,                // expired entries read count as misses
+    pub evictions: u64,             // entries dropped to make room, not admitted by the eviction policy, or too heavy
+    pub expired: u64,
+    pub entries: usize,
+    pub weight: usize,
+    pub capacity: usize,
+}
+
+impl<K: Eq + Hash + Clone + Display + Send + 'static, V: Clone> LRUCache<K, V> {
+    pub fn new(capacity: usize, expires: Option<Duration>) -> LRUCache<K, V> {
+        LRUCache::with_policy(capacity, expires, EvictionPolicyKind::Lru)
+    }
+
+    pub fn with_policy(capacity: usize, expires: Option<Duration>, policy: EvictionPolicyKind) -> LRUCache<K, V> {
+        LRUCache::with_max_entry_weight(capacity, expires, policy, 1)
+    }
+
+    // creates the cache with few enough segments for each of them to hold an entry of the given weight, as far as the
+    // capacity allows, so that the heaviest entries accepted are still cached
+    pub fn with_max_entry_weight(capacity: usize, expires: Option<Duration>, policy: EvictionPolicyKind, max_entry_weight: usize) -> LRUCache<K, V> {
+        let parallelism = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let segments = (capacity / MIN_SEGMENT_CAPACITY)
+            .min(capacity / max_entry_weight.max(1))
+            .clamp(1, (parallelism * 4).min(MAX_SEGMENTS));
+        LRUCache::with_segments(capacity, expires, segments, policy)
+    }
+
+    // creates the cache with a given number of segments, at most the capacity and rounded down to a power of two.
+    // the capacity is split evenly between them, an entry weighing more than its segment's share is not cached
+    pub fn with_segments(capacity: usize, expires: Option<Duration>, segments: usize, policy: EvictionPolicyKind) -> LRUCache<K, V> {
+        let expires = expires.unwrap_or(Duration::from_secs(3600));
+        let segments = 1 << segments.min(capacity).max(1).ilog2();
+        info!("Adding new element to panda's pouch with capacity {}, {} segments, {:?} eviction and expiry {:?}",
+            capacity, segments, policy, expires);
+
+        let segments = (0..segments)
+            .map(|i| {
+                let segment_capacity = capacity / segments + usize::from(i < capacity % segments);
+                Mutex::new(Segment::new(segment_capacity, policy.build(segment_capacity)))
+            })
+            .collect();
+
+        LRUCache {
+            segments,
+            hash_builder: RandomState::new(),
+            weigher: Box::new(|_, _| 1),
+            expires,
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+        }
+    }
+
+    // weighs the entries with the weigher instead of counting them, the capacity becomes their maximum total weight
+    pub fn with_weigher(mut self, weigher: impl Fn(&K, &V) -> usize + Send + Sync + 'static) -> LRUCache<K, V> {
+        self.weigher = Box::new(weigher);
+        self
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // total weight of the entries, expired ones included until they are reclaimed
+    pub fn weight(&self) -> usize {
+        self.segments.iter().map(|segment| segment.lock().weight).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.lock().map.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.iter().all(|segment| segment.lock().map.is_empty())
+    }
+
+    // number of entries removed because they expired, by reads or by purges
+    pub fn expired(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (entries, weight) = self.segments.iter()
+            .map(|segment| {
+                let segment = segment.lock();
+                (segment.map.len(), segment.weight)
+            })
+            .fold((0, 0), |(entries, weight), (len, w)| (entries + len, weight + w));
+
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            entries,
+            weight,
+            capacity: self.capacity,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.get_with_ttl(key).map(|(value, _)| value)
+    }
+
+    // gets the value along with the time it has left to live
+    pub fn get_with_ttl(&self, key: &K) -> Option<(V, Duration)> {
+        info!("Trying to get cache value for key: {}", key);
+        let mut segment = self.segment(key).lock();
+        self.get_from(&mut segment, key, Instant::now())
+    }
+
+    // gets a live value without counting a hit or a miss, nor telling the eviction policy it was read
+    pub fn peek_with_ttl(&self, key: &K) -> Option<(V, Duration)> {
+        let now = Instant::now();
+        let segment = self.segment(key).lock();
+        let entry = segment.map.get(key).filter(|entry| entry.expires_at >= now)?;
+        Some((entry.value.clone(), entry.expires_at - now))
+    }
+
+    // gets the values of many keys, in their order, locking every segment once
+    pub fn get_many_with_ttl(&self, keys: &[K]) -> Vec<Option<(V, Duration)>> {
+        let mut found: Vec<Option<(V, Duration)>> = keys.iter().map(|_| None).collect();
+        let now = Instant::now();
+        let mut segments: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            segments.entry(self.segment_index(key)).or_default().push(i);
+        }
+        for (index, positions) in segments {
+            let mut segment = self.segments[index].lock();
+            for i in positions {
+                found[i] = self.get_from(&mut segment, &keys[i], now);
+            }
+        }
+        found
+    }
+
+    fn get_from(&self, segment: &mut Segment<K, V>, key: &K, now: Instant) -> Option<(V, Duration)> {
+        let Some(entry) = segment.map.get(key) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        if entry.expires_at < now {
+            warn!("Cache entry for key {} has expired", key);
+            segment.remove(key);
+            self.expired.fetch_add(1, Ordering::Relaxed);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            None
+        } else {
+            let value = entry.value.clone();
+            let ttl = entry.expires_at - now;
+            let weight = entry.weight;
+            let evicted = segment.policy.on_access(key, weight);
+            let evicted = segment.evict(key, evicted);
+            self.evictions.fetch_add(evicted, Ordering::Relaxed);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            debug!("Cache hit for key: {}", key);
+            Some((value, ttl))
+        }
+    }
+
+    pub fn put(&self, key: K, value: V) {
+        self.put_with_ttl(key, value, None);
+    }
+
+    // puts the value with its own time to live, instead of the default expiry of the cache
+    pub fn put_with_ttl(&self, key: K, value: V, ttl: Option<Duration>) {
+        info!("Adding the key {key} to the cache");
+        let expires_at = self.expiry(Instant::now(), ttl);
+        let weight = (self.weigher)(&key, &value);
+        let evicted = self.segment(&key).lock().put(key, value, weight, expires_at);
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    // puts many values, each with its own time to live, locking every segment once
+    pub fn put_many_with_ttl(&self, entries: Vec<(K, V, Option<Duration>)>) {
+        let now = Instant::now();
+        let mut segments: BTreeMap<usize, Vec<(K, V, Option<Duration>)>> = BTreeMap::new();
+        for entry in entries {
+            segments.entry(self.segment_index(&entry.0)).or_default().push(entry);
+        }
+        for (index, entries) in segments {
+            let mut segment = self.segments[index].lock();
+            for (key, value, ttl) in entries {
+                let weight = (self.weigher)(&key, &value);
+                let evicted = segment.put(key, value, weight, self.expiry(now, ttl));
+                self.evictions.fetch_add(evicted, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // puts the value only if there is no live entry for the key, returns whether it was put
+    pub fn put_if_absent(&self, key: K, value: V, ttl: Option<Duration>) -> bool {
+        let expires_at = self.expiry(Instant::now(), ttl);
+        let weight = (self.weigher)(&key, &value);
+        let mut segment = self.segment(&key).lock();
+        if let Some(entry) = segment.map.get(&key) {
+            if entry.expires_at >= Instant::now() {
+                debug!("Keeping existing entry for key: {}", key);
+                return false;
+            }
+        }
+        let evicted = segment.put(key, value, weight, expires_at);
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+        true
+    }
+
+    pub fn remove(&self, key: K) -> Option<(K, V)> {
+        self.segment(&key).lock().remove(&key)
+    }
+
+    pub fn print(&self) -> Vec<(K, V)> {
+        info!("Printing all elements from pouch.");
+        let get_all: Vec<(K, V)> = self.entries()
+            .into_iter()
+            .map(|(key, value, _)| {
+                debug!("Valid entry: {}", key);
+                (key, value)
+            })
+            .collect();
+        info!("Total valid entries from pouch: {}", get_all.len());
+        get_all
+    }
+
+    // all the live entries, along with the time they have left to live
+    pub fn entries(&self) -> Vec<(K, V, Duration)> {
+        let now = Instant::now();
+        let mut entries = Vec::new();
+        for segment in self.segments.iter() {
+            let segment = segment.lock();
+            for (key, entry) in segment.map.iter() {
+                if entry.expires_at >= now {
+                    entries.push((key.clone(), entry.value.clone(), entry.expires_at - now));
+                }
+            }
+        }
+        entries
+    }
+
+    // removes up to `limit` expired entries, in expiry order within each segment, returns how many were removed
+    pub fn purge_expired(&self, limit: usize) -> usize {
+        let now = Instant::now();
+        let mut purged = 0;
+        for segment in self.segments.iter() {
+            if purged >= limit {
+                break;
+            }
+            purged += segment.lock().purge_expired(now, limit - purged);
+        }
+        self.expired.fetch_add(purged as u64, Ordering::Relaxed);
+        purged
+    }
+
+    // instant an entry put now with the given ttl, or the default expiry, expires at
+    fn expiry(&self, now: Instant, ttl: Option<Duration>) -> Instant {
+        now + ttl.unwrap_or(self.expires).min(MAX_TTL)
+    }
+
+    fn segment(&self, key: &K) -> &Mutex<Segment<K, V>> {
+        &self.segments[self.segment_index(key)]
+    }
+
+    fn segment_index(&self, key: &K) -> usize {
+        // segment count is a power of two
+        let hash = self.hash_builder.hash_one(key) as usize;
+        hash & (self.segments.len() - 1)
+    }
+}
+
+impl<K: Eq + Hash + Clone + Display, V> Segment<K, V> {
+    fn new(capacity: usize, policy: Box<dyn EvictionPolicy<K>>) -> Segment<K, V> {
+        Segment {
+            map: HashMap::new(),
+            policy,
+            capacity,
+            weight: 0,
+            expiry_index: BTreeMap::new(),
+            next_seq: 0,
+        }
+    }
+
+    // returns the number of entries evicted, an entry too heavy to be cached counts as evicted
+    fn put(&mut self, key: K, value: V, weight: usize, expires_at: Instant) -> u64 {
+        if weight > self.capacity {
+            warn!("Entry for key {} weighs {}, more than the capacity {} of its segment, not caching it", key, weight, self.capacity);
+            self.remove(&key);
+            return 1;
+        }
+
+        let evicted = if let Some(entry) = self.map.get_mut(&key) {
+            entry.value = value;
+            let old_expiry = entry.expires_at;
+            entry.expires_at = expires_at;
+            self.weight = self.weight - entry.weight + weight;
+            entry.weight = weight;
+            self.expiry_index.remove(&(old_expiry, entry.seq));
+            self.expiry_index.insert((expires_at, entry.seq), key.clone());
+            debug!("Updated existing entry for key: {}", key);
+            self.policy.on_access(&key, weight)
+        } else {
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            self.expiry_index.insert((expires_at, seq), key.clone());
+            self.map.insert(key.clone(), Entry { value, weight, expires_at, seq });
+            self.weight += weight;
+            debug!("Added new entry to the pouch with key: {}", key);
+            self.policy.on_insert(&key, weight)
+        };
+
+        self.evict(&key, evicted)
+    }
+
+    // drops the entries the policy evicted while `key` was put or read, returns how many there were
+    fn evict(&mut self, key: &K, evicted: Vec<K>) -> u64 {
+        for evicted in evicted.iter() {
+            if evicted == key {
+                debug!("Eviction policy did not admit key: {}", key);
+            } else {
+                warn!("Panda's pouch is full. Evicting key: {}", evicted);
+            }
+            self.detach_entry(evicted);
+        }
+        evicted.len() as u64
+    }
+
+    fn remove(&mut self, key: &K) -> Option<(K, V)> {
+        let removed = self.detach_entry(key)?;
+        self.policy.on_remove(key);
+        Some(removed)
+    }
+
+    // removes the entry without telling the policy, for the keys the policy evicted itself
+    fn detach_entry(&mut self, key: &K) -> Option<(K, V)> {
+        let (key, entry) = self.map.remove_entry(key)?;
+        self.expiry_index.remove(&(entry.expires_at, entry.seq));
+        self.weight -= entry.weight;
+        Some((key, entry.value))
+    }
+
+    fn purge_expired(&mut self, now: Instant, limit: usize) -> usize {
+        let expired: Vec<K> = self.expiry_index
+            .range(..(now, 0))
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect();
+
+        for key in expired.iter() {
+            debug!("Purging expired entry for key: {}", key);
+            self.remove(key);
+        }
+        expired.len()
+    }
+}
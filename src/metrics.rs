@@ -0,0 +1,316 @@
+// Prometheus metrics: counters and latency histograms recorded by the server, exported in the text format
+// by a small HTTP listener next to the gRPC server
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tonic::codegen::{http, BoxFuture, Service};
+use tower::Layer;
+
+use crate::lru::CacheStats;
+use crate::write_behind::WriteBehindStats;
+
+// upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 14] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// largest scrape request read, anything longer is not a metrics request
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+// how long a scrape may take before its connection is closed, and how many are served at once. the connections
+// above the limit are closed right away, so that idle clients cannot hold the listener
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_SCRAPES: usize = 32;
+
+// grpc status of the calls to methods the service does not have
+const GRPC_UNIMPLEMENTED: &str = "12";
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+// calls and failures of an operation, with their latency
+#[derive(Default)]
+struct OperationMetrics {
+    latency: Histogram,
+    errors: AtomicU64,
+}
+
+impl OperationMetrics {
+    fn record(&self, elapsed: Duration, failed: bool) {
+        self.latency.observe(elapsed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    rpcs: DashMap<String, OperationMetrics>,            // by gRPC method
+    db: DashMap<&'static str, OperationMetrics>,        // by database operation
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn record_rpc(&self, method: &str, elapsed: Duration, failed: bool) {
+        if let Some(rpc) = self.rpcs.get(method) {
+            rpc.record(elapsed, failed);
+            return;
+        }
+        self.rpcs.entry(method.to_string()).or_default().record(elapsed, failed);
+    }
+
+    pub fn record_db(&self, operation: &'static str, elapsed: Duration, failed: bool) {
+        self.db.entry(operation).or_default().record(elapsed, failed);
+    }
+
+    // renders the metrics, along with the state of the cache, the cluster and the write-behind queue if there is one,
+    // in the Prometheus text format
+    pub fn render(&self, cache: &CacheStats, members: usize, write_behind: Option<&WriteBehindStats>) -> String {
+        let mut out = String::new();
+
+        let rpcs: BTreeMap<String, (u64, u64)> = self.rpcs.iter()
+            .map(|rpc| {
+                let (count, errors) = (rpc.latency.count.load(Ordering::Relaxed), rpc.errors.load(Ordering::Relaxed));
+                (rpc.key().clone(), (count, errors))
+            })
+            .collect();
+        out.push_str("# HELP pandas_pouch_rpc_requests_total gRPC requests received, by method.\n");
+        out.push_str("# TYPE pandas_pouch_rpc_requests_total counter\n");
+        for (method, (count, _)) in rpcs.iter() {
+            let _ = writeln!(out, "pandas_pouch_rpc_requests_total{{method=\"{}\"}} {}", method, count);
+        }
+        out.push_str("# HELP pandas_pouch_rpc_errors_total gRPC requests that failed, by method.\n");
+        out.push_str("# TYPE pandas_pouch_rpc_errors_total counter\n");
+        for (method, (_, errors)) in rpcs.iter() {
+            let _ = writeln!(out, "pandas_pouch_rpc_errors_total{{method=\"{}\"}} {}", method, errors);
+        }
+        out.push_str("# HELP pandas_pouch_rpc_duration_seconds Latency of the gRPC requests, by method.\n");
+        out.push_str("# TYPE pandas_pouch_rpc_duration_seconds histogram\n");
+        for method in rpcs.keys() {
+            if let Some(rpc) = self.rpcs.get(method) {
+                rpc.latency.render(&mut out, "pandas_pouch_rpc_duration_seconds", &format!("method=\"{}\"", method));
+            }
+        }
+
+        let counters = [
+            ("pandas_pouch_cache_hits_total", "Reads served from the cache.", cache.hits),
+            ("pandas_pouch_cache_misses_total", "Reads the cache could not serve.", cache.misses),
+            ("pandas_pouch_cache_evictions_total", "Entries evicted to make room.", cache.evictions),
+            ("pandas_pouch_cache_expirations_total", "Entries removed because they expired.", cache.expired),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+        }
+        let gauges = [
+            ("pandas_pouch_cache_entries", "Entries in the cache.", cache.entries),
+            ("pandas_pouch_cache_weight", "Total weight of the entries in the cache.", cache.weight),
+            ("pandas_pouch_cache_capacity", "Maximum total weight of the entries in the cache.", cache.capacity),
+            ("pandas_pouch_cluster_members", "Members of the cluster, as seen by this node.", members),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+        }
+
+        if let Some(write_behind) = write_behind {
+            let gauges = [
+                ("pandas_pouch_write_behind_queue_depth", "Writes queued for the storage, not flushed yet.", write_behind.depth),
+                ("pandas_pouch_write_behind_queue_capacity", "Writes the write-behind queue holds before puts wait.", write_behind.capacity),
+            ];
+            for (name, help, value) in gauges {
+                let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+            }
+            let counters = [
+                ("pandas_pouch_write_behind_flushed_total", "Queued writes flushed to the storage.", write_behind.flushed),
+                ("pandas_pouch_write_behind_coalesced_total", "Queued writes replaced by a later write to the same key.", write_behind.coalesced),
+                ("pandas_pouch_write_behind_failed_total", "Flushes of queued writes the storage rejected.", write_behind.failed),
+            ];
+            for (name, help, value) in counters {
+                let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+            }
+        }
+
+        let operations: BTreeMap<&'static str, u64> = self.db.iter()
+            .map(|operation| (*operation.key(), operation.errors.load(Ordering::Relaxed)))
+            .collect();
+        out.push_str("# HELP pandas_pouch_db_errors_total Database operations that failed, by operation.\n");
+        out.push_str("# TYPE pandas_pouch_db_errors_total counter\n");
+        for (operation, errors) in operations.iter() {
+            let _ = writeln!(out, "pandas_pouch_db_errors_total{{operation=\"{}\"}} {}", operation, errors);
+        }
+        out.push_str("# HELP pandas_pouch_db_duration_seconds Latency of the database operations, by operation.\n");
+        out.push_str("# TYPE pandas_pouch_db_duration_seconds histogram\n");
+        for operation in operations.keys() {
+            if let Some(db) = self.db.get(operation) {
+                db.latency.render(&mut out, "pandas_pouch_db_duration_seconds", &format!("operation=\"{}\"", operation));
+            }
+        }
+        out
+    }
+}
+
+// records the count, failures and latency of every gRPC request served by the tonic server
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> MetricsLayer {
+        MetricsLayer { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService { inner, metrics: Arc::clone(&self.metrics) }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        // the path of a gRPC request is /package.Service/Method
+        let method = request.uri().path().rsplit('/').next().unwrap_or_default().to_string();
+        let metrics = Arc::clone(&self.metrics);
+        let started = Instant::now();
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = response.await;
+            // a failed call answers with its status in the headers, a successful one in the trailers
+            let status = response.as_ref().ok().and_then(|response| response.headers().get("grpc-status").cloned());
+            match status {
+                Some(status) if status == GRPC_UNIMPLEMENTED => {
+                    // not labelling by unknown methods, so clients can not grow the metrics without bound
+                    metrics.record_rpc("unknown", started.elapsed(), true);
+                },
+                status => {
+                    let failed = response.is_err() || status.is_some_and(|status| status != "0");
+                    metrics.record_rpc(&method, started.elapsed(), failed);
+                },
+            }
+            response
+        })
+    }
+}
+
+// serves the rendered metrics on GET /metrics, every connection is answered once and closed
+pub fn spawn_metrics_server(
+    listener: TcpListener,
+    render: impl Fn() -> String + Send + Sync + 'static,
+) -> JoinHandle<()> {
+    let render = Arc::new(render);
+    let scrapes = Arc::new(Semaphore::new(MAX_SCRAPES));
+    tokio::spawn(async move {
+        info!("Serving metrics on {:?}", listener.local_addr());
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("Could not accept metrics connection: {}", e);
+                    continue;
+                },
+            };
+            let Ok(scrape) = Arc::clone(&scrapes).try_acquire_owned() else {
+                warn!("Too many metrics connections, closing the one from {}", peer);
+                continue;
+            };
+            let render = Arc::clone(&render);
+            tokio::spawn(async move {
+                match tokio::time::timeout(SCRAPE_TIMEOUT, answer_scrape(stream, render.as_ref())).await {
+                    Ok(Ok(())) => {},
+                    Ok(Err(e)) => debug!("Metrics connection from {} failed: {}", peer, e),
+                    Err(_) => debug!("Metrics connection from {} timed out", peer),
+                }
+                drop(scrape);
+            });
+        }
+    })
+}
+
+async fn answer_scrape(mut stream: TcpStream, render: &(impl Fn() -> String + ?Sized)) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body,
+            )
+        },
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
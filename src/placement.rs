@@ -0,0 +1,320 @@
+// placement of the keys on the nodes: the Placement trait, implemented by the consistent hash ring, and the
+// rendezvous, jump and Maglev hashing alternatives
+
+use std::hash::{BuildHasher, Hasher};
+
+use crate::hash_ring::XxHash64Hasher;
+
+pub trait Placement<T> {
+    fn add_node(&mut self, node: &T);
+
+    fn remove_node(&mut self, node: &T);
+
+    // the node owning a key
+    fn get_node(&self, key: &str) -> Option<&T>;
+
+    // the n distinct nodes a key is replicated on, its owner first
+    fn get_nodes(&self, key: &str, n: usize) -> Vec<&T>;
+
+    fn node_count(&self) -> usize;
+}
+
+fn hash_bytes<S: BuildHasher>(hash_builder: &S, bytes: &[u8]) -> u64 {
+    let mut hasher = hash_builder.build_hasher();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+fn hash_pair<S: BuildHasher>(hash_builder: &S, a: u64, b: u64) -> u64 {
+    let mut hasher = hash_builder.build_hasher();
+    hasher.write_u64(a);
+    hasher.write_u64(b);
+    hasher.finish()
+}
+
+// smallest prime at or above n, and at least 2
+fn next_prime(n: usize) -> usize {
+    let is_prime = |n: usize| n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| !n.is_multiple_of(d));
+    (n.max(2)..).find(|n| is_prime(*n)).unwrap()
+}
+
+// Rendezvous (highest random weight) hashing: a key is owned by the node scoring the highest for it. the balance is
+// as good as the hash, no memory is needed beyond the nodes, and only the keys of a removed node move, but a lookup
+// scores every node
+#[derive(Clone)]
+pub struct Rendezvous<T, S = XxHash64Hasher> {
+    nodes: Vec<(T, u64)>,           // node -> seed, the hash of its name
+    hash_builder: S,
+}
+
+impl<T: ToString + Clone + PartialEq> Rendezvous<T, XxHash64Hasher> {
+    pub fn new(nodes: Vec<T>) -> Rendezvous<T, XxHash64Hasher> {
+        Rendezvous::with_hasher(nodes, XxHash64Hasher::default())
+    }
+}
+
+impl<T, S> Rendezvous<T, S>
+where
+    T: ToString + Clone + PartialEq,
+    S: BuildHasher,
+{
+    pub fn with_hasher(nodes: Vec<T>, hash_builder: S) -> Rendezvous<T, S> {
+        let mut rendezvous = Rendezvous { nodes: Vec::new(), hash_builder };
+        for node in &nodes {
+            Placement::add_node(&mut rendezvous, node);
+        }
+        rendezvous
+    }
+
+    fn scores(&self, key: &str) -> impl Iterator<Item = (u64, &T)> {
+        let key = hash_bytes(&self.hash_builder, key.as_bytes());
+        self.nodes.iter().map(move |(node, seed)| (hash_pair(&self.hash_builder, key, *seed), node))
+    }
+}
+
+impl<T, S> Placement<T> for Rendezvous<T, S>
+where
+    T: ToString + Clone + PartialEq,
+    S: BuildHasher,
+{
+    fn add_node(&mut self, node: &T) {
+        if self.nodes.iter().any(|(n, _)| n == node) {
+            return;
+        }
+        let seed = hash_bytes(&self.hash_builder, node.to_string().as_bytes());
+        self.nodes.push((node.clone(), seed));
+    }
+
+    fn remove_node(&mut self, node: &T) {
+        self.nodes.retain(|(n, _)| n != node);
+    }
+
+    fn get_node(&self, key: &str) -> Option<&T> {
+        self.scores(key).max_by_key(|(score, _)| *score).map(|(_, node)| node)
+    }
+
+    fn get_nodes(&self, key: &str, n: usize) -> Vec<&T> {
+        let mut scores: Vec<(u64, &T)> = self.scores(key).collect();
+        scores.sort_unstable_by_key(|(score, _)| std::cmp::Reverse(*score));
+        scores.into_iter().take(n).map(|(_, node)| node).collect()
+    }
+
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+// Jump consistent hashing (Lamping and Veach): a key is mapped to one of the numbered nodes with no memory and a
+// perfect balance. only the keys of the new node move when one is added at the end, but removing a node other than
+// the last renumbers the ones after it, moving their keys too. the replicas of a key are the nodes following its owner
+#[derive(Clone)]
+pub struct JumpHash<T, S = XxHash64Hasher> {
+    nodes: Vec<T>,
+    hash_builder: S,
+}
+
+impl<T: Clone + PartialEq> JumpHash<T, XxHash64Hasher> {
+    pub fn new(nodes: Vec<T>) -> JumpHash<T, XxHash64Hasher> {
+        JumpHash::with_hasher(nodes, XxHash64Hasher::default())
+    }
+}
+
+impl<T, S> JumpHash<T, S>
+where
+    T: Clone + PartialEq,
+    S: BuildHasher,
+{
+    pub fn with_hasher(nodes: Vec<T>, hash_builder: S) -> JumpHash<T, S> {
+        let mut jump = JumpHash { nodes: Vec::new(), hash_builder };
+        for node in &nodes {
+            Placement::add_node(&mut jump, node);
+        }
+        jump
+    }
+
+    // index of the node owning a key. there must be at least one node
+    fn bucket(&self, key: &str) -> usize {
+        let mut key = hash_bytes(&self.hash_builder, key.as_bytes());
+        let (mut b, mut j): (i64, i64) = (-1, 0);
+        while j < self.nodes.len() as i64 {
+            b = j;
+            key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+            j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+        }
+        b as usize
+    }
+}
+
+impl<T, S> Placement<T> for JumpHash<T, S>
+where
+    T: Clone + PartialEq,
+    S: BuildHasher,
+{
+    fn add_node(&mut self, node: &T) {
+        if !self.nodes.contains(node) {
+            self.nodes.push(node.clone());
+        }
+    }
+
+    fn remove_node(&mut self, node: &T) {
+        self.nodes.retain(|n| n != node);
+    }
+
+    fn get_node(&self, key: &str) -> Option<&T> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        self.nodes.get(self.bucket(key))
+    }
+
+    fn get_nodes(&self, key: &str, n: usize) -> Vec<&T> {
+        if self.nodes.is_empty() {
+            return Vec::new();
+        }
+        let owner = self.bucket(key);
+        (0..n.min(self.nodes.len())).map(|i| &self.nodes[(owner + i) % self.nodes.len()]).collect()
+    }
+
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+// default size of the Maglev lookup table, a prime much larger than the number of nodes
+pub const MAGLEV_TABLE_SIZE: usize = 65_537;
+
+// Maglev hashing: a key is owned by the node at its slot of a lookup table, that every node fills in the order of its
+// own permutation of the slots. the balance is close to perfect and a lookup is a single read, but the table is rebuilt
+// on every change, which moves a few keys between the nodes that stay
+#[derive(Clone)]
+pub struct Maglev<T, S = XxHash64Hasher> {
+    nodes: Vec<T>,
+    table: Vec<usize>,              // slot -> index of the node owning it, empty while there are no nodes
+    table_size: usize,
+    hash_builder: S,
+}
+
+impl<T: ToString + Clone + PartialEq> Maglev<T, XxHash64Hasher> {
+    pub fn new(nodes: Vec<T>) -> Maglev<T, XxHash64Hasher> {
+        Maglev::with_hasher(nodes, MAGLEV_TABLE_SIZE, XxHash64Hasher::default())
+    }
+}
+
+impl<T, S> Maglev<T, S>
+where
+    T: ToString + Clone + PartialEq,
+    S: BuildHasher,
+{
+    // the table size is rounded up to a prime, for every permutation to cover all the slots
+    pub fn with_hasher(nodes: Vec<T>, table_size: usize, hash_builder: S) -> Maglev<T, S> {
+        let mut nodes_without_duplicates: Vec<T> = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            if !nodes_without_duplicates.contains(&node) {
+                nodes_without_duplicates.push(node);
+            }
+        }
+        let mut maglev = Maglev {
+            nodes: nodes_without_duplicates,
+            table: Vec::new(),
+            table_size: next_prime(table_size),
+            hash_builder,
+        };
+        maglev.populate();
+        maglev
+    }
+
+    fn populate(&mut self) {
+        let size = self.table_size;
+        self.table.clear();
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        // the permutation of a node visits the slots offset, offset + skip, offset + 2 * skip... modulo the size
+        let permutations: Vec<(usize, usize)> = self.nodes.iter()
+            .map(|node| {
+                let name = hash_bytes(&self.hash_builder, node.to_string().as_bytes());
+                let offset = hash_pair(&self.hash_builder, name, 0) as usize % size;
+                let skip = hash_pair(&self.hash_builder, name, 1) as usize % (size - 1) + 1;
+                (offset, skip)
+            })
+            .collect();
+
+        self.table = vec![usize::MAX; size];
+        let mut next = vec![0usize; self.nodes.len()];
+        let mut filled = 0;
+        loop {
+            for (i, (offset, skip)) in permutations.iter().enumerate() {
+                let mut slot = (offset + next[i] * skip) % size;
+                while self.table[slot] != usize::MAX {
+                    next[i] += 1;
+                    slot = (offset + next[i] * skip) % size;
+                }
+                self.table[slot] = i;
+                next[i] += 1;
+                filled += 1;
+                if filled == size {
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn table_size(&self) -> usize {
+        self.table_size
+    }
+
+    fn slot(&self, key: &str) -> usize {
+        hash_bytes(&self.hash_builder, key.as_bytes()) as usize % self.table_size
+    }
+}
+
+impl<T, S> Placement<T> for Maglev<T, S>
+where
+    T: ToString + Clone + PartialEq,
+    S: BuildHasher,
+{
+    fn add_node(&mut self, node: &T) {
+        if !self.nodes.contains(node) {
+            self.nodes.push(node.clone());
+            self.populate();
+        }
+    }
+
+    fn remove_node(&mut self, node: &T) {
+        let count = self.nodes.len();
+        self.nodes.retain(|n| n != node);
+        if self.nodes.len() != count {
+            self.populate();
+        }
+    }
+
+    fn get_node(&self, key: &str) -> Option<&T> {
+        let index = *self.table.get(self.slot(key))?;
+        self.nodes.get(index)
+    }
+
+    // the replicas are the next distinct nodes of the table, following the slot of the key
+    fn get_nodes(&self, key: &str, n: usize) -> Vec<&T> {
+        let n = n.min(self.nodes.len());
+        let mut nodes: Vec<&T> = Vec::with_capacity(n);
+        if self.table.is_empty() {
+            return nodes;
+        }
+        let start = self.slot(key);
+        for i in 0..self.table_size {
+            if nodes.len() == n {
+                break;
+            }
+            let node = &self.nodes[self.table[(start + i) % self.table_size]];
+            if !nodes.contains(&node) {
+                nodes.push(node);
+            }
+        }
+        nodes
+    }
+
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+}
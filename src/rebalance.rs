@@ -0,0 +1,234 @@
+// Rebalancing: moves the cached entries to their new owners when the ring topology changes
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use bytes::Bytes;
+use log::{debug, error, info, warn};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::cluster::Cluster;
+use crate::hash_ring::{HashRing, NodeInfo};
+use crate::lru::LRUCache;
+use crate::pandas_pouch::KeyValuePair;
+
+// entries sent per Migrate stream, progress is reported after each batch
+const MIGRATION_BATCH_SIZE: usize = 256;
+
+// a range of ring positions whose owner changed, from `start` (exclusive) to `end` (inclusive).
+// when `start >= end` the range wraps around the end of the ring
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MovedRange {
+    pub start: u64,
+    pub end: u64,
+    pub from: NodeInfo,
+    pub to: NodeInfo,
+}
+
+impl MovedRange {
+    pub fn contains(&self, position: u64) -> bool {
+        if self.start < self.end {
+            self.start < position && position <= self.end
+        } else {
+            position > self.start || position <= self.end
+        }
+    }
+}
+
+// computes the ranges of the ring that are owned by a different node in the new ring.
+// between two consecutive virtual nodes of either ring, every position has the same owner in both rings,
+// so comparing the owners at each virtual node is enough
+pub fn moved_ranges(old: &HashRing<NodeInfo>, new: &HashRing<NodeInfo>) -> Vec<MovedRange> {
+    if old.sorted_keys.is_empty() || new.sorted_keys.is_empty() {
+        return Vec::new();
+    }
+
+    let boundaries: Vec<u64> = old.sorted_keys.iter()
+        .chain(new.sorted_keys.iter())
+        .copied()
+        .collect::<BTreeSet<u64>>()
+        .into_iter()
+        .collect();
+
+    let mut ranges: Vec<MovedRange> = Vec::new();
+    for (i, end) in boundaries.iter().enumerate() {
+        let start = if i == 0 { boundaries[boundaries.len() - 1] } else { boundaries[i - 1] };
+        let (from, to) = match (old.node_at(*end), new.node_at(*end)) {
+            (Some(from), Some(to)) if from != to => (from, to),
+            _ => continue,
+        };
+
+        // merging with the previous range when it moved between the same nodes
+        if let Some(last) = ranges.last_mut() {
+            if last.end == start && last.from == *from && last.to == *to {
+                last.end = *end;
+                continue;
+            }
+        }
+        ranges.push(MovedRange { start, end: *end, from: from.clone(), to: to.clone() });
+    }
+    ranges
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RebalanceStatus {
+    pub in_progress: bool,
+    pub moved_ranges: usize,
+    pub total: usize,           // entries to move in the current (or last) rebalance
+    pub transferred: usize,
+    pub failed: usize,
+}
+
+#[derive(Default)]
+struct RebalanceProgress {
+    in_progress: AtomicBool,
+    moved_ranges: AtomicUsize,
+    total: AtomicUsize,
+    transferred: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+pub struct Rebalancer {
+    cache: Arc<LRUCache<String, Bytes>>,
+    cluster: Arc<Cluster>,
+    progress: RebalanceProgress,
+    running: Mutex<()>,         // one rebalance at a time
+}
+
+impl Rebalancer {
+    pub fn new(cache: Arc<LRUCache<String, Bytes>>, cluster: Arc<Cluster>) -> Rebalancer {
+        Rebalancer {
+            cache,
+            cluster,
+            progress: RebalanceProgress::default(),
+            running: Mutex::new(()),
+        }
+    }
+
+    pub fn status(&self) -> RebalanceStatus {
+        RebalanceStatus {
+            in_progress: self.progress.in_progress.load(Ordering::Relaxed),
+            moved_ranges: self.progress.moved_ranges.load(Ordering::Relaxed),
+            total: self.progress.total.load(Ordering::Relaxed),
+            transferred: self.progress.transferred.load(Ordering::Relaxed),
+            failed: self.progress.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    // rebalances on every topology change of the cluster
+    pub fn spawn(self: &Arc<Self>) -> JoinHandle<()> {
+        let rebalancer = Arc::clone(self);
+        let mut ring_updates = self.cluster.watch_ring();
+        tokio::spawn(async move {
+            let mut old = ring_updates.borrow_and_update().clone();
+            while ring_updates.changed().await.is_ok() {
+                let new = ring_updates.borrow_and_update().clone();
+                rebalancer.rebalance(&old, &new).await;
+                old = new;
+            }
+        })
+    }
+
+    // hands every entry of this node off to the rest of the cluster, before it leaves
+    pub async fn hand_off(&self) -> RebalanceStatus {
+        let old = self.cluster.ring();
+        let new = self.cluster.ring_without_self();
+        self.rebalance(&old, &new).await
+    }
+
+    // copies the local entries to the nodes that became one of their replicas. a key is sent by the first of its
+    // previous replicas that is still in the cluster. when none is, its previous owner sends it, as when this node
+    // hands everything off before leaving. the other previous replicas drop the key right away if they are no longer
+    // one of its replicas. the sender drops it once every new replica received it. the entries stay readable here
+    // until then, and the receivers keep the values written to them in the meantime, so reads and writes keep
+    // working while the transfer is in progress
+    pub async fn rebalance(&self, old: &HashRing<NodeInfo>, new: &HashRing<NodeInfo>) -> RebalanceStatus {
+        let _running = self.running.lock().await;
+        let self_node = self.cluster.self_node();
+        let replication_factor = self.cluster.replication_factor();
+
+        let ranges: Vec<MovedRange> = moved_ranges(old, new)
+            .into_iter()
+            .filter(|range| range.from == *self_node)
+            .collect();
+
+        let entries = self.cache.entries();
+        let mut moves: HashMap<NodeInfo, Vec<KeyValuePair>> = HashMap::new();
+        for (key, value, ttl) in entries {
+            let previous = old.get_nodes(&key, replication_factor);
+            let sender = previous.iter()
+                .find(|node| new.weight(**node).is_some())
+                .or(previous.first());
+            let replicas = new.get_nodes(&key, replication_factor);
+            if sender != Some(&self_node) {
+                if !replicas.contains(&self_node) {
+                    self.cache.remove(key);
+                }
+                continue;
+            }
+            for target in replicas {
+                if !previous.contains(&target) {
+                    let pair = KeyValuePair { key: key.clone(), value: value.clone(), ttl_ms: Some(ttl.as_millis() as u64) };
+                    moves.entry(target.clone()).or_default().push(pair);
+                }
+            }
+        }
+
+        let total = moves.values().map(Vec::len).sum();
+        self.progress.moved_ranges.store(ranges.len(), Ordering::Relaxed);
+        self.progress.total.store(total, Ordering::Relaxed);
+        self.progress.transferred.store(0, Ordering::Relaxed);
+        self.progress.failed.store(0, Ordering::Relaxed);
+        self.progress.in_progress.store(true, Ordering::Relaxed);
+        info!("Rebalancing {}: {} ranges moved away, {} entries to transfer", self_node, ranges.len(), total);
+
+        let mut failed = HashSet::new();
+        for (target, pairs) in moves.iter() {
+            for batch in pairs.chunks(MIGRATION_BATCH_SIZE) {
+                if !self.transfer(target, batch.to_vec()).await {
+                    failed.extend(batch.iter().map(|pair| pair.key.clone()));
+                }
+            }
+        }
+
+        // the entries this node is not a replica of anymore, and that reached all their new replicas
+        for key in moves.values().flatten().map(|pair| &pair.key) {
+            if !failed.contains(key) && !new.get_nodes(key, replication_factor).contains(&self_node) {
+                self.cache.remove(key.clone());
+            }
+        }
+
+        self.progress.in_progress.store(false, Ordering::Relaxed);
+        let status = self.status();
+        info!("Rebalancing {} done: {} entries transferred, {} failed", self_node, status.transferred, status.failed);
+        status
+    }
+
+    // sends a batch of entries to the target, returning whether it received them
+    async fn transfer(&self, target: &NodeInfo, batch: Vec<KeyValuePair>) -> bool {
+        let keys: Vec<String> = batch.iter().map(|pair| pair.key.clone()).collect();
+        let response = match self.cluster.client(target) {
+            Ok(mut client) => client.migrate(tokio_stream::iter(batch)).await,
+            Err(e) => Err(e),
+        };
+
+        match response {
+            Ok(response) => {
+                let response = response.into_inner();
+                debug!("Migrated {} entries to {}, {} stored", response.received, target, response.stored);
+
+                let transferred = self.progress.transferred.fetch_add(keys.len(), Ordering::Relaxed) + keys.len();
+                let total = self.progress.total.load(Ordering::Relaxed);
+                info!("Rebalancing progress: {}/{} entries transferred", transferred, total);
+                true
+            },
+            Err(e) => {
+                error!("Failed to migrate {} entries to {}: {}", keys.len(), target, e.message());
+                self.progress.failed.fetch_add(keys.len(), Ordering::Relaxed);
+                warn!("Entries that failed to migrate stay on {} until the next rebalance", self.cluster.self_node());
+                false
+            },
+        }
+    }
+}
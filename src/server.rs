@@ -1,146 +1,1185 @@
-use std::sync::Arc;
-use std::string::String;
-use log::{debug, error, info};
-use tokio::sync::Mutex;
-use tonic::{async_trait, Request, Response, Status};
-use tonic::transport::Server;
-
-use pandas_pouch::pandas_pouch_cache_service_server::{PandasPouchCacheService, PandasPouchCacheServiceServer};
-use pandas_pouch::{
-    GetRequest, 
-    GetResponse, 
-    PutRequest, 
-    PutResponse, 
-    PrintAllRequest, 
-    PrintAllResponse, 
-    KeyValuePair,
-    JoinClusterRequest,
-    JoinClusterResponse,
-    LeaveClusterRequest,
-    LeaveClusterResponse,
-};
-use crate::db::Database;
-use crate::lru::LRUCache;
-
-pub mod pandas_pouch {
-    tonic::include_proto!("pandas_pouch");
-}
-
-pub struct CacheServiceImpl {
-    cache: Arc<Mutex<LRUCache<String, String>>>,
-    db: Arc<Database>,
-}
-
-#[async_trait]
-impl PandasPouchCacheService for CacheServiceImpl {
-    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
-        let key = request.into_inner().key;
-        info!("GET: key: {}", key);
-
-        // getting the key, from the in-memory cache
-        let mut cache = self.cache.lock().await;
-        if let Some(value) = cache.get(&key) {
-            debug!("Cache hit for key: {}", key);
-            return Ok(Response::new(GetResponse {
-                found: true,
-                value,
-            }));
-        }
-
-        // if not in the memory, trying to get in the database
-        debug!("Cache miss for key: {}", key);
-        match self.db.get(&key).await {
-            Ok(Some(value)) => {
-                // updating the in-memory cache
-                debug!("Found value in database for key: {}", key);
-                cache.put(key.clone(), value.clone());
-                Ok(Response::new(GetResponse {
-                    found: true,
-                    value,
-                }))
-            },
-            Ok(None) => {
-                info!("Key not found in cache or database: {}", key);
-                Ok(Response::new(GetResponse {
-                    found: false,
-                    value: String::new(),
-                }))
-            },
-            Err(e) => {
-                error!("Database error while getting key {}: {}", key, e);
-                Err(Status::internal(format!("Database error: {}", e)))
-            },
-        }
-    }
-
-    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
-        let req = request.into_inner();
-        info!("PUT: {}", req.key);
-
-        // update the in-memory cache
-        let mut cache = self.cache.lock().await;
-        cache.put(req.key.clone(), req.value.clone());
-
-        // updating the database
-        match self.db.put(&req.key, &req.value).await {
-            Ok(_) => {
-                debug!("Successfully put key-value pair in cache and database");
-                Ok(Response::new(PutResponse { success: true })) 
-            },
-            Err(e) => {
-                error!("Database error while putting key {}: {}", req.key, e);
-                Err(Status::internal(format!("Database error:  {}", e))) 
-            },
-        }
-    }
-
-    async fn print_all(&self, _request: Request<PrintAllRequest>) -> Result<Response<PrintAllResponse>, Status> {
-        info!("Received PrintAll request");
-        let mut cache = self.cache.lock().await;
-        let pairs = cache.print().into_iter().map(|(k, v)| {
-            debug!("Printing cache entry: {} -> {}", k, v);
-            KeyValuePair {
-                key: k,
-                value: v,
-            }
-        }).collect::<Vec<_>>();
-
-        info!("Returning {} cache entries", pairs.len());
-        Ok(Response::new(PrintAllResponse { pairs }))
-    }
-
-    async fn forward_get(&self, _request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
-        Err(Status::unimplemented("Not implemented"))
-    }
-
-    async fn forward_put(&self, _request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
-        Err(Status::unimplemented("Not implemented"))
-    }
-
-    async fn join_cluster(&self, _request: Request<JoinClusterRequest>) -> Result<Response<JoinClusterResponse>, Status> {
-        Err(Status::unimplemented("Not implemented"))
-    }
-
-    async fn leave_cluster(&self, _request: Request<LeaveClusterRequest>) -> Result<Response<LeaveClusterResponse>, Status> {
-        Err(Status::unimplemented("Not implemented"))
-    }
-}
-
-pub async fn run_server(addr: &str, database_url: &str) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Initializing server with address: {}", addr);
-    let cache = Arc::new(Mutex::new(LRUCache::new(10, None)));          // keeping capacity 10 for now
-    let db: Arc<Database> = Arc::new(Database::new(database_url).await?);
-
-    db.create_table_if_not_exists().await?;
-    info!("Database table created or verified");
-
-    let service = CacheServiceImpl { cache, db };
-
-    info!("Starting server on {}", addr);
-    Server::builder()
-        .add_service(PandasPouchCacheServiceServer::new(service))
-        .serve(addr.parse()?)
-        .await?;
-
-    Ok(())
-}
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::string::String;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use dashmap::DashMap;
+use log::{debug, error, info, warn};
+use tokio::task::{JoinHandle, JoinSet};
+use tonic::{async_trait, Request, Response, Status, Streaming};
+use tonic::transport::Server;
+
+use crate::pandas_pouch::pandas_pouch_cache_service_server::{PandasPouchCacheService, PandasPouchCacheServiceServer};
+use crate::pandas_pouch::{
+    GetRequest,
+    GetResponse,
+    PutRequest,
+    PutResponse,
+    DeleteRequest,
+    DeleteResponse,
+    PrintAllRequest,
+    PrintAllResponse,
+    StatsRequest,
+    StatsResponse,
+    KeyValuePair,
+    MultiGetRequest,
+    MultiGetResponse,
+    MultiPutRequest,
+    MultiPutResponse,
+    JoinClusterRequest,
+    JoinClusterResponse,
+    LeaveClusterRequest,
+    LeaveClusterResponse,
+    ListNodesRequest,
+    ListNodesResponse,
+    MigrateResponse,
+    PersistenceMode as RequestedPersistence,
+};
+use crate::cluster::{Cluster, PeerClient, HANDOFF_WINDOW};
+use crate::config::{CapacityUnit, PersistenceMode, Settings};
+use crate::db::{self, unix_time_ms, Storage};
+use crate::error::{is_retryable, PouchError};
+use crate::hash_ring::NodeInfo;
+use crate::lru::LRUCache;
+use crate::metrics::{spawn_metrics_server, Metrics, MetricsLayer};
+use crate::rebalance::Rebalancer;
+use crate::write_behind::WriteBehind;
+
+// expired entries reclaimed per batch by the sweeper
+const SWEEP_BATCH_SIZE: usize = 512;
+
+// marks a get sent to the previous owner of a key during a handoff, which must not be handed off any further
+const HANDOFF_HEADER: &str = "pouch-handoff";
+
+// time to live requested by a put, either relative or as an absolute expiry
+fn put_ttl(req: &PutRequest) -> Option<Duration> {
+    match (req.ttl_ms, req.expires_at_ms) {
+        (Some(ttl_ms), _) => Some(Duration::from_millis(ttl_ms)),
+        (None, Some(expires_at_ms)) => Some(Duration::from_millis(expires_at_ms.saturating_sub(unix_time_ms() as u64))),
+        (None, None) => None,
+    }
+}
+
+// the responses of the replicas that applied a write. the write fails only when none of them did, with the
+// error of the owner
+fn applied<Res>(key: &str, results: Vec<(NodeInfo, Result<Res, Status>)>) -> Result<Vec<Res>, Status> {
+    let mut responses = Vec::new();
+    let mut failure = None;
+    for (replica, result) in results {
+        match result {
+            Ok(response) => responses.push(response),
+            Err(status) => {
+                warn!("Replica {} could not apply the write of key {}: {}", replica, key, status.message());
+                failure.get_or_insert(status);
+            },
+        }
+    }
+    match (responses.is_empty(), failure) {
+        (true, Some(status)) => Err(status),
+        _ => Ok(responses),
+    }
+}
+
+// counters of the requests served by this node, the cache keeps its own
+#[derive(Default)]
+struct RequestCounters {
+    gets: AtomicU64,
+    puts: AtomicU64,
+    deletes: AtomicU64,
+    forwarded: AtomicU64,
+    db_reads: AtomicU64,
+    db_hits: AtomicU64,
+    db_errors: AtomicU64,
+}
+
+fn increment(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub struct CacheServiceImpl {
+    cache: Arc<LRUCache<String, Bytes>>,
+    db: Option<Arc<dyn Storage>>,       // None runs the node as a pure in-memory cache
+    write_behind: Option<Arc<WriteBehind>>,     // queues the writes to db, instead of waiting for them
+    persistence: PersistenceMode,       // of the requests that do not pick their own
+    cluster: Arc<Cluster>,
+    deleted: DashMap<String, Instant>,  // keys deleted during a handoff, which the migrations must not bring back
+    counters: RequestCounters,
+    metrics: Arc<Metrics>,
+    started_at: Instant,
+    max_key_bytes: usize,
+    max_value_bytes: usize,
+}
+
+impl CacheServiceImpl {
+    pub fn new(cache: Arc<LRUCache<String, Bytes>>, db: Option<Arc<dyn Storage>>, cluster: Arc<Cluster>) -> Self {
+        // a node with a database reads and writes through it by default
+        let persistence = if db.is_some() { PersistenceMode::WriteThrough } else { PersistenceMode::None };
+        CacheServiceImpl {
+            cache,
+            db,
+            write_behind: None,
+            persistence,
+            cluster,
+            deleted: DashMap::new(),
+            counters: RequestCounters::default(),
+            metrics: Arc::new(Metrics::new()),
+            started_at: Instant::now(),
+            max_key_bytes: usize::MAX,
+            max_value_bytes: usize::MAX,
+        }
+    }
+
+    // records the database latencies and errors in the given metrics
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    // picks how the requests that do not override it use the database
+    pub fn with_persistence(mut self, persistence: PersistenceMode) -> Self {
+        self.persistence = persistence;
+        self
+    }
+
+    // queues the writes of the write-behind requests for the database, so they do not wait for it
+    pub fn with_write_behind(mut self, write_behind: Arc<WriteBehind>) -> Self {
+        self.write_behind = Some(write_behind);
+        self
+    }
+
+    // rejects the requests of clients with longer keys or larger values, there is no limit otherwise
+    pub fn with_limits(mut self, max_key_bytes: usize, max_value_bytes: usize) -> Self {
+        self.max_key_bytes = max_key_bytes;
+        self.max_value_bytes = max_value_bytes;
+        self
+    }
+
+    fn check_key(&self, key: &str) -> Result<(), PouchError> {
+        if key.is_empty() {
+            return Err(PouchError::InvalidKey("Keys can not be empty".to_string()));
+        }
+        if key.len() > self.max_key_bytes {
+            return Err(PouchError::InvalidKey(format!("Key of {} bytes is over the limit of {} bytes", key.len(), self.max_key_bytes)));
+        }
+        Ok(())
+    }
+
+    fn check_value(&self, value: &[u8]) -> Result<(), PouchError> {
+        match value.len() > self.max_value_bytes {
+            true => Err(PouchError::ValueTooLarge { size: value.len(), limit: self.max_value_bytes }),
+            false => Ok(()),
+        }
+    }
+
+    pub fn current_stats(&self) -> StatsResponse {
+        let cache = self.cache.stats();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StatsResponse {
+            hits: cache.hits,
+            misses: cache.misses,
+            evictions: cache.evictions,
+            expirations: cache.expired,
+            entries: cache.entries as u64,
+            weight: cache.weight as u64,
+            capacity: cache.capacity as u64,
+            gets: load(&self.counters.gets),
+            puts: load(&self.counters.puts),
+            deletes: load(&self.counters.deletes),
+            forwarded: load(&self.counters.forwarded),
+            db_reads: load(&self.counters.db_reads),
+            db_hits: load(&self.counters.db_hits),
+            db_errors: load(&self.counters.db_errors),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            write_behind_depth: self.write_behind.as_ref().map_or(0, |write_behind| write_behind.depth() as u64),
+        }
+    }
+
+    // the persistence mode of a request, the mode of the server unless the request overrides it
+    fn persistence(&self, requested: i32) -> Result<PersistenceMode, Status> {
+        let mode = match RequestedPersistence::try_from(requested) {
+            Ok(RequestedPersistence::PersistenceDefault) => return Ok(self.persistence),
+            Ok(RequestedPersistence::PersistenceNone) => PersistenceMode::None,
+            Ok(RequestedPersistence::PersistenceReadThrough) => PersistenceMode::ReadThrough,
+            Ok(RequestedPersistence::PersistenceWriteThrough) => PersistenceMode::WriteThrough,
+            Ok(RequestedPersistence::PersistenceWriteBehind) => PersistenceMode::WriteBehind,
+            Ok(RequestedPersistence::PersistenceWriteAround) => PersistenceMode::WriteAround,
+            Err(_) => return Err(PouchError::InvalidPersistence(format!("Unknown persistence mode {}", requested)).into()),
+        };
+        if mode.reads_storage() {
+            self.storage()?;
+        }
+        if mode == PersistenceMode::WriteBehind {
+            self.queue()?;
+        }
+        Ok(mode)
+    }
+
+    // the persistence mode of a replicated write on this node, which only updates its cache when it is not the owner
+    fn replica_persistence(&self, requested: i32, owner: bool) -> Result<PersistenceMode, Status> {
+        let persistence = self.persistence(requested)?;
+        Ok(if owner { persistence } else { PersistenceMode::None })
+    }
+
+    fn storage(&self) -> Result<&Arc<dyn Storage>, Status> {
+        self.db.as_ref().ok_or_else(|| PouchError::NotConfigured("This node runs without a database".to_string()).into())
+    }
+
+    fn queue(&self) -> Result<&Arc<WriteBehind>, Status> {
+        self.write_behind.as_ref().ok_or_else(|| PouchError::NotConfigured("This node has no write-behind queue".to_string()).into())
+    }
+
+    // serves a get from this node, without forwarding it
+    async fn local_get(&self, key: String, persistence: PersistenceMode, handoff: bool) -> Result<Response<GetResponse>, Status> {
+        // getting the key, from the in-memory cache. a handoff get was already counted by the node that sent it
+        let cached = if handoff { self.cache.get_with_ttl(&key) } else { self.cache.peek_with_ttl(&key) };
+        if let Some((value, ttl)) = cached {
+            debug!("Cache hit for key: {}", key);
+            return Ok(Response::new(GetResponse {
+                found: true,
+                value,
+                ttl_ms: Some(ttl.as_millis() as u64),
+            }));
+        }
+
+        // the key may not have been migrated from its previous owner yet
+        if handoff {
+            if let Some((value, ttl)) = self.handoff_get(&key).await {
+                self.cache.put_if_absent(key.clone(), value.clone(), ttl);
+                return Ok(Response::new(GetResponse {
+                    found: true,
+                    value,
+                    ttl_ms: ttl.map(|ttl| ttl.as_millis() as u64),
+                }));
+            }
+        }
+
+        if !persistence.reads_storage() {
+            info!("Key not found in cache: {}", key);
+            return Ok(Response::new(GetResponse {
+                found: false,
+                value: Bytes::new(),
+                ttl_ms: None,
+            }));
+        }
+
+        // a write queued for the storage is newer than the value it holds
+        if let Some(queued) = self.write_behind.as_ref().and_then(|write_behind| write_behind.lookup(&key)) {
+            debug!("Found queued write for key: {}", key);
+            let Some((value, ttl)) = queued else {
+                return Ok(Response::new(GetResponse {
+                    found: false,
+                    value: Bytes::new(),
+                    ttl_ms: None,
+                }));
+            };
+            self.cache.put_with_ttl(key.clone(), value.clone(), ttl);
+            return Ok(Response::new(GetResponse {
+                found: true,
+                value,
+                ttl_ms: ttl.map(|ttl| ttl.as_millis() as u64),
+            }));
+        }
+
+        // if not in the memory, trying to get in the database
+        debug!("Cache miss for key: {}", key);
+        let db = self.storage()?;
+        increment(&self.counters.db_reads);
+        let started = Instant::now();
+        let result = db.get(&key).await;
+        self.metrics.record_db("get", started.elapsed(), result.is_err());
+        match result {
+            Ok(Some((value, ttl))) => {
+                increment(&self.counters.db_hits);
+                // updating the in-memory cache, the entry must not outlive the row
+                debug!("Found value in database for key: {}", key);
+                let value = Bytes::from(value);
+                self.cache.put_with_ttl(key.clone(), value.clone(), ttl);
+                Ok(Response::new(GetResponse {
+                    found: true,
+                    value,
+                    ttl_ms: ttl.map(|ttl| ttl.as_millis() as u64),
+                }))
+            },
+            Ok(None) => {
+                info!("Key not found in cache or database: {}", key);
+                Ok(Response::new(GetResponse {
+                    found: false,
+                    value: Bytes::new(),
+                    ttl_ms: None,
+                }))
+            },
+            Err(e) => {
+                increment(&self.counters.db_errors);
+                error!("Database error while getting key {}: {}", key, e);
+                Err(e.into())
+            },
+        }
+    }
+
+    // asks the previous owner of a key for it, while the key may not have been migrated yet
+    async fn handoff_get(&self, key: &str) -> Option<(Bytes, Option<Duration>)> {
+        let previous_owner = self.cluster.previous_owner(key)?;
+        // the read of the cache was already counted
+        if previous_owner == *self.cluster.self_node() {
+            return self.cache.peek_with_ttl(&key.to_string()).map(|(value, ttl)| (value, Some(ttl)));
+        }
+        debug!("Asking previous owner {} for key {}", previous_owner, key);
+
+        let mut request = Request::new(GetRequest { key: key.to_string(), ..Default::default() });
+        request.metadata_mut().insert(HANDOFF_HEADER, "1".parse().unwrap());
+        match self.cluster.client(&previous_owner).ok()?.forward_get(request).await {
+            Ok(response) => {
+                let response = response.into_inner();
+                response.found.then(|| (response.value, response.ttl_ms.map(Duration::from_millis)))
+            },
+            Err(e) => {
+                debug!("Previous owner {} could not serve key {}: {}", previous_owner, key, e.message());
+                None
+            },
+        }
+    }
+
+    // asks the previous owners of many keys for them, with a single request to each of them
+    async fn handoff_multi_get(&self, keys: &[String]) -> HashMap<String, (Bytes, Option<Duration>)> {
+        let mut by_previous_owner: HashMap<NodeInfo, Vec<String>> = HashMap::new();
+        for key in keys {
+            if let Some(previous_owner) = self.cluster.previous_owner(key) {
+                by_previous_owner.entry(previous_owner).or_default().push(key.clone());
+            }
+        }
+
+        let mut handed_off = HashMap::new();
+        for (previous_owner, keys) in by_previous_owner {
+            // the read of the cache was already counted
+            if previous_owner == *self.cluster.self_node() {
+                for key in keys {
+                    if let Some((value, ttl)) = self.cache.peek_with_ttl(&key) {
+                        handed_off.insert(key, (value, Some(ttl)));
+                    }
+                }
+                continue;
+            }
+            debug!("Asking previous owner {} for {} keys", previous_owner, keys.len());
+
+            let mut request = Request::new(MultiGetRequest { keys: keys.clone(), ..Default::default() });
+            request.metadata_mut().insert(HANDOFF_HEADER, "1".parse().unwrap());
+            let Ok(mut client) = self.cluster.client(&previous_owner) else {
+                continue;
+            };
+            match client.forward_multi_get(request).await {
+                Ok(response) => {
+                    for (key, value) in keys.into_iter().zip(response.into_inner().values) {
+                        if value.found {
+                            handed_off.insert(key, (value.value, value.ttl_ms.map(Duration::from_millis)));
+                        }
+                    }
+                },
+                Err(e) => debug!("Previous owner {} could not serve {} keys: {}", previous_owner, keys.len(), e.message()),
+            }
+        }
+        handed_off
+    }
+
+    // serves a put on this node, without forwarding it
+    async fn local_put(&self, req: PutRequest, persistence: PersistenceMode) -> Result<Response<PutResponse>, Status> {
+        // update the in-memory cache
+        let ttl = put_ttl(&req);
+        if persistence == PersistenceMode::WriteAround {
+            // the next read loads the value from the database
+            self.cache.remove(req.key.clone());
+        } else {
+            self.cache.put_with_ttl(req.key.clone(), req.value.clone(), ttl);
+            self.deleted.remove(&req.key);
+        }
+
+        self.persist_put(req.key, req.value, ttl, persistence).await?;
+        Ok(Response::new(PutResponse { success: true }))
+    }
+
+    // writes a put already applied to the cache to the database, as the persistence mode asks
+    async fn persist_put(&self, key: String, value: Bytes, ttl: Option<Duration>, persistence: PersistenceMode) -> Result<(), Status> {
+        match persistence {
+            PersistenceMode::None | PersistenceMode::ReadThrough => {
+                debug!("Successfully put key-value pair in cache");
+                return Ok(());
+            },
+            PersistenceMode::WriteBehind => {
+                // waits only while the queue is full
+                self.queue()?.put(key, value, ttl).await;
+                debug!("Successfully put key-value pair in cache, queued for the database");
+                return Ok(());
+            },
+            PersistenceMode::WriteThrough | PersistenceMode::WriteAround => {},
+        }
+
+        // updating the database
+        let started = Instant::now();
+        let result = self.storage()?.put(&key, &value, ttl).await;
+        self.metrics.record_db("put", started.elapsed(), result.is_err());
+        if let Err(e) = result {
+            increment(&self.counters.db_errors);
+            error!("Database error while putting key {}: {}", key, e);
+            return Err(e.into());
+        }
+
+        // a write to the key still queued would overwrite this one once flushed
+        if let Some(write_behind) = &self.write_behind {
+            if write_behind.lookup(&key).is_some() {
+                write_behind.put(key, value, ttl).await;
+            }
+        }
+        debug!("Successfully put key-value pair in cache and database");
+        Ok(())
+    }
+
+    // serves a batch of gets from this node, without forwarding them. the cache is read once for all the keys, and
+    // the misses the storage is asked for are read in a single query
+    async fn local_multi_get(&self, keys: Vec<String>, persistence: PersistenceMode, handoff: bool) -> Result<Vec<GetResponse>, Status> {
+        let found = |value: Bytes, ttl: Option<Duration>| GetResponse {
+            found: true,
+            value,
+            ttl_ms: ttl.map(|ttl| ttl.as_millis() as u64),
+        };
+        // a handoff get was already counted by the node that sent it
+        let cached = match handoff {
+            true => self.cache.get_many_with_ttl(&keys),
+            false => keys.iter().map(|key| self.cache.peek_with_ttl(key)).collect(),
+        };
+        let mut values: Vec<Option<GetResponse>> = cached
+            .into_iter()
+            .map(|cached| cached.map(|(value, ttl)| found(value, Some(ttl))))
+            .collect();
+
+        // the keys may not have been migrated from their previous owner yet
+        if handoff {
+            let missing: Vec<String> = keys.iter().zip(values.iter())
+                .filter(|(_, value)| value.is_none())
+                .map(|(key, _)| key.clone())
+                .collect();
+            let handed_off = self.handoff_multi_get(&missing).await;
+            for (key, value) in keys.iter().zip(values.iter_mut()).filter(|(_, value)| value.is_none()) {
+                if let Some((handed_off, ttl)) = handed_off.get(key) {
+                    self.cache.put_if_absent(key.clone(), handed_off.clone(), *ttl);
+                    *value = Some(found(handed_off.clone(), *ttl));
+                }
+            }
+        }
+
+        if persistence.reads_storage() {
+            // a write queued for the storage is newer than the value it holds
+            if let Some(write_behind) = &self.write_behind {
+                for (key, value) in keys.iter().zip(values.iter_mut()).filter(|(_, value)| value.is_none()) {
+                    *value = match write_behind.lookup(key) {
+                        Some(Some((queued, ttl))) => {
+                            self.cache.put_with_ttl(key.clone(), queued.clone(), ttl);
+                            Some(found(queued, ttl))
+                        },
+                        Some(None) => Some(GetResponse::default()),
+                        None => None,
+                    };
+                }
+            }
+
+            let misses: Vec<String> = keys.iter().zip(values.iter())
+                .filter(|(_, value)| value.is_none())
+                .map(|(key, _)| key.clone())
+                .collect();
+            if !misses.is_empty() {
+                debug!("Cache misses for {} keys", misses.len());
+                let db = self.storage()?;
+                self.counters.db_reads.fetch_add(misses.len() as u64, Ordering::Relaxed);
+                let started = Instant::now();
+                let result = db.get_many(&misses).await;
+                self.metrics.record_db("get_many", started.elapsed(), result.is_err());
+                let stored = result.map_err(|e| {
+                    increment(&self.counters.db_errors);
+                    error!("Database error while getting {} keys: {}", misses.len(), e);
+                    e
+                })?;
+                self.counters.db_hits.fetch_add(stored.len() as u64, Ordering::Relaxed);
+                let stored: HashMap<String, (Bytes, Option<Duration>)> = stored.into_iter()
+                    .map(|(key, (value, ttl))| (key, (Bytes::from(value), ttl)))
+                    .collect();
+
+                // updating the in-memory cache, the entries must not outlive the rows
+                self.cache.put_many_with_ttl(stored.iter()
+                    .map(|(key, (value, ttl))| (key.clone(), value.clone(), *ttl))
+                    .collect());
+                for (key, value) in keys.iter().zip(values.iter_mut()).filter(|(_, value)| value.is_none()) {
+                    *value = stored.get(key).map(|(stored, ttl)| found(stored.clone(), *ttl));
+                }
+            }
+        }
+
+        Ok(values.into_iter().map(Option::unwrap_or_default).collect())
+    }
+
+    // serves a batch of puts on this node, without forwarding them. the cache is written once for all the entries
+    async fn local_multi_put(&self, entries: Vec<KeyValuePair>, persistence: PersistenceMode) -> Result<Response<MultiPutResponse>, Status> {
+        if persistence == PersistenceMode::WriteAround {
+            // the next reads load the values from the database
+            for entry in &entries {
+                self.cache.remove(entry.key.clone());
+            }
+        } else {
+            self.cache.put_many_with_ttl(entries.iter()
+                .map(|entry| (entry.key.clone(), entry.value.clone(), entry.ttl_ms.map(Duration::from_millis)))
+                .collect());
+            for entry in &entries {
+                self.deleted.remove(&entry.key);
+            }
+        }
+
+        match persistence {
+            PersistenceMode::WriteThrough | PersistenceMode::WriteAround => self.persist_many(entries).await?,
+            _ => {
+                for entry in entries {
+                    let ttl = entry.ttl_ms.map(Duration::from_millis);
+                    self.persist_put(entry.key, entry.value, ttl, persistence).await?;
+                }
+            },
+        }
+        Ok(Response::new(MultiPutResponse { success: true }))
+    }
+
+    // writes a batch of puts already applied to the cache to the database at once, so that a failure writes none
+    async fn persist_many(&self, entries: Vec<KeyValuePair>) -> Result<(), Status> {
+        let rows: Vec<(&str, &[u8], Option<Duration>)> = entries.iter()
+            .map(|entry| (entry.key.as_str(), entry.value.as_ref(), entry.ttl_ms.map(Duration::from_millis)))
+            .collect();
+        let started = Instant::now();
+        let result = self.storage()?.put_many(&rows).await;
+        self.metrics.record_db("put_many", started.elapsed(), result.is_err());
+        if let Err(e) = result {
+            increment(&self.counters.db_errors);
+            error!("Database error while putting {} keys: {}", entries.len(), e);
+            return Err(e.into());
+        }
+
+        // a write to a key still queued would overwrite this one once flushed
+        if let Some(write_behind) = &self.write_behind {
+            for entry in entries {
+                if write_behind.lookup(&entry.key).is_some() {
+                    write_behind.put(entry.key, entry.value, entry.ttl_ms.map(Duration::from_millis)).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // serves a delete on this node, without forwarding it
+    async fn local_delete(&self, key: String, persistence: PersistenceMode, handoff: bool) -> Result<Response<DeleteResponse>, Status> {
+        let mut found = self.cache.remove(key.clone()).is_some();
+        if self.cluster.handing_off() {
+            self.deleted.insert(key.clone(), Instant::now());
+        } else if !self.deleted.is_empty() {
+            self.deleted.clear();
+        }
+
+        // the previous owner may still hold the key, which would otherwise be migrated back or handed off
+        if handoff {
+            found |= self.handoff_delete(&key).await;
+        }
+
+        match persistence {
+            PersistenceMode::None | PersistenceMode::ReadThrough => {},
+            PersistenceMode::WriteBehind => {
+                // the delete is queued without reading the database, so whether the key existed is best-effort,
+                // told by the cache and the queued write to it only
+                let write_behind = self.queue()?;
+                found |= write_behind.lookup(&key).is_some_and(|queued| queued.is_some());
+                write_behind.delete(key.clone()).await;
+            },
+            PersistenceMode::WriteThrough | PersistenceMode::WriteAround => {
+                let started = Instant::now();
+                let result = self.storage()?.delete(&key).await;
+                self.metrics.record_db("delete", started.elapsed(), result.is_err());
+                match result {
+                    Ok(deleted) => found |= deleted,
+                    Err(e) => {
+                        increment(&self.counters.db_errors);
+                        error!("Database error while deleting key {}: {}", key, e);
+                        return Err(e.into());
+                    },
+                }
+
+                // a write to the key still queued would bring it back once flushed
+                if let Some(write_behind) = &self.write_behind {
+                    if let Some(queued) = write_behind.lookup(&key) {
+                        found |= queued.is_some();
+                        write_behind.delete(key.clone()).await;
+                    }
+                }
+            },
+        }
+
+        debug!("Deleted key {}, found: {}", key, found);
+        Ok(Response::new(DeleteResponse { found }))
+    }
+
+    // sends a write to every replica of its key concurrently, serving it here when this node is one of them. the
+    // owner receives the request, the other replicas the one updating their cache only, so that the key is written to
+    // the database once. the results are in the order of the replicas, the owner first
+    async fn replicate<Req, Res, Fut>(
+        &self,
+        replicas: &[NodeInfo],
+        request: Req,
+        cache_only: Req,
+        local: impl Future<Output = Result<Response<Res>, Status>>,
+        forward: impl Fn(PeerClient, Req) -> Fut,
+    ) -> Vec<(NodeInfo, Result<Res, Status>)>
+    where
+        Req: Clone + Send + 'static,
+        Res: Send + 'static,
+        Fut: Future<Output = Result<Response<Res>, Status>> + Send + 'static,
+    {
+        let mut forwards = JoinSet::new();
+        for (i, replica) in replicas.iter().enumerate() {
+            if replica == self.cluster.self_node() {
+                continue;
+            }
+            debug!("Forwarding write to replica {}", replica);
+            increment(&self.counters.forwarded);
+            match self.cluster.client(replica) {
+                Ok(client) => {
+                    let request = if i == 0 { request.clone() } else { cache_only.clone() };
+                    let forwarded = forward(client, request);
+                    forwards.spawn(async move { (i, forwarded.await) });
+                },
+                Err(e) => { forwards.spawn(async move { (i, Err(e)) }); },
+            }
+        }
+
+        let mut results: Vec<Option<Result<Res, Status>>> = replicas.iter().map(|_| None).collect();
+        if let Some(i) = replicas.iter().position(|replica| replica == self.cluster.self_node()) {
+            results[i] = Some(local.await.map(Response::into_inner));
+        }
+        while let Some(forwarded) = forwards.join_next().await {
+            match forwarded {
+                Ok((i, result)) => results[i] = Some(result.map(Response::into_inner)),
+                Err(e) => error!("Replicated write panicked: {}", e),
+            }
+        }
+
+        replicas.iter().cloned()
+            .zip(results)
+            .map(|(replica, result)| (replica, result.unwrap_or_else(|| Err(Status::internal("Replicated write failed")))))
+            .collect()
+    }
+
+    // serves a get from the owner of the key, and from the next replica when it can not be reached
+    async fn routed_get(&self, key: String, persistence: i32) -> Result<Response<GetResponse>, Status> {
+        let mut unreachable = None;
+        for replica in self.cluster.replicas(&key) {
+            if replica == *self.cluster.self_node() {
+                return self.local_get(key, self.persistence(persistence)?, true).await;
+            }
+
+            debug!("Forwarding GET for key {} to {}", key, replica);
+            increment(&self.counters.forwarded);
+            let mut client = self.cluster.client(&replica)?;
+            // the owner resolves the persistence mode, the request may leave it to the owner's configuration
+            let response = match client.forward_get(GetRequest { key: key.clone(), persistence }).await {
+                Ok(response) => response,
+                Err(status) if is_retryable(&status) => {
+                    warn!("Replica {} of key {} is unreachable: {}", replica, key, status.message());
+                    unreachable = Some(PouchError::from(status).into());
+                    continue;
+                },
+                Err(status) => return Err(status),
+            };
+            if response.get_ref().found {
+                return Ok(response);
+            }
+
+            // the owner may not know about the topology change yet, while this node does
+            return match self.handoff_get(&key).await {
+                Some((value, ttl)) => Ok(Response::new(GetResponse {
+                    found: true,
+                    value,
+                    ttl_ms: ttl.map(|ttl| ttl.as_millis() as u64),
+                })),
+                None => Ok(response),
+            };
+        }
+        Err(unreachable.unwrap_or_else(|| PouchError::NodeUnavailable(format!("No replica of key {} is reachable", key)).into()))
+    }
+
+    async fn handoff_delete(&self, key: &str) -> bool {
+        let Some(previous_owner) = self.cluster.previous_owner(key) else {
+            return false;
+        };
+        if previous_owner == *self.cluster.self_node() {
+            return self.cache.remove(key.to_string()).is_some();
+        }
+
+        let mut request = Request::new(DeleteRequest { key: key.to_string(), ..Default::default() });
+        request.metadata_mut().insert(HANDOFF_HEADER, "1".parse().unwrap());
+        let response = match self.cluster.client(&previous_owner) {
+            Ok(mut client) => client.forward_delete(request).await,
+            Err(e) => Err(e),
+        };
+        match response {
+            Ok(response) => response.into_inner().found,
+            Err(e) => {
+                warn!("Could not delete key {} from previous owner {}: {}", key, previous_owner, e.message());
+                false
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl PandasPouchCacheService for CacheServiceImpl {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let GetRequest { key, persistence } = request.into_inner();
+        info!("GET: key: {}", key);
+        increment(&self.counters.gets);
+        self.check_key(&key)?;
+        self.routed_get(key, persistence).await
+    }
+
+    async fn put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let req = request.into_inner();
+        info!("PUT: {}", req.key);
+        increment(&self.counters.puts);
+        self.check_key(&req.key)?;
+        self.check_value(&req.value)?;
+
+        let replicas = self.cluster.replicas(&req.key);
+        let owner = replicas.first() == Some(self.cluster.self_node());
+        let local = async {
+            let persistence = self.replica_persistence(req.persistence, owner)?;
+            self.local_put(req.clone(), persistence).await
+        };
+        let cache_only = PutRequest { persistence: RequestedPersistence::PersistenceNone.into(), ..req.clone() };
+        let forward = |mut client: PeerClient, req: PutRequest| async move { client.forward_put(req).await };
+        let results = self.replicate(&replicas, req.clone(), cache_only, local, forward).await;
+        applied(&req.key, results)?;
+        Ok(Response::new(PutResponse { success: true }))
+    }
+
+    async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        let req = request.into_inner();
+        info!("DELETE: key: {}", req.key);
+        increment(&self.counters.deletes);
+        self.check_key(&req.key)?;
+
+        let replicas = self.cluster.replicas(&req.key);
+        let owner = replicas.first() == Some(self.cluster.self_node());
+        let local = async {
+            let persistence = self.replica_persistence(req.persistence, owner)?;
+            self.local_delete(req.key.clone(), persistence, true).await
+        };
+        let cache_only = DeleteRequest { persistence: RequestedPersistence::PersistenceNone.into(), ..req.clone() };
+        let forward = |mut client: PeerClient, req: DeleteRequest| async move { client.forward_delete(req).await };
+        let results = self.replicate(&replicas, req.clone(), cache_only, local, forward).await;
+        let mut found = applied(&req.key, results)?.iter().any(|response| response.found);
+
+        // the owner may not know about the topology change yet, while this node does
+        if !replicas.contains(self.cluster.self_node()) && self.handoff_delete(&req.key).await {
+            found = true;
+        }
+        Ok(Response::new(DeleteResponse { found }))
+    }
+
+    // the keys are split by owner, and every owner serves its keys in a single request. the keys of an owner that can
+    // not be reached are served one by one, by their next replicas
+    async fn multi_get(&self, request: Request<MultiGetRequest>) -> Result<Response<MultiGetResponse>, Status> {
+        let MultiGetRequest { keys, persistence } = request.into_inner();
+        info!("MULTI GET: {} keys", keys.len());
+        self.counters.gets.fetch_add(keys.len() as u64, Ordering::Relaxed);
+        for key in &keys {
+            self.check_key(key)?;
+        }
+
+        let mut by_owner: HashMap<NodeInfo, Vec<usize>> = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            let owner = self.cluster.replicas(key).into_iter().next().unwrap_or_else(|| self.cluster.self_node().clone());
+            by_owner.entry(owner).or_default().push(i);
+        }
+
+        let mut values: Vec<GetResponse> = vec![GetResponse::default(); keys.len()];
+        let mut local = None;
+        let mut forwards = JoinSet::new();
+        for (owner, positions) in by_owner {
+            let owned: Vec<String> = positions.iter().map(|i| keys[*i].clone()).collect();
+            if owner == *self.cluster.self_node() {
+                local = Some((positions, owned));
+                continue;
+            }
+            debug!("Forwarding MULTI GET of {} keys to {}", owned.len(), owner);
+            increment(&self.counters.forwarded);
+            let mut client = self.cluster.client(&owner)?;
+            forwards.spawn(async move {
+                let result = client.forward_multi_get(MultiGetRequest { keys: owned, persistence }).await;
+                (owner, positions, result)
+            });
+        }
+
+        if let Some((positions, owned)) = local {
+            let found = self.local_multi_get(owned, self.persistence(persistence)?, true).await?;
+            for (i, value) in positions.into_iter().zip(found) {
+                values[i] = value;
+            }
+        }
+        while let Some(forwarded) = forwards.join_next().await {
+            let (owner, positions, result) = forwarded.map_err(|e| Status::internal(format!("Forwarded batch failed: {}", e)))?;
+            match result {
+                Ok(response) => {
+                    let mut missing = Vec::new();
+                    for (i, value) in positions.into_iter().zip(response.into_inner().values) {
+                        if !value.found {
+                            missing.push(i);
+                        }
+                        values[i] = value;
+                    }
+
+                    // the owner may not know about the topology change yet, while this node does
+                    let missing_keys: Vec<String> = missing.iter().map(|i| keys[*i].clone()).collect();
+                    let handed_off = self.handoff_multi_get(&missing_keys).await;
+                    for i in missing {
+                        if let Some((value, ttl)) = handed_off.get(&keys[i]) {
+                            values[i] = GetResponse { found: true, value: value.clone(), ttl_ms: ttl.map(|ttl| ttl.as_millis() as u64) };
+                        }
+                    }
+                },
+                Err(status) if is_retryable(&status) => {
+                    warn!("Owner {} of {} keys is unreachable, getting them from the replicas: {}", owner, positions.len(), status.message());
+                    for i in positions {
+                        values[i] = self.routed_get(keys[i].clone(), persistence).await?.into_inner();
+                    }
+                },
+                Err(status) => return Err(status),
+            }
+        }
+        Ok(Response::new(MultiGetResponse { values }))
+    }
+
+    // every replica receives the entries it holds in a single request. the batch fails when an entry could not be
+    // written to any of its replicas
+    async fn multi_put(&self, request: Request<MultiPutRequest>) -> Result<Response<MultiPutResponse>, Status> {
+        let MultiPutRequest { entries, persistence } = request.into_inner();
+        info!("MULTI PUT: {} entries", entries.len());
+        self.counters.puts.fetch_add(entries.len() as u64, Ordering::Relaxed);
+        for entry in &entries {
+            self.check_key(&entry.key)?;
+            self.check_value(&entry.value)?;
+        }
+
+        // the entries are grouped by replica, and by whether the replica owns them, as only the owner persists them
+        let mut by_replica: HashMap<(NodeInfo, bool), Vec<KeyValuePair>> = HashMap::new();
+        let replicas: Vec<Vec<NodeInfo>> = entries.iter().map(|entry| self.cluster.replicas(&entry.key)).collect();
+        for (entry, replicas) in entries.iter().zip(replicas.iter()) {
+            for (i, replica) in replicas.iter().enumerate() {
+                by_replica.entry((replica.clone(), i == 0)).or_default().push(entry.clone());
+            }
+        }
+
+        let mut local = Vec::new();
+        let mut forwards = JoinSet::new();
+        for ((replica, owner), entries) in by_replica {
+            if replica == *self.cluster.self_node() {
+                local.push((owner, entries));
+                continue;
+            }
+            debug!("Forwarding MULTI PUT of {} entries to {}", entries.len(), replica);
+            increment(&self.counters.forwarded);
+            let persistence = if owner { persistence } else { RequestedPersistence::PersistenceNone.into() };
+            match self.cluster.client(&replica) {
+                Ok(mut client) => {
+                    forwards.spawn(async move {
+                        let result = client.forward_multi_put(MultiPutRequest { entries, persistence }).await;
+                        ((replica, owner), result.map(|_| ()))
+                    });
+                },
+                Err(e) => { forwards.spawn(async move { ((replica, owner), Err(e)) }); },
+            }
+        }
+
+        let mut results: HashMap<(NodeInfo, bool), Result<(), Status>> = HashMap::new();
+        for (owner, entries) in local {
+            let result = match self.replica_persistence(persistence, owner) {
+                Ok(persistence) => self.local_multi_put(entries, persistence).await.map(|_| ()),
+                Err(status) => Err(status),
+            };
+            results.insert((self.cluster.self_node().clone(), owner), result);
+        }
+        while let Some(forwarded) = forwards.join_next().await {
+            match forwarded {
+                Ok((replica, result)) => { results.insert(replica, result); },
+                Err(e) => error!("Replicated batch panicked: {}", e),
+            }
+        }
+        for ((replica, _), result) in &results {
+            if let Err(status) = result {
+                warn!("Replica {} could not apply a batch of writes: {}", replica, status.message());
+            }
+        }
+
+        for (entry, replicas) in entries.iter().zip(replicas.iter()) {
+            let applied = |(i, replica): (usize, &NodeInfo)| matches!(results.get(&(replica.clone(), i == 0)), Some(Ok(())));
+            if replicas.iter().enumerate().any(applied) {
+                continue;
+            }
+            return Err(match replicas.first().and_then(|owner| results.remove(&(owner.clone(), true))) {
+                Some(Err(status)) => status,
+                _ => Status::internal(format!("No replica applied the write of key {}", entry.key)),
+            });
+        }
+        Ok(Response::new(MultiPutResponse { success: true }))
+    }
+
+    async fn print_all(&self, _request: Request<PrintAllRequest>) -> Result<Response<PrintAllResponse>, Status> {
+        info!("Received PrintAll request");
+        let pairs = self.cache.entries().into_iter().map(|(k, v, ttl)| {
+            debug!("Printing cache entry: {} ({} bytes)", k, v.len());
+            KeyValuePair {
+                key: k,
+                value: v,
+                ttl_ms: Some(ttl.as_millis() as u64),
+            }
+        }).collect::<Vec<_>>();
+
+        info!("Returning {} cache entries", pairs.len());
+        Ok(Response::new(PrintAllResponse { pairs }))
+    }
+
+    async fn stats(&self, _request: Request<StatsRequest>) -> Result<Response<StatsResponse>, Status> {
+        info!("Received Stats request");
+        Ok(Response::new(self.current_stats()))
+    }
+
+    // forwarded requests are always served locally, so a stale ring on either side can not bounce a key around
+    async fn forward_get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let handoff = !request.metadata().contains_key(HANDOFF_HEADER);
+        let GetRequest { key, persistence } = request.into_inner();
+        info!("FORWARD GET: key: {}", key);
+        self.local_get(key, self.persistence(persistence)?, handoff).await
+    }
+
+    async fn forward_put(&self, request: Request<PutRequest>) -> Result<Response<PutResponse>, Status> {
+        let req = request.into_inner();
+        info!("FORWARD PUT: {}", req.key);
+        let persistence = self.persistence(req.persistence)?;
+        self.local_put(req, persistence).await
+    }
+
+    async fn forward_delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        let handoff = !request.metadata().contains_key(HANDOFF_HEADER);
+        let DeleteRequest { key, persistence } = request.into_inner();
+        info!("FORWARD DELETE: key: {}", key);
+        self.local_delete(key, self.persistence(persistence)?, handoff).await
+    }
+
+    async fn forward_multi_get(&self, request: Request<MultiGetRequest>) -> Result<Response<MultiGetResponse>, Status> {
+        let handoff = !request.metadata().contains_key(HANDOFF_HEADER);
+        let MultiGetRequest { keys, persistence } = request.into_inner();
+        info!("FORWARD MULTI GET: {} keys", keys.len());
+        let values = self.local_multi_get(keys, self.persistence(persistence)?, handoff).await?;
+        Ok(Response::new(MultiGetResponse { values }))
+    }
+
+    async fn forward_multi_put(&self, request: Request<MultiPutRequest>) -> Result<Response<MultiPutResponse>, Status> {
+        let MultiPutRequest { entries, persistence } = request.into_inner();
+        info!("FORWARD MULTI PUT: {} entries", entries.len());
+        self.local_multi_put(entries, self.persistence(persistence)?).await
+    }
+
+    async fn join_cluster(&self, request: Request<JoinClusterRequest>) -> Result<Response<JoinClusterResponse>, Status> {
+        let req = request.into_inner();
+        let node = NodeInfo::try_from(req.joining_node.ok_or_else(|| Status::invalid_argument("Missing joining node"))?)?;
+        info!("JOIN CLUSTER: {} (forwarded: {})", node, req.forwarded);
+
+        if self.cluster.add_member(node.clone()) && !req.forwarded {
+            self.cluster.broadcast_join(&node).await;
+        }
+
+        let current_nodes = self.cluster.members().iter().map(Into::into).collect();
+        Ok(Response::new(JoinClusterResponse { success: true, current_nodes }))
+    }
+
+    async fn leave_cluster(&self, request: Request<LeaveClusterRequest>) -> Result<Response<LeaveClusterResponse>, Status> {
+        let req = request.into_inner();
+        let node = NodeInfo::try_from(req.leaving_node.ok_or_else(|| Status::invalid_argument("Missing leaving node"))?)?;
+        info!("LEAVE CLUSTER: {} (forwarded: {})", node, req.forwarded);
+
+        if self.cluster.remove_member(&node) && !req.forwarded {
+            self.cluster.broadcast_leave(&node).await;
+        }
+
+        Ok(Response::new(LeaveClusterResponse { success: true }))
+    }
+
+    async fn list_nodes(&self, _request: Request<ListNodesRequest>) -> Result<Response<ListNodesResponse>, Status> {
+        debug!("Received ListNodes request");
+        Ok(Response::new(ListNodesResponse {
+            nodes: self.cluster.members().iter().map(Into::into).collect(),
+            virtual_nodes: self.cluster.virtual_nodes().try_into().unwrap_or(i32::MAX),
+            replication_factor: self.cluster.replication_factor().try_into().unwrap_or(u32::MAX),
+        }))
+    }
+
+    // receives the entries moved to this node by a rebalancing. values already held here were written
+    // after the topology change, so they are newer than the migrated ones and are kept
+    async fn migrate(&self, request: Request<Streaming<KeyValuePair>>) -> Result<Response<MigrateResponse>, Status> {
+        let mut stream = request.into_inner();
+        let mut received = 0;
+        let mut stored = 0;
+        self.deleted.retain(|_, deleted_at| deleted_at.elapsed() <= HANDOFF_WINDOW);
+
+        while let Some(pair) = stream.message().await? {
+            received += 1;
+            if self.deleted.contains_key(&pair.key) {
+                continue;
+            }
+            if self.cache.put_if_absent(pair.key, pair.value, pair.ttl_ms.map(Duration::from_millis)) {
+                stored += 1;
+            }
+        }
+
+        info!("MIGRATE: received {} entries, stored {}", received, stored);
+        Ok(Response::new(MigrateResponse { received, stored }))
+    }
+}
+
+// reclaims the expired entries in the background, so they do not take up capacity until a read touches them
+pub fn spawn_expiry_sweeper(cache: Arc<LRUCache<String, Bytes>>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let mut purged = 0;
+            loop {
+                // purging in batches, so requests are not held up by a large purge
+                let batch = cache.purge_expired(SWEEP_BATCH_SIZE);
+                purged += batch;
+                if batch < SWEEP_BATCH_SIZE {
+                    break;
+                }
+            }
+            if purged > 0 {
+                let total = cache.expired();
+                info!("Expiry sweeper removed {} entries ({} expired in total), cache weighs {} of {}",
+                    purged, total, cache.weight(), cache.capacity());
+            }
+        }
+    })
+}
+
+pub async fn run_server(settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+    let addr = format!("{}:{}", settings.local_addr, settings.local_port);
+    info!("Initializing server with address: {}", addr);
+    let cache = match settings.cache.capacity_unit {
+        CapacityUnit::Entries => LRUCache::with_policy(settings.cache.capacity, None, settings.cache.policy),
+        CapacityUnit::Bytes => {
+            // the segments are large enough for the largest key and value a request may carry
+            let max_entry_bytes = settings.cache.max_key_bytes.saturating_add(settings.cache.max_value_bytes);
+            LRUCache::with_max_entry_weight(settings.cache.capacity, None, settings.cache.policy, max_entry_bytes)
+                .with_weigher(|key: &String, value: &Bytes| key.len() + value.len())
+        }
+    };
+    info!("Cache capacity is {} {:?}", cache.capacity(), settings.cache.capacity_unit);
+    let cache = Arc::new(cache);
+    let sweeper = spawn_expiry_sweeper(Arc::clone(&cache), Duration::from_millis(settings.cache.sweep_interval_ms));
+    let persistence = &settings.persistence;
+    // a node that does not persist anything runs without a database
+    let db = match persistence.mode {
+        PersistenceMode::None => None,
+        _ => {
+            let db = db::connect(settings).await?;
+            info!("{:?} storage connected, table created or verified", settings.database.backend);
+            Some(db)
+        },
+    };
+
+    let cluster = Cluster::new(settings.self_node(), Vec::new(), settings.cluster.virtual_nodes)
+        .with_replication_factor(settings.cluster.replication_factor)
+        .with_timeouts(
+            Duration::from_millis(settings.cluster.connect_timeout_ms),
+            Duration::from_millis(settings.cluster.request_timeout_ms),
+        );
+    let cluster = Arc::new(cluster);
+    let rebalancer = Arc::new(Rebalancer::new(Arc::clone(&cache), Arc::clone(&cluster)));
+    let rebalancing = rebalancer.spawn();
+    let metrics = Arc::new(Metrics::new());
+    let mut service = CacheServiceImpl::new(Arc::clone(&cache), db.clone(), Arc::clone(&cluster))
+        .with_persistence(persistence.mode)
+        .with_metrics(Arc::clone(&metrics))
+        .with_limits(settings.cache.max_key_bytes, settings.cache.max_value_bytes);
+
+    // the queue is there for the requests that pick write-behind, whatever the mode of the node
+    let write_behind = match db {
+        None => None,
+        Some(db) => {
+            let write_behind = WriteBehind::new(db, persistence.queue_capacity, persistence.batch_size)
+                .with_metrics(Arc::clone(&metrics));
+            let write_behind = Arc::new(write_behind);
+            let flusher = write_behind.spawn(Duration::from_millis(persistence.flush_interval_ms));
+            service = service.with_write_behind(Arc::clone(&write_behind));
+            Some((write_behind, flusher))
+        },
+    };
+    info!("Persistence mode is {:?}", persistence.mode);
+
+    let metrics_server = if settings.metrics.enabled {
+        let listener = tokio::net::TcpListener::bind((settings.local_addr.as_str(), settings.metrics.port)).await?;
+        let (metrics, cluster) = (Arc::clone(&metrics), Arc::clone(&cluster));
+        let write_behind = write_behind.as_ref().map(|(write_behind, _)| Arc::clone(write_behind));
+        Some(spawn_metrics_server(listener, move || {
+            let write_behind = write_behind.as_ref().map(|write_behind| write_behind.stats());
+            metrics.render(&cache.stats(), cluster.members().len(), write_behind.as_ref())
+        }))
+    } else {
+        None
+    };
+
+    // the node leaves the cluster on shutdown and hands its entries off, while it is still serving
+    // the requests forwarded to it
+    let shutdown = {
+        let cluster = Arc::clone(&cluster);
+        let rebalancer = Arc::clone(&rebalancer);
+        async move {
+            tokio::signal::ctrl_c().await.ok();
+            info!("Shutting down {}", cluster.self_node());
+            rebalancing.abort();
+            cluster.leave().await;
+            rebalancer.hand_off().await;
+        }
+    };
+
+    info!("Starting server on {}", addr);
+    let server = tokio::spawn(
+        Server::builder()
+            .layer(MetricsLayer::new(metrics))
+            .add_service(PandasPouchCacheServiceServer::new(service))
+            .serve_with_shutdown(addr.parse()?, shutdown),
+    );
+
+    let seeds = settings.cluster_seeds()?;
+    if !cluster.join(&seeds).await {
+        info!("No seed reachable, {} starts as the first member of the cluster", cluster.self_node());
+    }
+    let rejoin = cluster.spawn_rejoin(seeds, Duration::from_secs(settings.cluster.rejoin_interval_secs));
+
+    // the server may have failed, the writes still queued are flushed all the same before the error is returned
+    let served = server.await;
+    rejoin.abort();
+    sweeper.abort();
+    // the writes still queued are flushed, once no more can be queued
+    if let Some((write_behind, flusher)) = write_behind {
+        flusher.abort();
+        match write_behind.flush().await {
+            Ok(written) => info!("Flushed {} queued writes to the database", written),
+            Err(e) => error!("Could not flush {} queued writes to the database: {}", write_behind.depth(), e),
+        }
+    }
+    if let Some(metrics_server) = metrics_server {
+        metrics_server.abort();
+    }
+
+    served??;
+    Ok(())
+}
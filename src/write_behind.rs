@@ -0,0 +1,238 @@
+// write-behind persistence: puts and deletes are queued and written to the storage in the background, in batches
+// where the writes to the same key are coalesced into the last one
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use log::{debug, warn};
+use parking_lot::Mutex;
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::db::Storage;
+use crate::error::PouchError;
+use crate::metrics::Metrics;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PendingWrite {
+    Put { value: Bytes, expires_at: Option<Instant> },
+    Delete,
+}
+
+impl PendingWrite {
+    // the value the storage will hold once the write is flushed, with the time it has left to live
+    fn value(&self) -> Option<(Bytes, Option<Duration>)> {
+        match self {
+            PendingWrite::Put { value, expires_at: None } => Some((value.clone(), None)),
+            PendingWrite::Put { value, expires_at: Some(expires_at) } => {
+                let ttl = expires_at.checked_duration_since(Instant::now()).filter(|ttl| !ttl.is_zero())?;
+                Some((value.clone(), Some(ttl)))
+            },
+            PendingWrite::Delete => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteBehindStats {
+    pub depth: usize,           // writes waiting to be flushed, or being flushed
+    pub capacity: usize,
+    pub flushed: u64,
+    pub coalesced: u64,         // writes replaced by a later write to the same key before being flushed
+    pub failed: u64,            // flushed writes the storage rejected, they are retried
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: HashMap<String, PendingWrite>,
+    order: VecDeque<String>,                    // pending keys, oldest first
+    flushing: HashMap<String, PendingWrite>,    // taken by the current batch, until the storage has them
+}
+
+pub struct WriteBehind {
+    storage: Arc<dyn Storage>,
+    queue: Mutex<Queue>,
+    slots: Semaphore,               // room left in the queue, writes of new keys wait for one when it is full
+    capacity: usize,
+    batch_size: usize,
+    batch_ready: Notify,
+    flush_lock: tokio::sync::Mutex<()>,
+    flushed: AtomicU64,
+    coalesced: AtomicU64,
+    failed: AtomicU64,
+    metrics: Arc<Metrics>,
+}
+
+impl WriteBehind {
+    pub fn new(storage: Arc<dyn Storage>, capacity: usize, batch_size: usize) -> Self {
+        let capacity = capacity.max(1);
+        WriteBehind {
+            storage,
+            queue: Mutex::new(Queue::default()),
+            slots: Semaphore::new(capacity),
+            capacity,
+            batch_size: batch_size.max(1),
+            batch_ready: Notify::new(),
+            flush_lock: tokio::sync::Mutex::new(()),
+            flushed: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            metrics: Arc::new(Metrics::new()),
+        }
+    }
+
+    // records the latencies and errors of the flushed writes in the given metrics
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn depth(&self) -> usize {
+        let queue = self.queue.lock();
+        queue.pending.len() + queue.flushing.len()
+    }
+
+    pub fn stats(&self) -> WriteBehindStats {
+        WriteBehindStats {
+            depth: self.depth(),
+            capacity: self.capacity,
+            flushed: self.flushed.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    // queues a put, waiting for room when the queue is full
+    pub async fn put(&self, key: String, value: Bytes, ttl: Option<Duration>) {
+        // a ttl reaching past the instants the clock can tell means the value never expires
+        let expires_at = ttl.and_then(|ttl| Instant::now().checked_add(ttl));
+        self.enqueue(key, PendingWrite::Put { value, expires_at }).await;
+    }
+
+    // queues a delete, waiting for room when the queue is full
+    pub async fn delete(&self, key: String) {
+        self.enqueue(key, PendingWrite::Delete).await;
+    }
+
+    // the value a queued write will leave in the storage: Some(None) when the key is being deleted, and None when no
+    // write to the key is queued, so the storage is up to date
+    pub fn lookup(&self, key: &str) -> Option<Option<(Bytes, Option<Duration>)>> {
+        let queue = self.queue.lock();
+        queue.pending.get(key).or_else(|| queue.flushing.get(key)).map(PendingWrite::value)
+    }
+
+    async fn enqueue(&self, key: String, write: PendingWrite) {
+        if self.coalesce(&key, &write) {
+            return;
+        }
+
+        // the semaphore is never closed
+        self.slots.acquire().await.expect("write-behind queue closed").forget();
+        let mut queue = self.queue.lock();
+        let queue = &mut *queue;
+        match queue.pending.entry(key) {
+            Entry::Occupied(mut entry) => {
+                // queued by a concurrent write while this one waited for room
+                entry.insert(write);
+                self.slots.add_permits(1);
+                self.coalesced.fetch_add(1, Ordering::Relaxed);
+            },
+            Entry::Vacant(entry) => {
+                queue.order.push_back(entry.key().clone());
+                entry.insert(write);
+                if queue.pending.len() >= self.batch_size {
+                    self.batch_ready.notify_one();
+                }
+            },
+        }
+    }
+
+    // replaces the pending write to the key, which needs no room in the queue
+    fn coalesce(&self, key: &str, write: &PendingWrite) -> bool {
+        let mut queue = self.queue.lock();
+        match queue.pending.get_mut(key) {
+            Some(pending) => {
+                *pending = write.clone();
+                self.coalesced.fetch_add(1, Ordering::Relaxed);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // writes a batch of the oldest pending writes to the storage, returning how many were written. the writes of a
+    // failed batch are kept and retried first by the next one
+    pub async fn flush_batch(&self) -> Result<usize, PouchError> {
+        let _flushing = self.flush_lock.lock().await;
+        let batch: Vec<(String, PendingWrite)> = {
+            let mut queue = self.queue.lock();
+            let queue = &mut *queue;
+            while queue.flushing.len() < self.batch_size {
+                let Some(key) = queue.order.pop_front() else {
+                    break;
+                };
+                let Some(write) = queue.pending.remove(&key) else {
+                    continue;
+                };
+                // a write left over by a failed batch is superseded by the newer one
+                if queue.flushing.insert(key, write).is_some() {
+                    self.slots.add_permits(1);
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            queue.flushing.iter().map(|(key, write)| (key.clone(), write.clone())).collect()
+        };
+
+        let mut written = 0;
+        for (key, write) in batch {
+            let started = Instant::now();
+            let (operation, result) = match write.value() {
+                Some((value, ttl)) => ("put", self.storage.put(&key, &value, ttl).await),
+                // a put that expired while queued must not leave an older value behind
+                None => ("delete", self.storage.delete(&key).await.map(|_| ())),
+            };
+            self.metrics.record_db(operation, started.elapsed(), result.is_err());
+            if let Err(e) = result {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                return Err(e);
+            }
+
+            self.queue.lock().flushing.remove(&key);
+            self.slots.add_permits(1);
+            self.flushed.fetch_add(1, Ordering::Relaxed);
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    // writes every queued write to the storage, as on shutdown
+    pub async fn flush(&self) -> Result<usize, PouchError> {
+        let mut written = 0;
+        while self.depth() > 0 {
+            written += self.flush_batch().await?;
+        }
+        Ok(written)
+    }
+
+    // flushes the queue every interval, or as soon as a batch is full
+    pub fn spawn(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let write_behind = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {},
+                    _ = write_behind.batch_ready.notified() => {},
+                }
+                match write_behind.flush().await {
+                    Ok(0) => {},
+                    Ok(written) => debug!("Write-behind flushed {} writes", written),
+                    Err(e) => warn!("Write-behind flush failed, {} writes kept queued: {}", write_behind.depth(), e),
+                }
+            }
+        })
+    }
+}
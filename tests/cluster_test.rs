@@ -0,0 +1,723 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use pandas_pouch::client::{Client, ClusterClient};
+    use pandas_pouch::cluster::Cluster;
+    use pandas_pouch::db::{MemoryStorage, Storage};
+    use pandas_pouch::hash_ring::{HashRing, NodeInfo};
+    use pandas_pouch::lru::LRUCache;
+    use pandas_pouch::pandas_pouch::pandas_pouch_cache_service_client::PandasPouchCacheServiceClient;
+    use pandas_pouch::pandas_pouch::pandas_pouch_cache_service_server::PandasPouchCacheServiceServer;
+    use pandas_pouch::pandas_pouch::{KeyValuePair, PrintAllRequest};
+    use pandas_pouch::rebalance::Rebalancer;
+    use pandas_pouch::server::CacheServiceImpl;
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+
+    const REJOIN_INTERVAL: Duration = Duration::from_millis(100);
+
+    struct TestNode {
+        node: NodeInfo,
+        cluster: Arc<Cluster>,
+        rebalancer: Arc<Rebalancer>,
+        shutdown: oneshot::Sender<()>,
+        server: JoinHandle<()>,
+        rejoin: JoinHandle<()>,
+        rebalancing: JoinHandle<()>,
+    }
+
+    impl TestNode {
+        // Stops serving, without announcing a leave, as if the process was killed.
+        async fn stop(self) {
+            self.rejoin.abort();
+            self.rebalancing.abort();
+            self.shutdown.send(()).unwrap();
+            self.server.await.unwrap();
+        }
+    }
+
+    // Binds a listener on a free loopback port, returning it with the node it will serve as.
+    async fn bind() -> (TcpListener, NodeInfo) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, NodeInfo::new("127.0.0.1", port))
+    }
+
+    // Serves an in-memory node on the listener, and joins the cluster through the seeds.
+    async fn start_node(listener: TcpListener, node: NodeInfo, seeds: Vec<NodeInfo>) -> TestNode {
+        start_replicated_node(listener, node, seeds, 1).await
+    }
+
+    // Serves an in-memory node that writes every key to the given number of nodes.
+    async fn start_replicated_node(listener: TcpListener, node: NodeInfo, seeds: Vec<NodeInfo>, replication_factor: usize) -> TestNode {
+        let cluster = Cluster::new(node, Vec::new(), 10).with_replication_factor(replication_factor);
+        start_cluster_node(listener, cluster, seeds, None).await
+    }
+
+    // Serves a node as the member of the given cluster, in memory unless it is given a database.
+    async fn start_cluster_node(listener: TcpListener, cluster: Cluster, seeds: Vec<NodeInfo>, db: Option<Arc<dyn Storage>>) -> TestNode {
+        let node = cluster.self_node().clone();
+        let cache = Arc::new(LRUCache::new(1000, None));
+        let cluster = Arc::new(cluster);
+        let rebalancer = Arc::new(Rebalancer::new(Arc::clone(&cache), Arc::clone(&cluster)));
+        let rebalancing = rebalancer.spawn();
+        let service = CacheServiceImpl::new(cache, db, Arc::clone(&cluster));
+
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            Server::builder()
+                .add_service(PandasPouchCacheServiceServer::new(service))
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                    signal.await.ok();
+                })
+                .await
+                .unwrap();
+        });
+
+        cluster.join(&seeds).await;
+        let rejoin = cluster.spawn_rejoin(seeds, REJOIN_INTERVAL);
+        TestNode { node, cluster, rebalancer, shutdown, server, rejoin, rebalancing }
+    }
+
+    // Waits until every node sees exactly the expected members.
+    async fn converge(nodes: &[&TestNode], expected: &[NodeInfo]) {
+        let mut expected = expected.to_vec();
+        expected.sort();
+        for _ in 0..50 {
+            if nodes.iter().all(|node| node.cluster.members() == expected) {
+                return;
+            }
+            tokio::time::sleep(REJOIN_INTERVAL).await;
+        }
+        for node in nodes {
+            assert_eq!(expected, node.cluster.members(), "members seen by {}", node.node);
+        }
+    }
+
+    async fn local_keys(node: &NodeInfo) -> Vec<String> {
+        let mut client = PandasPouchCacheServiceClient::connect(node.uri()).await.unwrap();
+        let response = client.print_all(PrintAllRequest {}).await.unwrap().into_inner();
+        response.pairs.into_iter().map(|pair| pair.key).collect()
+    }
+
+    #[tokio::test]
+    async fn test_any_node_serves_any_key() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        let nodes = vec![node_a.clone(), node_b.clone()];
+        converge(&[&a, &b], &nodes).await;
+
+        let keys: Vec<String> = (0..20).map(|i| format!("key{}", i)).collect();
+
+        let mut client_a = Client::new(&node_a.host, node_a.port).await.unwrap();
+        for key in &keys {
+            assert!(client_a.put(key.clone(), format!("value of {}", key)).await.unwrap());
+        }
+
+        let mut client_b = Client::new(&node_b.host, node_b.port).await.unwrap();
+        for key in &keys {
+            assert_eq!(Some(format!("value of {}", key)), client_b.get(key.clone()).await.unwrap());
+        }
+
+        // every key is held only by its owner on the ring
+        let ring = HashRing::new(nodes, 10);
+        let keys_a = local_keys(&node_a).await;
+        let keys_b = local_keys(&node_b).await;
+        assert_eq!(keys.len(), keys_a.len() + keys_b.len());
+        for key in &keys_a {
+            assert_eq!(Some(&node_a), ring.get_node(key.clone()));
+        }
+        for key in &keys_b {
+            assert_eq!(Some(&node_b), ring.get_node(key.clone()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join_through_seed() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let (listener_c, node_c) = bind().await;
+
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+        assert_eq!(vec![node_a.clone()], a.cluster.members());
+
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        let c = start_node(listener_c, node_c.clone(), vec![node_a.clone()]).await;
+
+        // c learns every member from the seed, and the seed relays the join of c to b
+        let mut expected = vec![node_a.clone(), node_b.clone(), node_c.clone()];
+        expected.sort();
+        assert_eq!(expected, c.cluster.members());
+        assert_eq!(expected, a.cluster.members());
+        assert_eq!(expected, b.cluster.members());
+    }
+
+    #[tokio::test]
+    async fn test_leave_cluster() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let (listener_c, node_c) = bind().await;
+
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        let c = start_node(listener_c, node_c.clone(), vec![node_a.clone()]).await;
+        converge(&[&a, &b, &c], &[node_a.clone(), node_b.clone(), node_c.clone()]).await;
+
+        c.cluster.leave().await;
+        c.stop().await;
+
+        let expected = vec![node_a.clone(), node_b.clone()];
+        converge(&[&a, &b], &expected).await;
+    }
+
+    #[tokio::test]
+    async fn test_join_drops_unreachable_members() {
+        // b accepts connections but never answers, and c was stopped without leaving
+        let (hung, node_b) = bind().await;
+        let (listener_a, node_a) = bind().await;
+        let (listener_c, node_c) = bind().await;
+        let (listener_d, node_d) = bind().await;
+        let timeout = Duration::from_millis(200);
+        let cluster = Cluster::new(node_a.clone(), vec![node_b.clone()], 10).with_timeouts(timeout, timeout);
+        let a = start_cluster_node(listener_a, cluster, vec![], None).await;
+        let c = start_node(listener_c, node_c.clone(), vec![node_a.clone()]).await;
+        converge(&[&a], &[node_a.clone(), node_c.clone()]).await;
+        c.stop().await;
+
+        let started = std::time::Instant::now();
+        let d = start_node(listener_d, node_d.clone(), vec![node_a.clone()]).await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+        converge(&[&a, &d], &[node_a.clone(), node_d.clone()]).await;
+        drop(hung);
+    }
+
+    #[tokio::test]
+    async fn test_membership_survives_seed_restart() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let (listener_c, node_c) = bind().await;
+        let all = vec![node_a.clone(), node_b.clone(), node_c.clone()];
+
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        let c = start_node(listener_c, node_c.clone(), vec![node_a.clone()]).await;
+        converge(&[&a, &b, &c], &all).await;
+
+        // the seed comes back with no memory of the cluster
+        a.stop().await;
+        let listener_a = TcpListener::bind(("127.0.0.1", node_a.port)).await.unwrap();
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+        assert_eq!(vec![node_a.clone()], a.cluster.members());
+
+        // and learns the members back as they rejoin
+        converge(&[&a, &b, &c], &all).await;
+    }
+
+    #[tokio::test]
+    async fn test_keys_migrate_to_joining_node() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+
+        let keys: Vec<String> = (0..200).map(|i| format!("key{}", i)).collect();
+        let mut client_a = Client::new(&node_a.host, node_a.port).await.unwrap();
+        for key in &keys {
+            client_a.put(key.clone(), format!("value of {}", key)).await.unwrap();
+        }
+        assert_eq!(keys.len(), local_keys(&node_a).await.len());
+
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        let ring = HashRing::new(vec![node_a.clone(), node_b.clone()], 10);
+        let moved = keys.iter().filter(|key| ring.get_node(key) == Some(&node_b)).count();
+        assert!(moved > 0);
+
+        for _ in 0..50 {
+            let status = a.rebalancer.status();
+            if !status.in_progress && status.transferred == moved {
+                break;
+            }
+            tokio::time::sleep(REJOIN_INTERVAL).await;
+        }
+        let status = a.rebalancer.status();
+        assert_eq!(moved, status.total);
+        assert_eq!(moved, status.transferred);
+        assert_eq!(0, status.failed);
+
+        // the moved keys live on the new owner only
+        let keys_b = local_keys(&node_b).await;
+        assert_eq!(moved, keys_b.len());
+        assert_eq!(keys.len() - moved, local_keys(&node_a).await.len());
+        for key in &keys_b {
+            assert_eq!(Some(&node_b), ring.get_node(key.clone()));
+        }
+
+        let mut client_b = Client::new(&b.node.host, b.node.port).await.unwrap();
+        for key in &keys {
+            assert_eq!(Some(format!("value of {}", key)), client_b.get(key.clone()).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reads_and_writes_during_migration() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+
+        let keys: Vec<String> = (0..500).map(|i| format!("key{}", i)).collect();
+        let mut client_a = Client::new(&node_a.host, node_a.port).await.unwrap();
+        for key in &keys {
+            client_a.put(key.clone(), "old".to_string()).await.unwrap();
+        }
+
+        // keeps reading every key while b joins and the entries move
+        let reader = {
+            let keys = keys.clone();
+            let mut client = Client::new(&node_a.host, node_a.port).await.unwrap();
+            tokio::spawn(async move {
+                for _ in 0..3 {
+                    for key in &keys {
+                        assert!(client.get(key.clone()).await.unwrap().is_some(), "{} was not readable", key);
+                    }
+                }
+            })
+        };
+
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        for key in keys.iter().take(50) {
+            client_a.put(key.clone(), "new".to_string()).await.unwrap();
+        }
+        reader.await.unwrap();
+        converge(&[&a, &b], &[node_a.clone(), node_b.clone()]).await;
+
+        // writes made while migrating are not overwritten by the migrated values
+        let mut client_b = Client::new(&node_b.host, node_b.port).await.unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            let expected = if i < 50 { "new" } else { "old" };
+            assert_eq!(Some(expected.to_string()), client_b.get(key.clone()).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_migrations_skip_keys_deleted_during_handoff() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        converge(&[&a, &b], &[node_a.clone(), node_b.clone()]).await;
+
+        // a key b owns is deleted from it before the entry migrated from its previous owner arrives
+        let ring = HashRing::new(vec![node_a.clone(), node_b.clone()], 10);
+        let mut owned = (0..).map(|i| format!("key{}", i)).filter(|key| ring.get_node(key) == Some(&node_b));
+        let (deleted, kept) = (owned.next().unwrap(), owned.next().unwrap());
+        let mut client = Client::new(&node_a.host, node_a.port).await.unwrap();
+        assert!(!client.delete(deleted.clone()).await.unwrap());
+
+        let pairs = [&deleted, &kept].map(|key| KeyValuePair { key: key.clone(), value: "old".into(), ttl_ms: None });
+        let mut peer = PandasPouchCacheServiceClient::connect(node_b.uri()).await.unwrap();
+        let response = peer.migrate(tokio_stream::iter(pairs)).await.unwrap().into_inner();
+        assert_eq!(2, response.received);
+        assert_eq!(1, response.stored);
+        assert_eq!(vec![kept], local_keys(&node_b).await);
+    }
+
+    #[tokio::test]
+    async fn test_delete_from_any_node() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        converge(&[&a, &b], &[node_a.clone(), node_b.clone()]).await;
+
+        let keys: Vec<String> = (0..20).map(|i| format!("key{}", i)).collect();
+        let mut client_a = Client::new(&node_a.host, node_a.port).await.unwrap();
+        for key in &keys {
+            client_a.put(key.clone(), "value".to_string()).await.unwrap();
+        }
+
+        let mut client_b = Client::new(&node_b.host, node_b.port).await.unwrap();
+        for key in &keys {
+            assert!(client_b.delete(key.clone()).await.unwrap());
+            assert_eq!(None, client_a.get(key.clone()).await.unwrap());
+            assert!(!client_a.delete(key.clone()).await.unwrap());
+        }
+        assert!(local_keys(&node_a).await.is_empty());
+        assert!(local_keys(&node_b).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_put_with_ttl() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        converge(&[&a, &b], &[node_a.clone(), node_b.clone()]).await;
+
+        let mut client = Client::new(&node_a.host, node_a.port).await.unwrap();
+        for i in 0..10 {
+            let ttl = Duration::from_millis(if i % 2 == 0 { 200 } else { 60_000 });
+            client.put_with_ttl(format!("key{}", i), "value".to_string(), Some(ttl)).await.unwrap();
+        }
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        for i in 0..10 {
+            let expected = if i % 2 == 0 { None } else { Some("value".to_string()) };
+            assert_eq!(expected, client.get(format!("key{}", i)).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        converge(&[&a, &b], &[node_a.clone(), node_b.clone()]).await;
+
+        let mut client = Client::new(&node_a.host, node_a.port).await.unwrap();
+        for i in 0..10 {
+            client.put(format!("key{}", i), "value".to_string()).await.unwrap();
+        }
+        for i in 0..20 {
+            client.get(format!("key{}", i)).await.unwrap();
+        }
+
+        let stats_a = client.stats().await.unwrap();
+        let stats_b = Client::new(&node_b.host, node_b.port).await.unwrap().stats().await.unwrap();
+        assert_eq!(10, stats_a.puts);
+        assert_eq!(20, stats_a.gets);
+        assert_eq!(0, stats_b.gets);
+
+        // every key put is read once from the cache of its owner, the keys owned by b through a forward
+        assert!(stats_a.forwarded >= 2 * stats_b.entries);
+        assert_eq!(10, stats_a.hits + stats_b.hits);
+        assert_eq!(10, stats_a.entries + stats_b.entries);
+        assert_eq!(1000, stats_a.capacity);
+        assert_eq!(0, stats_a.db_reads);
+    }
+
+    // Starts three nodes replicating every key twice, and waits for them to see each other.
+    async fn start_replicated_cluster() -> (Vec<TestNode>, Vec<NodeInfo>) {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let (listener_c, node_c) = bind().await;
+        let a = start_replicated_node(listener_a, node_a.clone(), vec![], 2).await;
+        let b = start_replicated_node(listener_b, node_b.clone(), vec![node_a.clone()], 2).await;
+        let c = start_replicated_node(listener_c, node_c.clone(), vec![node_a.clone()], 2).await;
+        let nodes = vec![node_a, node_b, node_c];
+        converge(&[&a, &b, &c], &nodes).await;
+        (vec![a, b, c], nodes)
+    }
+
+    #[tokio::test]
+    async fn test_puts_reach_every_replica() {
+        let (_cluster, nodes) = start_replicated_cluster().await;
+        let keys: Vec<String> = (0..30).map(|i| format!("key{}", i)).collect();
+        let mut client = Client::new(&nodes[0].host, nodes[0].port).await.unwrap();
+        for key in &keys {
+            assert!(client.put(key.clone(), "value".to_string()).await.unwrap());
+        }
+
+        let ring = HashRing::new(nodes.clone(), 10);
+        let mut held = Vec::new();
+        for node in &nodes {
+            held.push(local_keys(node).await);
+        }
+        for key in &keys {
+            let replicas = ring.get_nodes(key, 2);
+            assert_eq!(2, replicas.len());
+            for (node, keys) in nodes.iter().zip(held.iter()) {
+                assert_eq!(replicas.contains(&node), keys.contains(key), "{} on {}", key, node);
+            }
+        }
+
+        // deletes reach every replica as well
+        for key in &keys {
+            assert!(client.delete(key.clone()).await.unwrap());
+        }
+        for node in &nodes {
+            assert!(local_keys(node).await.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_only_owners_persist() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let db_a: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let db_b: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let cluster_a = Cluster::new(node_a.clone(), Vec::new(), 10).with_replication_factor(2);
+        let cluster_b = Cluster::new(node_b.clone(), Vec::new(), 10).with_replication_factor(2);
+        let a = start_cluster_node(listener_a, cluster_a, vec![], Some(Arc::clone(&db_a))).await;
+        let b = start_cluster_node(listener_b, cluster_b, vec![node_a.clone()], Some(Arc::clone(&db_b))).await;
+        let nodes = vec![node_a.clone(), node_b.clone()];
+        converge(&[&a, &b], &nodes).await;
+
+        let keys: Vec<String> = (0..20).map(|i| format!("key{}", i)).collect();
+        let mut client = Client::new(&node_a.host, node_a.port).await.unwrap();
+        for key in &keys[..10] {
+            assert!(client.put(key.clone(), "value".to_string()).await.unwrap());
+        }
+        let entries: Vec<(String, String)> = keys[10..].iter().map(|key| (key.clone(), "value".to_string())).collect();
+        assert!(client.put_many(entries).await.unwrap());
+
+        // both nodes cache every key, but only its owner writes it to its database
+        let ring = HashRing::new(nodes.clone(), 10);
+        for (node, db) in [(&node_a, &db_a), (&node_b, &db_b)] {
+            assert_eq!(keys.len(), local_keys(node).await.len());
+            for key in &keys {
+                let owned = ring.get_node(key) == Some(node);
+                assert_eq!(owned, db.get(key).await.unwrap().is_some(), "{} in the database of {}", key, node);
+            }
+        }
+
+        for key in &keys {
+            assert!(client.delete(key.clone()).await.unwrap());
+        }
+        for db in [&db_a, &db_b] {
+            for key in &keys {
+                assert!(db.get(key).await.unwrap().is_none());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keys_migrate_to_every_new_replica() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let (listener_c, node_c) = bind().await;
+        let a = start_replicated_node(listener_a, node_a.clone(), vec![], 2).await;
+        let b = start_replicated_node(listener_b, node_b.clone(), vec![node_a.clone()], 2).await;
+        converge(&[&a, &b], &[node_a.clone(), node_b.clone()]).await;
+
+        let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+        let mut client = Client::new(&node_a.host, node_a.port).await.unwrap();
+        for key in &keys {
+            client.put(key.clone(), format!("value of {}", key)).await.unwrap();
+        }
+
+        // c becomes the owner of some keys, and the second replica of others
+        let c = start_replicated_node(listener_c, node_c.clone(), vec![node_a.clone()], 2).await;
+        let nodes = vec![node_a.clone(), node_b.clone(), node_c.clone()];
+        converge(&[&a, &b, &c], &nodes).await;
+        let ring = HashRing::new(nodes.clone(), 10);
+        assert!(keys.iter().any(|key| ring.get_nodes(key, 2)[0] == &node_c));
+        assert!(keys.iter().any(|key| ring.get_nodes(key, 2)[1] == &node_c));
+
+        let mut held = Vec::new();
+        for _ in 0..50 {
+            held.clear();
+            for node in &nodes {
+                held.push(local_keys(node).await);
+            }
+            let expected = |node: &NodeInfo| keys.iter().filter(|key| ring.get_nodes(key, 2).contains(&node)).count();
+            if nodes.iter().zip(held.iter()).all(|(node, keys)| keys.len() == expected(node)) {
+                break;
+            }
+            tokio::time::sleep(REJOIN_INTERVAL).await;
+        }
+        for key in &keys {
+            let replicas = ring.get_nodes(key, 2);
+            for (node, keys) in nodes.iter().zip(held.iter()) {
+                assert_eq!(replicas.contains(&node), keys.contains(key), "{} on {}", key, node);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gets_fall_back_to_replicas() {
+        let (mut cluster, nodes) = start_replicated_cluster().await;
+        let keys: Vec<String> = (0..30).map(|i| format!("key{}", i)).collect();
+        let mut client = Client::new(&nodes[0].host, nodes[0].port).await.unwrap();
+        for key in &keys {
+            client.put(key.clone(), format!("value of {}", key)).await.unwrap();
+        }
+
+        // b goes down without leaving, so the others still route its keys to it first
+        let ring = HashRing::new(nodes.clone(), 10);
+        assert!(keys.iter().any(|key| ring.get_node(key.clone()) == Some(&nodes[1])));
+        cluster.remove(1).stop().await;
+
+        for entry in [&nodes[0], &nodes[2]] {
+            let mut client = Client::new(&entry.host, entry.port).await.unwrap();
+            for key in &keys {
+                assert_eq!(Some(format!("value of {}", key)), client.get(key.clone()).await.unwrap(), "{} through {}", key, entry);
+            }
+        }
+
+        // and writes still succeed on the replica that is up
+        for key in &keys {
+            assert!(client.put(key.clone(), "new".to_string()).await.unwrap());
+            assert_eq!(Some("new".to_string()), client.get(key.clone()).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cluster_client_routes_to_owners() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+        let mut client = ClusterClient::connect(vec![node_a.clone()]).await.unwrap();
+        assert_eq!(vec![node_a.clone()], client.nodes());
+
+        // the client picks up the node that joined on a refresh
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        let nodes = vec![node_a.clone(), node_b.clone()];
+        converge(&[&a, &b], &nodes).await;
+        client.refresh().await.unwrap();
+        let mut expected = nodes.clone();
+        expected.sort();
+        assert_eq!(expected, client.nodes());
+
+        let keys: Vec<String> = (0..20).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            assert!(client.put(key.clone(), format!("value of {}", key)).await.unwrap());
+        }
+        for key in &keys {
+            assert_eq!(Some(format!("value of {}", key)), client.get(key.clone()).await.unwrap());
+        }
+        assert!(client.delete("key0".to_string()).await.unwrap());
+        assert_eq!(None, client.get("key0".to_string()).await.unwrap());
+
+        // every request went straight to the owner of its key, none was forwarded
+        let ring = HashRing::new(nodes.clone(), 10);
+        for node in &nodes {
+            let stats = Client::new(&node.host, node.port).await.unwrap().stats().await.unwrap();
+            assert_eq!(0, stats.forwarded, "forwarded by {}", node);
+            for key in local_keys(node).await {
+                assert_eq!(Some(node), ring.get_node(&key));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cluster_client_fails_over_to_replicas() {
+        let (mut cluster, nodes) = start_replicated_cluster().await;
+        let mut client = ClusterClient::connect(vec![nodes[0].clone()]).await.unwrap();
+        assert_eq!(3, client.nodes().len());
+
+        let keys: Vec<String> = (0..30).map(|i| format!("key{}", i)).collect();
+        for key in &keys {
+            client.put(key.clone(), format!("value of {}", key)).await.unwrap();
+        }
+
+        // b goes down without leaving, so the keys it owns are read from and written to their next replica
+        assert!(keys.iter().any(|key| client.replicas(key)[0] == nodes[1]));
+        cluster.remove(1).stop().await;
+        for key in &keys {
+            assert_eq!(Some(format!("value of {}", key)), client.get(key.clone()).await.unwrap(), "{}", key);
+            assert!(client.put(key.clone(), "new".to_string()).await.unwrap());
+            assert_eq!(Some("new".to_string()), client.get(key.clone()).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_to_a_hung_node_time_out() {
+        // the hung node accepts connections but never answers them
+        let (hung, node_hung) = bind().await;
+        let (listener_a, node_a) = bind().await;
+        let timeout = Duration::from_millis(200);
+        let cluster = Cluster::new(node_a.clone(), vec![node_hung.clone()], 10)
+            .with_replication_factor(2)
+            .with_timeouts(timeout, timeout);
+        let a = start_cluster_node(listener_a, cluster, vec![], None).await;
+
+        let keys: Vec<String> = (0..10).map(|i| format!("key{}", i)).collect();
+        assert!(keys.iter().any(|key| a.cluster.replicas(key)[0] == node_hung));
+        let mut client = Client::new(&node_a.host, node_a.port).await.unwrap();
+        for key in &keys {
+            assert!(client.put(key.clone(), format!("value of {}", key)).await.unwrap());
+            assert_eq!(Some(format!("value of {}", key)), client.get(key.clone()).await.unwrap(), "{}", key);
+        }
+
+        // the cluster client skips the hung node for the next replica as well, which waits for the hung node in turn
+        let client_timeout = Duration::from_secs(2);
+        let mut cluster_client = ClusterClient::connect(vec![node_a.clone()]).await.unwrap().with_timeouts(timeout, client_timeout);
+        assert_eq!(2, cluster_client.nodes().len());
+        for key in &keys {
+            assert_eq!(Some(format!("value of {}", key)), cluster_client.get(key.clone()).await.unwrap(), "{}", key);
+        }
+        drop(hung);
+    }
+
+    #[tokio::test]
+    async fn test_batches_split_by_owner() {
+        let (listener_a, node_a) = bind().await;
+        let (listener_b, node_b) = bind().await;
+        let a = start_node(listener_a, node_a.clone(), vec![]).await;
+        let b = start_node(listener_b, node_b.clone(), vec![node_a.clone()]).await;
+        let nodes = vec![node_a.clone(), node_b.clone()];
+        converge(&[&a, &b], &nodes).await;
+
+        let keys: Vec<String> = (0..20).map(|i| format!("key{}", i)).collect();
+        let entries: Vec<(String, String)> = keys.iter().map(|key| (key.clone(), format!("value of {}", key))).collect();
+        let mut client_a = Client::new(&node_a.host, node_a.port).await.unwrap();
+        assert!(client_a.put_many(entries).await.unwrap());
+
+        // every key is held only by its owner, which received its keys in a single forward
+        let ring = HashRing::new(nodes.clone(), 10);
+        for node in &nodes {
+            for key in local_keys(node).await {
+                assert_eq!(Some(node), ring.get_node(&key));
+            }
+        }
+        assert_eq!(1, client_a.stats().await.unwrap().forwarded);
+
+        let mut asked = keys.clone();
+        asked.push("missing".to_string());
+        let mut expected: Vec<Option<String>> = keys.iter().map(|key| Some(format!("value of {}", key))).collect();
+        expected.push(None);
+        let mut client_b = Client::new(&node_b.host, node_b.port).await.unwrap();
+        assert_eq!(expected, client_b.get_many(asked.clone()).await.unwrap());
+        assert_eq!(1, client_b.stats().await.unwrap().forwarded);
+
+        // the cluster client sends every owner its keys directly
+        let mut cluster_client = ClusterClient::connect(vec![node_a.clone()]).await.unwrap();
+        let entries: Vec<(String, String)> = keys.iter().map(|key| (key.clone(), "new".to_string())).collect();
+        assert!(cluster_client.put_many(entries).await.unwrap());
+        let values = cluster_client.get_many(asked).await.unwrap();
+        assert_eq!(20, values.iter().filter(|value| value.as_deref() == Some("new")).count());
+        assert_eq!(None, values[20]);
+        assert_eq!(1, client_a.stats().await.unwrap().forwarded);
+        assert_eq!(1, client_b.stats().await.unwrap().forwarded);
+    }
+
+    #[tokio::test]
+    async fn test_batches_fall_back_to_replicas() {
+        let (mut cluster, nodes) = start_replicated_cluster().await;
+        let keys: Vec<String> = (0..30).map(|i| format!("key{}", i)).collect();
+        let entries: Vec<(String, String)> = keys.iter().map(|key| (key.clone(), format!("value of {}", key))).collect();
+        let mut client = Client::new(&nodes[0].host, nodes[0].port).await.unwrap();
+        assert!(client.put_many(entries).await.unwrap());
+
+        let ring = HashRing::new(nodes.clone(), 10);
+        let mut held = Vec::new();
+        for node in &nodes {
+            held.push(local_keys(node).await);
+        }
+        for key in &keys {
+            for (node, keys) in nodes.iter().zip(held.iter()) {
+                assert_eq!(ring.get_nodes(key, 2).contains(&node), keys.contains(key), "{} on {}", key, node);
+            }
+        }
+
+        // b goes down without leaving, so its keys are read from their next replica
+        cluster.remove(1).stop().await;
+        let expected: Vec<Option<String>> = keys.iter().map(|key| Some(format!("value of {}", key))).collect();
+        assert_eq!(expected, client.get_many(keys.clone()).await.unwrap());
+        let mut cluster_client = ClusterClient::connect(vec![nodes[0].clone()]).await.unwrap();
+        assert_eq!(expected, cluster_client.get_many(keys.clone()).await.unwrap());
+
+        // and batches are still written to the replicas that are up
+        let entries: Vec<(String, String)> = keys.iter().map(|key| (key.clone(), "new".to_string())).collect();
+        assert!(cluster_client.put_many(entries.clone()).await.unwrap());
+        assert!(client.put_many(entries).await.unwrap());
+        let values = client.get_many(keys.clone()).await.unwrap();
+        assert!(values.iter().all(|value| value.as_deref() == Some("new")));
+    }
+}
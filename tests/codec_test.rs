@@ -0,0 +1,96 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use pandas_pouch::client::Client;
+    use pandas_pouch::codec::{Codec, CodecError, Json};
+    use pandas_pouch::error::PouchError;
+    #[cfg(feature = "bincode")]
+    use pandas_pouch::codec::Bincode;
+    #[cfg(feature = "msgpack")]
+    use pandas_pouch::codec::MessagePack;
+    use pandas_pouch::hash_ring::NodeInfo;
+    use pandas_pouch::lru::LRUCache;
+    use pandas_pouch::server::CacheServiceImpl;
+    use serde::{Deserialize, Serialize};
+    use crate::common::{serve, standalone};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Session {
+        user: String,
+        visits: u32,
+        roles: Vec<String>,
+        settings: BTreeMap<String, bool>,
+        expires_at: Option<u64>,
+    }
+
+    fn session() -> Session {
+        Session {
+            user: "panda".to_string(),
+            visits: 42,
+            roles: vec!["admin".to_string(), "reader".to_string()],
+            settings: BTreeMap::from([("dark_mode".to_string(), true)]),
+            expires_at: None,
+        }
+    }
+
+    async fn start_node() -> NodeInfo {
+        serve(|node| CacheServiceImpl::new(Arc::new(LRUCache::new(1000, None)), None, standalone(node))).await
+    }
+
+    fn check_codec(codec: impl Codec) {
+        let encoded = codec.encode(&session()).unwrap();
+        assert_eq!(codec.decode::<Session>(&encoded).unwrap(), session());
+        assert!(matches!(codec.decode::<Session>(&encoded[..encoded.len() / 2]), Err(CodecError::Decode(_))));
+    }
+
+    #[test]
+    fn test_codecs() {
+        check_codec(Json);
+        #[cfg(feature = "bincode")]
+        check_codec(Bincode);
+        #[cfg(feature = "msgpack")]
+        check_codec(MessagePack);
+        assert_eq!(Json.encode(&[1, 2, 3]).unwrap(), b"[1,2,3]");
+    }
+
+    #[tokio::test]
+    async fn test_json_client() {
+        let node = start_node().await;
+        let mut client = Client::new(&node.host, node.port).await.unwrap();
+
+        assert!(client.put_json("session".to_string(), &session()).await.unwrap());
+        assert_eq!(client.get_json::<Session>("session".to_string()).await.unwrap(), Some(session()));
+        assert_eq!(client.get("session".to_string()).await.unwrap(), Some(String::from_utf8(Json.encode(&session()).unwrap()).unwrap()));
+        assert_eq!(client.get_json::<Session>("missing".to_string()).await.unwrap(), None);
+
+        // a value that is not a session is a decode error, not a failed request
+        client.put("counter".to_string(), "7").await.unwrap();
+        assert_eq!(client.get_json::<u32>("counter".to_string()).await.unwrap(), Some(7));
+        let error = client.get_json::<Session>("counter".to_string()).await.unwrap_err();
+        assert!(matches!(error, PouchError::Codec(CodecError::Decode(_))), "{:?}", error);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[tokio::test]
+    async fn test_typed_client() {
+        use std::time::Duration;
+
+        let node = start_node().await;
+        let mut client = Client::new(&node.host, node.port).await.unwrap().with_codec(MessagePack);
+
+        assert!(client.put("session".to_string(), &session()).await.unwrap());
+        assert_eq!(client.get::<Session>("session".to_string()).await.unwrap(), Some(session()));
+        assert!(client.get::<Vec<String>>("session".to_string()).await.is_err());
+
+        client.put_with_ttl("short".to_string(), &1u8, Some(Duration::from_millis(20))).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(client.get::<u8>("short".to_string()).await.unwrap(), None);
+
+        assert!(client.delete("session".to_string()).await.unwrap());
+        assert_eq!(client.get::<Session>("session".to_string()).await.unwrap(), None);
+        assert_eq!(client.client().stats().await.unwrap().puts, 2);
+    }
+}
@@ -0,0 +1,38 @@
+// Helpers shared by the integration tests, not every test file uses all of them
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use pandas_pouch::client::Client;
+use pandas_pouch::cluster::Cluster;
+use pandas_pouch::hash_ring::NodeInfo;
+use pandas_pouch::pandas_pouch::pandas_pouch_cache_service_server::PandasPouchCacheServiceServer;
+use pandas_pouch::server::CacheServiceImpl;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+
+// A cluster of the node alone.
+pub fn standalone(node: NodeInfo) -> Arc<Cluster> {
+    Arc::new(Cluster::new(node, Vec::new(), 10))
+}
+
+// Serves the service built for a node on a free loopback port, returning the node.
+pub async fn serve(service: impl FnOnce(NodeInfo) -> CacheServiceImpl) -> NodeInfo {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let node = NodeInfo::new("127.0.0.1", listener.local_addr().unwrap().port());
+    let service = service(node.clone());
+    tokio::spawn(async move {
+        Server::builder()
+            .add_service(PandasPouchCacheServiceServer::new(service))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await
+            .unwrap();
+    });
+    node
+}
+
+// Serves the service built for a node, returning a client connected to it.
+pub async fn start_node(service: impl FnOnce(NodeInfo) -> CacheServiceImpl) -> Client {
+    let node = serve(service).await;
+    Client::new(&node.host, node.port).await.unwrap()
+}
@@ -0,0 +1,37 @@
+#[cfg(test)]
+mod tests {
+    use pandas_pouch::config::Settings;
+
+    fn settings() -> Settings {
+        Settings {
+            local_addr: "127.0.0.1".to_string(),
+            local_port: 50051,
+            database: Default::default(),
+            rust_log: "info".to_string(),
+            cluster: Default::default(),
+            cache: Default::default(),
+            metrics: Default::default(),
+            persistence: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_zero_intervals_are_rejected() {
+        assert!(settings().validate().is_ok());
+
+        let mut zero_rejoin = settings();
+        zero_rejoin.cluster.rejoin_interval_secs = 0;
+        let mut zero_sweep = settings();
+        zero_sweep.cache.sweep_interval_ms = 0;
+        let mut zero_flush = settings();
+        zero_flush.persistence.flush_interval_ms = 0;
+        for (settings, name) in [
+            (zero_rejoin, "cluster.rejoin_interval_secs"),
+            (zero_sweep, "cache.sweep_interval_ms"),
+            (zero_flush, "persistence.flush_interval_ms"),
+        ] {
+            let error = settings.validate().unwrap_err();
+            assert!(error.to_string().contains(name), "{}", error);
+        }
+    }
+}
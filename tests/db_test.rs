@@ -0,0 +1,219 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use bytes::Bytes;
+    use pandas_pouch::db::{MemoryStorage, SqliteStorage, Storage};
+    use pandas_pouch::lru::LRUCache;
+    use pandas_pouch::server::CacheServiceImpl;
+    use crate::common::{standalone, start_node};
+
+    async fn sqlite_storage() -> (SqliteStorage, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("pandas_pouch_{}.db", uuid::Uuid::new_v4()));
+        let storage = SqliteStorage::new(path.to_str().unwrap()).await.unwrap();
+        storage.create_table_if_not_exists().await.unwrap();
+        (storage, path)
+    }
+
+    // the behaviour every backend must have
+    async fn check_storage(storage: &dyn Storage) {
+        assert_eq!(storage.get("key1").await.unwrap(), None);
+
+        storage.put("key1", b"value1", None).await.unwrap();
+        assert_eq!(storage.get("key1").await.unwrap(), Some((b"value1".to_vec(), None)));
+        storage.put("key1", b"value2", None).await.unwrap();
+        assert_eq!(storage.get("key1").await.unwrap(), Some((b"value2".to_vec(), None)));
+
+        storage.put("key2", b"value2", Some(Duration::from_secs(60))).await.unwrap();
+        let (value, ttl) = storage.get("key2").await.unwrap().unwrap();
+        assert_eq!(value, b"value2");
+        assert!(ttl.unwrap() > Duration::from_secs(50));
+
+        storage.put("key3", b"value3", Some(Duration::from_millis(50))).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(storage.get("key3").await.unwrap(), None);
+        assert!(!storage.delete("key3").await.unwrap(), "expired entries are not found");
+
+        // a ttl too long to be stored never expires
+        storage.put("key4", b"value4", Some(Duration::MAX)).await.unwrap();
+        assert_eq!(storage.get("key4").await.unwrap(), Some((b"value4".to_vec(), None)));
+
+        assert!(storage.delete("key1").await.unwrap());
+        assert!(!storage.delete("key1").await.unwrap());
+        assert_eq!(storage.get("key1").await.unwrap(), None);
+    }
+
+    async fn check_get_many(storage: &dyn Storage) {
+        assert!(storage.get_many(&[]).await.unwrap().is_empty());
+
+        // more keys than a single SQLite query binds
+        let keys: Vec<String> = (0..1200).map(|i| format!("key{}", i)).collect();
+        for key in keys.iter().step_by(2) {
+            storage.put(key, format!("value of {}", key).as_bytes(), None).await.unwrap();
+        }
+        storage.put("expired", b"value", Some(Duration::from_millis(10))).await.unwrap();
+        storage.put("expiring", b"value", Some(Duration::from_secs(60))).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut asked = keys.clone();
+        asked.extend(["expired".to_string(), "expiring".to_string()]);
+        let found = storage.get_many(&asked).await.unwrap();
+        assert_eq!(found.len(), 601);
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(found.get(key).map(|(value, _)| value.clone()), (i % 2 == 0).then(|| format!("value of {}", key).into_bytes()));
+        }
+        assert!(!found.contains_key("expired"));
+        assert!(found["expiring"].1.unwrap() > Duration::from_secs(50));
+    }
+
+    async fn check_put_many(storage: &dyn Storage) {
+        storage.put_many(&[]).await.unwrap();
+
+        // more rows than a single SQLite query writes, with a key put twice
+        let values: Vec<String> = (0..1200).map(|i| format!("value{}", i)).collect();
+        let keys: Vec<String> = (0..1200).map(|i| format!("key{}", i)).collect();
+        let mut entries: Vec<(&str, &[u8], Option<Duration>)> = keys.iter().zip(values.iter())
+            .map(|(key, value)| (key.as_str(), value.as_bytes(), None))
+            .collect();
+        entries.push(("key0", b"last", Some(Duration::from_secs(60))));
+        storage.put_many(&entries).await.unwrap();
+
+        let found = storage.get_many(&keys).await.unwrap();
+        assert_eq!(found.len(), 1200);
+        assert_eq!(found["key1199"], (b"value1199".to_vec(), None));
+        let (value, ttl) = &found["key0"];
+        assert_eq!(value, b"last");
+        assert!(ttl.unwrap() > Duration::from_secs(50));
+    }
+
+    async fn check_scan(storage: &dyn Storage) {
+        for i in 0..25 {
+            storage.put(&format!("key{:02}", i), format!("value{}", i).as_bytes(), None).await.unwrap();
+        }
+        storage.put("expired", b"value", Some(Duration::from_millis(10))).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut keys = Vec::new();
+        let mut after = None;
+        loop {
+            let page = storage.scan(after.as_deref(), 10).await.unwrap();
+            assert!(page.len() <= 10);
+            if page.is_empty() {
+                break;
+            }
+            after = page.last().map(|(key, _, _)| key.clone());
+            keys.extend(page.into_iter().map(|(key, value, ttl)| {
+                assert_eq!(value, format!("value{}", key[3..].parse::<usize>().unwrap()).into_bytes());
+                assert_eq!(ttl, None);
+                key
+            }));
+        }
+        let expected: Vec<String> = (0..25).map(|i| format!("key{:02}", i)).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        check_storage(&MemoryStorage::new()).await;
+        check_get_many(&MemoryStorage::new()).await;
+        check_put_many(&MemoryStorage::new()).await;
+        check_scan(&MemoryStorage::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage() {
+        let (storage, path) = sqlite_storage().await;
+        check_storage(&storage).await;
+        std::fs::remove_file(path).ok();
+
+        let (storage, path) = sqlite_storage().await;
+        check_get_many(&storage).await;
+        std::fs::remove_file(path).ok();
+
+        let (storage, path) = sqlite_storage().await;
+        check_put_many(&storage).await;
+        std::fs::remove_file(path).ok();
+
+        let (storage, path) = sqlite_storage().await;
+        check_scan(&storage).await;
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_storage_persists() {
+        let (storage, path) = sqlite_storage().await;
+        storage.put("key1", b"value1", None).await.unwrap();
+        drop(storage);
+
+        let storage = SqliteStorage::new(path.to_str().unwrap()).await.unwrap();
+        storage.create_table_if_not_exists().await.unwrap();
+        assert_eq!(storage.get("key1").await.unwrap(), Some((b"value1".to_vec(), None)));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn test_server_falls_back_to_storage() {
+        let cache = Arc::new(LRUCache::new(1000, None));
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let mut client = start_node(|node| {
+            CacheServiceImpl::new(Arc::clone(&cache), Some(Arc::clone(&storage)), standalone(node))
+        }).await;
+
+        client.put("key1".to_string(), "value1".to_string()).await.unwrap();
+        assert_eq!(storage.get("key1").await.unwrap(), Some((b"value1".to_vec(), None)));
+
+        // the longest ttl a request can carry is accepted, and never expires
+        client.put_with_ttl("forever".to_string(), "value", Some(Duration::from_millis(u64::MAX))).await.unwrap();
+        assert_eq!(storage.get("forever").await.unwrap(), Some((b"value".to_vec(), None)));
+        assert_eq!(client.get("forever".to_string()).await.unwrap(), Some("value".to_string()));
+
+        // a miss in the cache is served from the storage, and cached again
+        cache.remove("key1".to_string());
+        assert_eq!(client.get("key1".to_string()).await.unwrap(), Some("value1".to_string()));
+        assert_eq!(cache.get(&"key1".to_string()), Some(Bytes::from("value1")));
+
+        assert!(client.delete("key1".to_string()).await.unwrap());
+        assert_eq!(storage.get("key1").await.unwrap(), None);
+        assert_eq!(client.get("key1".to_string()).await.unwrap(), None);
+
+        // a batch is written through, and its misses are read back from the storage and cached
+        let entries: Vec<(String, String)> = (0..10).map(|i| (format!("key{}", i), format!("value{}", i))).collect();
+        assert!(client.put_many(entries).await.unwrap());
+        assert_eq!(storage.get("key3").await.unwrap(), Some((b"value3".to_vec(), None)));
+        cache.remove("key3".to_string());
+        cache.remove("key7".to_string());
+        let db_reads = client.stats().await.unwrap().db_reads;
+
+        let keys = vec!["key3".to_string(), "key0".to_string(), "missing".to_string(), "key7".to_string()];
+        let values = client.get_many(keys).await.unwrap();
+        assert_eq!(values, vec![Some("value3".to_string()), Some("value0".to_string()), None, Some("value7".to_string())]);
+        assert_eq!(client.stats().await.unwrap().db_reads, db_reads + 3);
+        assert_eq!(cache.get(&"key7".to_string()), Some(Bytes::from("value7")));
+    }
+
+    #[tokio::test]
+    async fn test_binary_values() {
+        let cache = Arc::new(LRUCache::new(1000, None));
+        let (storage, path) = sqlite_storage().await;
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        let mut client = start_node(|node| {
+            CacheServiceImpl::new(Arc::clone(&cache), Some(Arc::clone(&storage)), standalone(node))
+        }).await;
+
+        // not valid UTF-8, with a nul byte in the middle
+        let blob = vec![0xff, 0x00, 0xfe, 0x80, b'a'];
+        client.put("blob".to_string(), blob.clone()).await.unwrap();
+        assert_eq!(storage.get("blob").await.unwrap(), Some((blob.clone(), None)));
+
+        cache.remove("blob".to_string());
+        assert_eq!(client.get_bytes("blob".to_string()).await.unwrap(), Some(Bytes::from(blob.clone())));
+        assert!(client.get("blob".to_string()).await.is_err(), "the text helper rejects invalid UTF-8");
+
+        assert!(client.put_many(vec![("other".to_string(), Bytes::from_static(&[0xc3, 0x28]))]).await.unwrap());
+        let values = client.get_many_bytes(vec!["blob".to_string(), "other".to_string()]).await.unwrap();
+        assert_eq!(values, vec![Some(Bytes::from(blob)), Some(Bytes::from_static(&[0xc3, 0x28]))]);
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,122 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use pandas_pouch::client::{Client, ClusterClient};
+    use pandas_pouch::db::Storage;
+    use pandas_pouch::error::PouchError;
+    use pandas_pouch::hash_ring::NodeInfo;
+    use pandas_pouch::lru::LRUCache;
+    use pandas_pouch::server::CacheServiceImpl;
+    use tokio::net::TcpListener;
+    use tonic::{async_trait, Code, Status};
+    use crate::common::{standalone, start_node};
+
+    // a storage whose every operation fails
+    struct BrokenStorage;
+
+    #[async_trait]
+    impl Storage for BrokenStorage {
+        async fn get(&self, _key: &str) -> Result<Option<(Vec<u8>, Option<Duration>)>, PouchError> {
+            Err(PouchError::Database("pool timed out".to_string()))
+        }
+
+        async fn put(&self, _key: &str, _value: &[u8], _ttl: Option<Duration>) -> Result<(), PouchError> {
+            Err(PouchError::Database("pool timed out".to_string()))
+        }
+
+        async fn delete(&self, _key: &str) -> Result<bool, PouchError> {
+            Err(PouchError::Database("pool timed out".to_string()))
+        }
+
+        async fn scan(&self, _after: Option<&str>, _limit: usize) -> Result<Vec<(String, Vec<u8>, Option<Duration>)>, PouchError> {
+            Err(PouchError::Database("pool timed out".to_string()))
+        }
+    }
+
+    fn round_trip(error: PouchError) -> PouchError {
+        PouchError::from(Status::from(error))
+    }
+
+    #[test]
+    fn test_status_round_trip() {
+        let cases = [
+            (PouchError::NotFound("key1".to_string()), Code::NotFound),
+            (PouchError::Database("pool timed out".to_string()), Code::Internal),
+            (PouchError::Transport("connection refused".to_string()), Code::Unavailable),
+            (PouchError::Timeout("key1".to_string()), Code::DeadlineExceeded),
+            (PouchError::NodeUnavailable("node1:50051".to_string()), Code::Unavailable),
+            (PouchError::ValueTooLarge { size: 2048, limit: 1024 }, Code::ResourceExhausted),
+            (PouchError::InvalidKey("empty".to_string()), Code::InvalidArgument),
+            (PouchError::InvalidPersistence("Unknown persistence mode 9".to_string()), Code::InvalidArgument),
+            (PouchError::NotConfigured("no database".to_string()), Code::FailedPrecondition),
+        ];
+        for (error, code) in cases {
+            let expected = format!("{:?}", error);
+            let status = Status::from(error);
+            assert_eq!(status.code(), code);
+            assert_eq!(format!("{:?}", PouchError::from(status)), expected);
+        }
+
+        // kinds sharing a code are told apart by the metadata
+        assert!(matches!(round_trip(PouchError::Transport("refused".to_string())), PouchError::Transport(message) if message == "refused"));
+        assert!(matches!(round_trip(PouchError::Database("broken".to_string())), PouchError::Database(message) if message == "broken"));
+
+        // statuses without metadata are mapped by their code
+        assert!(matches!(PouchError::from(Status::unavailable("down")), PouchError::NodeUnavailable(_)));
+        assert!(matches!(PouchError::from(Status::deadline_exceeded("slow")), PouchError::Timeout(_)));
+        assert!(matches!(PouchError::from(Status::internal("bug")), PouchError::Other(status) if status.code() == Code::Internal));
+        assert_eq!(PouchError::from(Status::failed_precondition("no database")).code(), Code::FailedPrecondition);
+
+        // only the failures of the node, not of the request, may succeed on another one
+        assert!(PouchError::Transport("refused".to_string()).is_retryable());
+        assert!(PouchError::from(Status::deadline_exceeded("slow")).is_retryable());
+        assert!(!PouchError::Database("broken".to_string()).is_retryable());
+        assert!(!PouchError::InvalidKey("empty".to_string()).is_retryable());
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let mut client = start_node(|node| {
+            CacheServiceImpl::new(Arc::new(LRUCache::new(1000, None)), None, standalone(node)).with_limits(8, 16)
+        }).await;
+
+        assert!(matches!(client.get(String::new()).await, Err(PouchError::InvalidKey(_))));
+        assert!(matches!(client.put("a long key".to_string(), "value").await, Err(PouchError::InvalidKey(_))));
+        let error = client.put("key1".to_string(), vec![0u8; 17]).await.unwrap_err();
+        assert!(matches!(error, PouchError::ValueTooLarge { size: 17, limit: 16 }), "{:?}", error);
+        let entries = vec![("key1".to_string(), vec![0u8; 16]), ("key2".to_string(), vec![0u8; 32])];
+        assert!(matches!(client.put_many(entries).await, Err(PouchError::ValueTooLarge { size: 32, .. })));
+
+        assert!(client.put("key1".to_string(), vec![0u8; 16]).await.unwrap());
+        assert_eq!(client.get_bytes("key1".to_string()).await.unwrap().map(|value| value.len()), Some(16));
+        assert!(client.get_bytes("key2".to_string()).await.unwrap().is_none(), "a rejected batch writes nothing");
+    }
+
+    #[tokio::test]
+    async fn test_database_errors() {
+        let mut client = start_node(|node| {
+            CacheServiceImpl::new(Arc::new(LRUCache::new(1000, None)), Some(Arc::new(BrokenStorage)), standalone(node))
+        }).await;
+
+        assert!(matches!(client.put("key1".to_string(), "value1").await, Err(PouchError::Database(_))));
+        assert!(matches!(client.get("key2".to_string()).await, Err(PouchError::Database(_))));
+        assert!(matches!(client.get_many(vec!["key2".to_string()]).await, Err(PouchError::Database(_))));
+        assert!(matches!(client.put_many(vec![("key3".to_string(), "value3")]).await, Err(PouchError::Database(_))));
+        assert!(matches!(client.delete("key1".to_string()).await, Err(PouchError::Database(_))));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_nodes() {
+        // a port nothing listens on once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        assert!(matches!(Client::new("127.0.0.1", port).await, Err(PouchError::Transport(_))));
+        let error = ClusterClient::connect(vec![NodeInfo::new("127.0.0.1", port)]).await.err().unwrap();
+        assert!(matches!(error, PouchError::NodeUnavailable(_)), "{:?}", error);
+    }
+}
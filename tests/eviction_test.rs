@@ -0,0 +1,174 @@
+#[cfg(test)]
+mod tests {
+    use pandas_pouch::eviction::{EvictionPolicy, EvictionPolicyKind, KeyList, TinyLfuPolicy};
+    use pandas_pouch::lru::LRUCache;
+
+    const CAPACITY: usize = 500;
+    const POLICIES: [EvictionPolicyKind; 4] = [
+        EvictionPolicyKind::Lru,
+        EvictionPolicyKind::Lfu,
+        EvictionPolicyKind::Arc,
+        EvictionPolicyKind::TinyLfu,
+    ];
+
+    // xorshift, so the traces are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next_f64(&mut self) -> f64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 11) as f64 / (1u64 << 53) as f64
+        }
+    }
+
+    // keys drawn from a Zipf distribution over `keys` keys, key 0 being the most popular
+    fn zipf_trace(keys: usize, exponent: f64, length: usize, seed: u64) -> Vec<u64> {
+        let mut cdf = Vec::with_capacity(keys);
+        let mut total = 0.0;
+        for rank in 1..=keys {
+            total += 1.0 / (rank as f64).powf(exponent);
+            cdf.push(total);
+        }
+
+        let mut rng = Rng(seed);
+        (0..length)
+            .map(|_| {
+                let target = rng.next_f64() * total;
+                cdf.partition_point(|c| *c < target) as u64
+            })
+            .collect()
+    }
+
+    // a Zipf workload interrupted by long scans of keys that are read only once
+    fn scan_trace() -> Vec<u64> {
+        let hot = zipf_trace(1000, 1.0, 60_000, 7);
+        let mut trace = Vec::new();
+        let mut next_scan_key = 1_000_000;
+        for chunk in hot.chunks(1000) {
+            trace.extend_from_slice(chunk);
+            trace.extend(next_scan_key..next_scan_key + 1000);
+            next_scan_key += 1000;
+        }
+        trace
+    }
+
+    // replays the trace as reads, putting the key on every miss, and returns the hit ratio
+    fn hit_ratio(policy: EvictionPolicyKind, trace: &[u64]) -> f64 {
+        let cache = LRUCache::with_segments(CAPACITY, None, 1, policy);
+        let mut hits = 0;
+        for key in trace {
+            if cache.get(key).is_some() {
+                hits += 1;
+            } else {
+                cache.put(*key, *key);
+            }
+            assert!(cache.len() <= CAPACITY);
+        }
+        hits as f64 / trace.len() as f64
+    }
+
+    #[test]
+    fn test_zipf_hit_ratio() {
+        let trace = zipf_trace(10_000, 0.9, 100_000, 42);
+        let lru = hit_ratio(EvictionPolicyKind::Lru, &trace);
+        assert!(lru > 0.3, "lru hit ratio {}", lru);
+
+        // frequency pays off on a skewed workload
+        for policy in POLICIES.into_iter().skip(1) {
+            let ratio = hit_ratio(policy, &trace);
+            assert!(ratio > lru + 0.05, "{:?} hit ratio {} against lru {}", policy, ratio, lru);
+        }
+    }
+
+    #[test]
+    fn test_scan_hit_ratio() {
+        let trace = scan_trace();
+        let lru = hit_ratio(EvictionPolicyKind::Lru, &trace);
+
+        // the scans flush the hot keys out of the lru, the other policies keep most of them
+        for policy in POLICIES.into_iter().skip(1) {
+            let ratio = hit_ratio(policy, &trace);
+            assert!(ratio > lru + 0.05, "{:?} hit ratio {} against lru {}", policy, ratio, lru);
+        }
+    }
+
+    #[test]
+    fn test_policies_evict_within_capacity() {
+        for policy in POLICIES {
+            let cache = LRUCache::with_segments(2, None, 1, policy);
+            cache.put(1, "a");
+            cache.put(2, "b");
+            cache.put(3, "c");
+            assert!(cache.len() <= 2, "{:?} holds {} entries", policy, cache.len());
+
+            // removed keys free their space for any policy
+            cache.remove(3);
+            cache.remove(2);
+            cache.remove(1);
+            assert!(cache.is_empty());
+            cache.put(4, "d");
+            assert_eq!(cache.get(&4), Some("d"), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn test_key_list() {
+        let mut list = KeyList::new();
+        list.push_front(1, 1);
+        list.push_front(2, 2);
+        list.push_front(3, 3);
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.weight(), 6);
+        assert_eq!(list.back(), Some(&1));
+
+        assert!(list.move_to_front(&1));
+        assert_eq!(list.back(), Some(&2));
+        assert_eq!(list.remove(&2), Some(2));
+        assert_eq!(list.remove(&2), None);
+
+        // pushing a key again updates its weight
+        list.push_front(3, 5);
+        assert_eq!(list.weight(), 6);
+        assert_eq!(list.pop_back(), Some((1, 1)));
+        assert_eq!(list.pop_back(), Some((3, 5)));
+        assert_eq!(list.pop_back(), None);
+        assert!(list.is_empty());
+        assert_eq!(list.weight(), 0);
+    }
+
+    #[test]
+    fn test_policies_respect_weights() {
+        let trace = zipf_trace(2000, 0.9, 20_000, 3);
+        for policy in POLICIES {
+            // entries weigh between 1 and 50
+            let cache = LRUCache::with_segments(1000, None, 1, policy).with_weigher(|key: &u64, _: &u64| (*key % 50 + 1) as usize);
+            for key in trace.iter() {
+                if cache.get(key).is_none() {
+                    cache.put(*key, *key);
+                }
+                assert!(cache.weight() <= 1000, "{:?} weighs {}", policy, cache.weight());
+            }
+            let weight: u64 = cache.print().iter().map(|(key, _)| key % 50 + 1).sum();
+            assert_eq!(weight as usize, cache.weight(), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn test_tiny_lfu_sketch_follows_key_count() {
+        // a segment of the default 64 MiB cache weighed in bytes, holding 2000 entries of 1 KiB
+        let mut policy = TinyLfuPolicy::new((64 << 20) / 32);
+        for key in 0..2000u64 {
+            policy.on_insert(&key, 1024);
+        }
+        assert!(policy.sketch_size() <= 4 * 2048, "{} counters", policy.sketch_size());
+
+        // a capacity counted in entries still caps the sketch
+        let mut policy = TinyLfuPolicy::new(100);
+        for key in 0..2000u64 {
+            policy.on_insert(&key, 1);
+        }
+        assert_eq!(policy.sketch_size(), 4 * 128);
+    }
+}
@@ -1,200 +1,422 @@
-#[cfg(test)]
-mod test {
-    use std::fmt::Display;
-    use pandas_pouch::hash_ring::{HashRing, NodeInfo};
-    use std::hash::BuildHasherDefault;
-    use std::hash::Hasher;
-
-    // Defines a NodeInfo for a localhost address with a given port.
-    fn node(port: u16) -> NodeInfo {
-        NodeInfo {
-            host: "localhost",
-            port,
-        }
-    }
-
-    #[test]
-    fn test_empty_ring() {
-        let hash_ring: HashRing<NodeInfo> = HashRing::new(vec![], 10);
-        assert_eq!(None, hash_ring.get_node("hello".to_string()));
-    }
-
-    #[test]
-    fn test_default_nodes() {
-        let mut nodes: Vec<NodeInfo> = Vec::new();
-        nodes.push(node(15324));
-        nodes.push(node(15325));
-        nodes.push(node(15326));
-        nodes.push(node(15327));
-        nodes.push(node(15328));
-        nodes.push(node(15329));
-
-        let mut hash_ring: HashRing<NodeInfo> = HashRing::new(nodes, 10);
-
-        assert_eq!(Some(&node(15324)), hash_ring.get_node("two".to_string()));
-        assert_eq!(Some(&node(15325)), hash_ring.get_node("seven".to_string()));
-        assert_eq!(Some(&node(15326)), hash_ring.get_node("hello".to_string()));
-        assert_eq!(Some(&node(15327)), hash_ring.get_node("dude".to_string()));
-        assert_eq!(Some(&node(15328)), hash_ring.get_node("fourteen".to_string()));
-        assert_eq!(Some(&node(15329)), hash_ring.get_node("five".to_string()));
-
-        hash_ring.remove_node(&node(15329));
-        assert_eq!(Some(&node(15326)), hash_ring.get_node("hello".to_string()));
-
-        hash_ring.add_node(&node(15329));
-        assert_eq!(Some(&node(15326)), hash_ring.get_node("hello".to_string()));
-    }
-
-    #[derive(Clone)]
-    struct CustomNodeInfo {
-        pub host: &'static str,
-        pub port: u16,
-    }
-
-    impl Display for CustomNodeInfo {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(f, "{}", format!("{}:{}", self.host, self.port))
-        }
-    }
-
-    #[test]
-    fn test_custom_nodes() {
-        let mut nodes: Vec<CustomNodeInfo> = Vec::new();
-        nodes.push(CustomNodeInfo {
-            host: "localhost",
-            port: 15324,
-        });
-        nodes.push(CustomNodeInfo {
-            host: "localhost",
-            port: 15325,
-        });
-        nodes.push(CustomNodeInfo {
-            host: "localhost",
-            port: 15326,
-        });
-        nodes.push(CustomNodeInfo {
-            host: "localhost",
-            port: 15327,
-        });
-        nodes.push(CustomNodeInfo {
-            host: "localhost",
-            port: 15328,
-        });
-        nodes.push(CustomNodeInfo {
-            host: "localhost",
-            port: 15329,
-        });
-
-        let mut hash_ring: HashRing<CustomNodeInfo> = HashRing::new(nodes, 10);
-
-        assert_eq!(
-            Some("localhost:15326".to_string()),
-            hash_ring
-                .get_node("hello".to_string())
-                .map(|x| x.to_string(),)
-        );
-        assert_eq!(
-            Some("localhost:15327".to_string()),
-            hash_ring
-                .get_node("dude".to_string())
-                .map(|x| x.to_string(),)
-        );
-
-        hash_ring.remove_node(&CustomNodeInfo {
-            host: "localhost",
-            port: 15329,
-        });
-        assert_eq!(
-            Some("localhost:15326".to_string()),
-            hash_ring
-                .get_node("hello".to_string())
-                .map(|x| x.to_string(),)
-        );
-
-        hash_ring.add_node(&CustomNodeInfo {
-            host: "localhost",
-            port: 15329,
-        });
-        assert_eq!(
-            Some("localhost:15326".to_string()),
-            hash_ring
-                .get_node("hello".to_string())
-                .map(|x| x.to_string(),)
-        );
-    }
-
-    #[test]
-    fn test_remove_actual_node() {
-        let mut nodes: Vec<NodeInfo> = Vec::new();
-        nodes.push(node(15324));
-        nodes.push(node(15325));
-        nodes.push(node(15326));
-        nodes.push(node(15327));
-        nodes.push(node(15328));
-        nodes.push(node(15329));
-
-        let mut hash_ring: HashRing<NodeInfo> = HashRing::new(nodes, 10);
-
-        // This should be num nodes * num replicas
-        assert_eq!(60, hash_ring.sorted_keys.len());
-        assert_eq!(60, hash_ring.ring.len());
-
-        hash_ring.remove_node(&node(15326));
-
-        // This should be num nodes * num replicas
-        assert_eq!(50, hash_ring.sorted_keys.len());
-        assert_eq!(50, hash_ring.ring.len());
-    }
-
-    #[test]
-    fn test_remove_non_existent_node() {
-        let mut nodes: Vec<NodeInfo> = Vec::new();
-        nodes.push(node(15324));
-        nodes.push(node(15325));
-        nodes.push(node(15326));
-        nodes.push(node(15327));
-        nodes.push(node(15328));
-        nodes.push(node(15329));
-
-        let mut hash_ring: HashRing<NodeInfo> = HashRing::new(nodes, 10);
-
-        hash_ring.remove_node(&node(15330));
-
-        // This should be num nodes * num replicas
-        assert_eq!(60, hash_ring.sorted_keys.len());
-        assert_eq!(60, hash_ring.ring.len());
-    }
-
-    #[test]
-    fn test_custom_hasher() {
-        #[derive(Default)]
-        struct ConstantHasher;
-
-        impl Hasher for ConstantHasher {
-            fn finish(&self) -> u64 {
-                1
-            }
-
-            fn write(&mut self, _bytes: &[u8]) {
-                // Do nothing
-            }
-        }
-
-        type ConstantBuildHasher = BuildHasherDefault<ConstantHasher>;
-
-        let mut nodes: Vec<NodeInfo> = Vec::new();
-        nodes.push(node(15324));
-        nodes.push(node(15325));
-        nodes.push(node(15326));
-        nodes.push(node(15327));
-        nodes.push(node(15328));
-        nodes.push(node(15329));
-
-        let hash_ring: HashRing<NodeInfo, ConstantBuildHasher> =
-            HashRing::with_hasher(nodes, 10, ConstantBuildHasher::default());
-
-        assert_eq!(Some(&node(15329)), hash_ring.get_node("hello".to_string()));
-        assert_eq!(Some(&node(15329)), hash_ring.get_node("dude".to_string()));
-        assert_eq!(Some(&node(15329)), hash_ring.get_node("two".to_string()));
-    }
+#[cfg(test)]
+mod test {
+    use std::fmt::Display;
+    use pandas_pouch::hash_ring::{HashRing, NodeInfo, NodeLoads, MAX_WEIGHT};
+    use std::hash::BuildHasherDefault;
+    use std::hash::Hasher;
+
+    // Defines a NodeInfo for a localhost address with a given port.
+    fn node(port: u16) -> NodeInfo {
+        NodeInfo {
+            host: "localhost".to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_empty_ring() {
+        let hash_ring: HashRing<NodeInfo> = HashRing::new(vec![], 10);
+        assert_eq!(None, hash_ring.get_node("hello"));
+    }
+
+    #[test]
+    fn test_default_nodes() {
+        let nodes: Vec<NodeInfo> = vec![
+            node(15324),
+            node(15325),
+            node(15326),
+            node(15327),
+            node(15328),
+            node(15329),
+        ];
+
+        let mut hash_ring: HashRing<NodeInfo> = HashRing::new(nodes, 10);
+
+        assert_eq!(Some(&node(15324)), hash_ring.get_node("two"));
+        assert_eq!(Some(&node(15325)), hash_ring.get_node("seven"));
+        assert_eq!(Some(&node(15326)), hash_ring.get_node("hello"));
+        assert_eq!(Some(&node(15327)), hash_ring.get_node("dude"));
+        assert_eq!(Some(&node(15328)), hash_ring.get_node("fourteen"));
+        assert_eq!(Some(&node(15329)), hash_ring.get_node("five"));
+
+        hash_ring.remove_node(&node(15329));
+        assert_eq!(Some(&node(15326)), hash_ring.get_node("hello"));
+
+        hash_ring.add_node(&node(15329));
+        assert_eq!(Some(&node(15326)), hash_ring.get_node("hello"));
+    }
+
+    #[test]
+    fn test_get_nodes() {
+        let nodes: Vec<NodeInfo> = (15324..15330).map(node).collect();
+        let hash_ring: HashRing<NodeInfo> = HashRing::new(nodes, 10);
+
+        for key in ["two", "seven", "hello", "dude", "fourteen", "five"] {
+            let replicas = hash_ring.get_nodes(key, 3);
+            assert_eq!(3, replicas.len());
+            assert_eq!(hash_ring.get_node(key), Some(replicas[0]));
+            assert!(replicas[0] != replicas[1] && replicas[0] != replicas[2] && replicas[1] != replicas[2]);
+
+            // a longer list extends a shorter one
+            assert_eq!(replicas, hash_ring.get_nodes(key, 5)[..3]);
+        }
+
+        assert_eq!(6, hash_ring.get_nodes("hello", 10).len());
+        assert_eq!(6, hash_ring.get_nodes("hello", usize::MAX).len());
+        assert!(hash_ring.get_nodes("hello", 0).is_empty());
+    }
+
+    #[test]
+    fn test_get_nodes_empty_ring() {
+        let hash_ring: HashRing<NodeInfo> = HashRing::new(vec![], 10);
+        assert!(hash_ring.get_nodes("hello", 3).is_empty());
+    }
+
+    #[test]
+    fn test_get_nodes_stability() {
+        let nodes: Vec<NodeInfo> = (15324..15330).map(node).collect();
+        let mut hash_ring: HashRing<NodeInfo> = HashRing::new(nodes, 10);
+        let keys: Vec<String> = (0..200).map(|i| format!("key{}", i)).collect();
+        let before: Vec<Vec<NodeInfo>> = keys
+            .iter()
+            .map(|key| hash_ring.get_nodes(key, 3).into_iter().cloned().collect())
+            .collect();
+
+        // adding a node only inserts it into the lists, pushing their last node out
+        hash_ring.add_node(&node(15330));
+        for (key, old) in keys.iter().zip(&before) {
+            let new: Vec<NodeInfo> = hash_ring.get_nodes(key, 3).into_iter().cloned().collect();
+            let kept: Vec<NodeInfo> = new.iter().filter(|n| **n != node(15330)).cloned().collect();
+            assert_eq!(kept, old[..kept.len()]);
+        }
+
+        // removing it again restores the previous lists
+        hash_ring.remove_node(&node(15330));
+        for (key, old) in keys.iter().zip(&before) {
+            let new: Vec<NodeInfo> = hash_ring.get_nodes(key, 3).into_iter().cloned().collect();
+            assert_eq!(&new, old);
+        }
+
+        // removing a node only drops it from the lists, the next node taking its place at the end
+        hash_ring.remove_node(&node(15326));
+        for (key, old) in keys.iter().zip(&before) {
+            let new: Vec<NodeInfo> = hash_ring.get_nodes(key, 3).into_iter().cloned().collect();
+            let kept: Vec<NodeInfo> = old.iter().filter(|n| **n != node(15326)).cloned().collect();
+            assert_eq!(kept, new[..kept.len()]);
+            assert_eq!(3, new.len());
+        }
+    }
+
+    // share of the keys each node owns
+    fn key_shares(hash_ring: &HashRing<NodeInfo>, nodes: &[NodeInfo], keys: usize) -> Vec<f64> {
+        let mut owned = vec![0; nodes.len()];
+        for i in 0..keys {
+            let owner = hash_ring.get_node(format!("key{}", i)).unwrap();
+            owned[nodes.iter().position(|n| n == owner).unwrap()] += 1;
+        }
+        owned.into_iter().map(|count| count as f64 / keys as f64).collect()
+    }
+
+    #[test]
+    fn test_weighted_distribution() {
+        let nodes: Vec<NodeInfo> = (15324..15328).map(node).collect();
+        let weights = [1, 2, 3, 4];
+        let mut hash_ring: HashRing<NodeInfo> = HashRing::new(vec![], 100);
+        for (n, weight) in nodes.iter().zip(weights) {
+            hash_ring.add_node_with_weight(n, weight);
+        }
+        assert_eq!(1000, hash_ring.sorted_keys.len());
+        assert_eq!(Some(3), hash_ring.weight(&node(15326)));
+
+        let total: usize = weights.iter().sum();
+        for (share, weight) in key_shares(&hash_ring, &nodes, 50_000).into_iter().zip(weights) {
+            let target = weight as f64 / total as f64;
+            assert!((share - target).abs() < target * 0.25, "share {} for a target of {}", share, target);
+        }
+    }
+
+    #[test]
+    fn test_set_weight() {
+        let nodes: Vec<NodeInfo> = (15324..15328).map(node).collect();
+        let mut hash_ring: HashRing<NodeInfo> = HashRing::new(nodes.clone(), 100);
+        let before: Vec<NodeInfo> = (0..1000)
+            .map(|i| hash_ring.get_node(format!("key{}", i)).unwrap().clone())
+            .collect();
+
+        // keys only move to the heavier node
+        assert!(hash_ring.set_weight(&node(15325), 3));
+        assert_eq!(Some(3), hash_ring.weight(&node(15325)));
+        assert_eq!(600, hash_ring.sorted_keys.len());
+        for (i, old) in before.iter().enumerate() {
+            let new = hash_ring.get_node(format!("key{}", i)).unwrap();
+            assert!(new == old || *new == node(15325));
+        }
+        let shares = key_shares(&hash_ring, &nodes, 50_000);
+        assert!((shares[1] - 0.5).abs() < 0.125, "share {} for a target of 0.5", shares[1]);
+
+        // setting the weight back restores the previous owners
+        assert!(hash_ring.set_weight(&node(15325), 1));
+        for (i, old) in before.iter().enumerate() {
+            assert_eq!(Some(old), hash_ring.get_node(format!("key{}", i)));
+        }
+
+        assert!(!hash_ring.set_weight(&node(15330), 2));
+        assert_eq!(None, hash_ring.weight(&node(15330)));
+
+        // a weight over the limit is rejected, and leaves the node as it was
+        assert!(!hash_ring.set_weight(&node(15325), MAX_WEIGHT + 1));
+        assert!(!hash_ring.add_node_with_weight(&node(15330), usize::MAX));
+        assert_eq!(Some(1), hash_ring.weight(&node(15325)));
+        assert_eq!(None, hash_ring.weight(&node(15330)));
+        assert_eq!(400, hash_ring.sorted_keys.len());
+
+        // adding a node again replaces its virtual nodes
+        assert!(hash_ring.add_node_with_weight(&node(15325), 3));
+        assert!(hash_ring.add_node_with_weight(&node(15325), 1));
+        assert_eq!(400, hash_ring.sorted_keys.len());
+        assert_eq!(400, hash_ring.ring.len());
+        for (i, old) in before.iter().enumerate() {
+            assert_eq!(Some(old), hash_ring.get_node(format!("key{}", i)));
+        }
+
+        hash_ring.remove_node(&node(15325));
+        assert_eq!(300, hash_ring.sorted_keys.len());
+        assert_eq!(None, hash_ring.weight(&node(15325)));
+    }
+
+    #[test]
+    fn test_bounded_load_skips_full_nodes() {
+        let nodes: Vec<NodeInfo> = (15324..15330).map(node).collect();
+        let hash_ring: HashRing<NodeInfo> = HashRing::new(nodes.clone(), 10);
+
+        // without loads every node has room, so the owner is found
+        let loads = NodeLoads::new();
+        for key in ["two", "seven", "hello", "dude"] {
+            assert_eq!(hash_ring.get_node(key), hash_ring.get_node_bounded(key, &loads, 0.25));
+        }
+
+        // the owner is at its capacity of ceil(1.25 * (58 + 1) / 6) = 13, so the key goes to its first replica
+        let mut loads = NodeLoads::new();
+        let replicas = hash_ring.get_nodes("hello", 2);
+        loads.set(replicas[0], 13);
+        for n in nodes.iter().filter(|n| *n != replicas[0]) {
+            loads.set(n, 9);
+        }
+        assert_eq!(Some(replicas[1]), hash_ring.get_node_bounded("hello", &loads, 0.25));
+        loads.set(replicas[0], 12);
+        assert_eq!(Some(replicas[0]), hash_ring.get_node_bounded("hello", &loads, 0.25));
+
+        // the loads of nodes that left the ring are not counted
+        loads.set(&node(15399), 1000);
+        assert_eq!(Some(replicas[0]), hash_ring.get_node_bounded("hello", &loads, 0.25));
+
+        let hash_ring: HashRing<NodeInfo> = HashRing::new(vec![], 10);
+        assert_eq!(None, hash_ring.get_node_bounded("hello", &loads, 0.25));
+    }
+
+    #[test]
+    fn test_bounded_load_placement() {
+        let nodes: Vec<NodeInfo> = (15324..15330).map(node).collect();
+        let mut hash_ring: HashRing<NodeInfo> = HashRing::new(nodes.clone(), 10);
+        hash_ring.set_weight(&node(15324), 2);
+
+        // placing the keys one at a time keeps every node under its bound, even with a few very hot keys
+        let place = |hash_ring: &HashRing<NodeInfo>| {
+            let mut loads = NodeLoads::new();
+            let mut placed = Vec::new();
+            for i in 0..7000 {
+                let key = format!("key{}", if i % 2 == 0 { i % 10 } else { i });
+                let owner = hash_ring.get_node_bounded(&key, &loads, 0.2).unwrap().clone();
+                loads.add(&owner, 1);
+                placed.push(owner);
+            }
+            (loads, placed)
+        };
+        let (loads, placed) = place(&hash_ring);
+        for n in &nodes {
+            let weight = hash_ring.weight(n).unwrap() as f64;
+            assert!(loads.get(n) as f64 <= (1.2 * 7000.0 * weight / 7.0).ceil(), "load {} on {}", loads.get(n), n);
+        }
+
+        // the same snapshots give the same placement
+        assert_eq!(placed, place(&hash_ring).1);
+    }
+
+    #[derive(Clone)]
+    struct CustomNodeInfo {
+        pub host: &'static str,
+        pub port: u16,
+    }
+
+    impl Display for CustomNodeInfo {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+
+    #[test]
+    fn test_custom_nodes() {
+        let nodes: Vec<CustomNodeInfo> = vec![
+            CustomNodeInfo {
+                host: "localhost",
+                port: 15324,
+            },
+            CustomNodeInfo {
+                host: "localhost",
+                port: 15325,
+            },
+            CustomNodeInfo {
+                host: "localhost",
+                port: 15326,
+            },
+            CustomNodeInfo {
+                host: "localhost",
+                port: 15327,
+            },
+            CustomNodeInfo {
+                host: "localhost",
+                port: 15328,
+            },
+            CustomNodeInfo {
+                host: "localhost",
+                port: 15329,
+            },
+        ];
+
+        let mut hash_ring: HashRing<CustomNodeInfo> = HashRing::new(nodes, 10);
+
+        assert_eq!(
+            Some("localhost:15326".to_string()),
+            hash_ring
+                .get_node("hello")
+                .map(|x| x.to_string(),)
+        );
+        assert_eq!(
+            Some("localhost:15327".to_string()),
+            hash_ring
+                .get_node("dude")
+                .map(|x| x.to_string(),)
+        );
+
+        hash_ring.remove_node(&CustomNodeInfo {
+            host: "localhost",
+            port: 15329,
+        });
+        assert_eq!(
+            Some("localhost:15326".to_string()),
+            hash_ring
+                .get_node("hello")
+                .map(|x| x.to_string(),)
+        );
+
+        hash_ring.add_node(&CustomNodeInfo {
+            host: "localhost",
+            port: 15329,
+        });
+        assert_eq!(
+            Some("localhost:15326".to_string()),
+            hash_ring
+                .get_node("hello")
+                .map(|x| x.to_string(),)
+        );
+    }
+
+    #[test]
+    fn test_remove_actual_node() {
+        let nodes: Vec<NodeInfo> = vec![
+            node(15324),
+            node(15325),
+            node(15326),
+            node(15327),
+            node(15328),
+            node(15329),
+        ];
+
+        let mut hash_ring: HashRing<NodeInfo> = HashRing::new(nodes, 10);
+
+        // This should be num nodes * num replicas
+        assert_eq!(60, hash_ring.sorted_keys.len());
+        assert_eq!(60, hash_ring.ring.len());
+
+        hash_ring.remove_node(&node(15326));
+
+        // This should be num nodes * num replicas
+        assert_eq!(50, hash_ring.sorted_keys.len());
+        assert_eq!(50, hash_ring.ring.len());
+    }
+
+    #[test]
+    fn test_sorted_keys_stay_sorted() {
+        let mut hash_ring: HashRing<NodeInfo> = HashRing::new((15324..15334).map(node).collect(), 200);
+        hash_ring.remove_node(&node(15327));
+        hash_ring.add_node_with_weight(&node(15340), 3);
+        hash_ring.add_node(&node(15341));
+        hash_ring.remove_node(&node(15324));
+
+        assert_eq!(12 * 200, hash_ring.sorted_keys.len());
+        assert_eq!(hash_ring.ring.len(), hash_ring.sorted_keys.len());
+        assert!(hash_ring.sorted_keys.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // a position is owned by the first virtual node at or after it, and the last one wraps around to the first
+        let first = hash_ring.sorted_keys[0];
+        let last = hash_ring.sorted_keys[hash_ring.sorted_keys.len() - 1];
+        assert_eq!(hash_ring.ring.get(&first), hash_ring.node_at(0));
+        assert_eq!(hash_ring.ring.get(&first), hash_ring.node_at(last + 1));
+        assert_eq!(hash_ring.ring.get(&last), hash_ring.node_at(last));
+        assert_eq!(hash_ring.ring.get(&last), hash_ring.node_at(last - 1));
+    }
+
+    #[test]
+    fn test_remove_non_existent_node() {
+        let nodes: Vec<NodeInfo> = vec![
+            node(15324),
+            node(15325),
+            node(15326),
+            node(15327),
+            node(15328),
+            node(15329),
+        ];
+
+        let mut hash_ring: HashRing<NodeInfo> = HashRing::new(nodes, 10);
+
+        hash_ring.remove_node(&node(15330));
+
+        // This should be num nodes * num replicas
+        assert_eq!(60, hash_ring.sorted_keys.len());
+        assert_eq!(60, hash_ring.ring.len());
+    }
+
+    #[test]
+    fn test_custom_hasher() {
+        #[derive(Default)]
+        struct ConstantHasher;
+
+        impl Hasher for ConstantHasher {
+            fn finish(&self) -> u64 {
+                1
+            }
+
+            fn write(&mut self, _bytes: &[u8]) {
+                // Do nothing
+            }
+        }
+
+        type ConstantBuildHasher = BuildHasherDefault<ConstantHasher>;
+
+        let nodes: Vec<NodeInfo> = vec![
+            node(15324),
+            node(15325),
+            node(15326),
+            node(15327),
+            node(15328),
+            node(15329),
+        ];
+
+        let hash_ring: HashRing<NodeInfo, ConstantBuildHasher> =
+            HashRing::with_hasher(nodes, 10, ConstantBuildHasher::default());
+
+        assert_eq!(Some(&node(15329)), hash_ring.get_node("hello"));
+        assert_eq!(Some(&node(15329)), hash_ring.get_node("dude"));
+        assert_eq!(Some(&node(15329)), hash_ring.get_node("two"));
+    }
 }
\ No newline at end of file
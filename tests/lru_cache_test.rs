@@ -1,69 +1,277 @@
-#[cfg(test)]
-mod tests {
-    use std::sync::{Arc, Mutex};
-    use pandas_pouch::lru::LRUCache;
-    use std::thread;
-    use std::time::Duration;
-
-    #[test]
-    fn test_lru_cache() {
-        let mut cache = LRUCache::new(2, None); // default expiration of 3600 seconds
-
-        cache.put(1, "a");
-        assert_eq!(cache.get(&1), Some("a"));
-        cache.put(2, "b");
-        assert_eq!(cache.get(&2), Some("b"));
-
-        cache.put(3, "c");
-        assert_eq!(cache.get(&1), None);
-        assert_eq!(cache.get(&2), Some("b"));
-        assert_eq!(cache.get(&3), Some("c"));
-
-        cache.put(4, "d");
-        assert_eq!(cache.get(&2), None);
-        assert_eq!(cache.get(&3), Some("c"));
-        assert_eq!(cache.get(&4), Some("d"));
-    }
-
-    #[test]
-    fn test_lru_cache_with_expiration() {
-        let mut cache = LRUCache::new(2, Some(Duration::from_secs(2)));
-
-        cache.put(1, "a");
-        assert_eq!(cache.get(&1), Some("a"));
-        cache.put(2, "b");
-        assert_eq!(cache.get(&2), Some("b"));
-
-        thread::sleep(Duration::from_secs(5));
-        assert_eq!(cache.get(&1), None);
-        assert_eq!(cache.get(&2), None);
-    }
-    
-    #[test]
-    fn test_thread_safety() {
-        let cache = LRUCache::new(100, Some(Duration::from_secs(1)));
-        let cache = Arc::new(Mutex::new(cache));
-        
-        let mut handles = vec![];
-        
-        for i in 0..10 {
-            let cache_clone = Arc::clone(&cache);
-            let handle = thread::spawn(move || {
-                let mut cache = cache_clone.lock().unwrap();
-                cache.put(i, i*2);
-                assert_eq!(cache.get(&i), Some(i*2));
-            });
-            handles.push(handle);
-        }
-        
-        for handle in handles {
-            handle.join().unwrap();
-        }
-        
-        // check that all values are still correct after all threads have finished
-        for i in 1..0 {
-            let mut cache = cache.lock().unwrap();
-            assert_eq!(cache.get(&i), Some(i*2));
-        }
-    }
-}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use bytes::Bytes;
+    use pandas_pouch::eviction::EvictionPolicyKind;
+    use pandas_pouch::lru::LRUCache;
+    use pandas_pouch::server::spawn_expiry_sweeper;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_lru_cache() {
+        let cache = LRUCache::new(2, None); // default expiration of 3600 seconds
+
+        cache.put(1, "a");
+        assert_eq!(cache.get(&1), Some("a"));
+        cache.put(2, "b");
+        assert_eq!(cache.get(&2), Some("b"));
+
+        cache.put(3, "c");
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some("b"));
+        assert_eq!(cache.get(&3), Some("c"));
+
+        cache.put(4, "d");
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&3), Some("c"));
+        assert_eq!(cache.get(&4), Some("d"));
+    }
+
+    #[test]
+    fn test_remove() {
+        let cache = LRUCache::new(2, None);
+
+        cache.put(1, "a");
+        cache.put(2, "b");
+        assert_eq!(cache.remove(1), Some((1, "a")));
+        assert_eq!(cache.remove(1), None);
+        assert_eq!(cache.get(&1), None);
+
+        // the removed entry does not take up capacity anymore
+        cache.put(3, "c");
+        assert_eq!(cache.get(&2), Some("b"));
+        assert_eq!(cache.get(&3), Some("c"));
+    }
+
+    #[test]
+    fn test_lru_cache_with_expiration() {
+        let cache = LRUCache::new(2, Some(Duration::from_secs(2)));
+
+        cache.put(1, "a");
+        assert_eq!(cache.get(&1), Some("a"));
+        cache.put(2, "b");
+        assert_eq!(cache.get(&2), Some("b"));
+
+        thread::sleep(Duration::from_secs(5));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), None);
+    }
+    
+    #[test]
+    fn test_put_with_ttl() {
+        let cache = LRUCache::new(3, None);
+
+        cache.put_with_ttl(1, "a", Some(Duration::from_millis(100)));
+        cache.put_with_ttl(2, "b", Some(Duration::from_secs(60)));
+        cache.put(3, "c");
+
+        let (_, ttl) = cache.get_with_ttl(&2).unwrap();
+        assert!(ttl <= Duration::from_secs(60) && ttl > Duration::from_secs(59));
+
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some("b"));
+        assert_eq!(cache.get(&3), Some("c"));
+
+        // a put without ttl goes back to the default expiry
+        cache.put_with_ttl(2, "b", Some(Duration::from_millis(100)));
+        cache.put(2, "b");
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(cache.get(&2), Some("b"));
+
+        // a ttl longer than the clock can tell does not overflow
+        cache.put_with_ttl(3, "c", Some(Duration::MAX));
+        assert!(cache.put_if_absent(4, "d", Some(Duration::MAX)));
+        assert_eq!(cache.get(&3), Some("c"));
+        assert_eq!(cache.get(&4), Some("d"));
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let cache = LRUCache::new(4, None);
+
+        cache.put_with_ttl(1, "a", Some(Duration::from_millis(50)));
+        cache.put_with_ttl(2, "b", Some(Duration::from_millis(50)));
+        cache.put_with_ttl(3, "c", Some(Duration::from_millis(50)));
+        cache.put(4, "d");
+        // updating the ttl moves the entry in the expiry order
+        cache.put_with_ttl(3, "c", Some(Duration::from_secs(60)));
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.purge_expired(1), 1);
+        assert_eq!(cache.purge_expired(10), 1);
+        assert_eq!(cache.purge_expired(10), 0);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.expired(), 2);
+
+        // the reclaimed capacity is used without evicting the live entries
+        cache.put(5, "e");
+        cache.put(6, "f");
+        assert_eq!(cache.get(&3), Some("c"));
+        assert_eq!(cache.get(&4), Some("d"));
+    }
+
+    #[test]
+    fn test_weigher() {
+        let cache = LRUCache::new(10, None).with_weigher(|key: &String, value: &String| key.len() + value.len());
+
+        cache.put("a".to_string(), "1234".to_string());
+        cache.put("b".to_string(), "1234".to_string());
+        assert_eq!(cache.weight(), 10);
+
+        // evicting until the new entry fits, a count based capacity of 10 would have kept them all
+        cache.put("c".to_string(), "12".to_string());
+        assert_eq!(cache.get(&"a".to_string()), None);
+        assert_eq!(cache.get(&"b".to_string()), Some("1234".to_string()));
+        assert_eq!(cache.weight(), 8);
+
+        // an entry heavier than the whole capacity is not cached, and replaces nothing
+        cache.put("d".to_string(), "12345678901".to_string());
+        assert_eq!(cache.get(&"d".to_string()), None);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.stats().evictions, 2);
+
+        // growing an entry evicts the others
+        cache.put("c".to_string(), "12345678".to_string());
+        assert_eq!(cache.get(&"b".to_string()), None);
+        assert_eq!(cache.weight(), 9);
+    }
+
+    #[test]
+    fn test_max_entry_weight() {
+        // the segments are sized to hold the heaviest entry, however many threads there are
+        let cache = LRUCache::with_max_entry_weight(64 * 1024, None, EvictionPolicyKind::Lru, 20_000)
+            .with_weigher(|_: &u32, value: &Vec<u8>| value.len());
+        for i in 0..10 {
+            cache.put(i, vec![0; 20_000]);
+            assert_eq!(cache.get(&i).map(|value| value.len()), Some(20_000));
+        }
+        assert_eq!(cache.stats().evictions, 10 - cache.len() as u64);
+    }
+
+    #[test]
+    fn test_stats() {
+        let cache = LRUCache::new(2, None);
+        cache.put(1, "a");
+        cache.put(2, "b");
+        cache.put_with_ttl(3, "c", Some(Duration::from_millis(10)));
+        assert_eq!(cache.get(&2), Some("b"));
+        assert_eq!(cache.get(&1), None);
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(cache.get(&3), None);
+
+        // peeking counts nothing
+        assert_eq!(cache.peek_with_ttl(&2).map(|(value, _)| value), Some("b"));
+        assert_eq!(cache.peek_with_ttl(&1), None);
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.expired, 1);
+        assert_eq!(stats.entries, 1);
+        assert_eq!(stats.weight, 1);
+        assert_eq!(stats.capacity, 2);
+    }
+
+    #[test]
+    fn test_batches() {
+        let cache = LRUCache::with_segments(1000, None, 8, EvictionPolicyKind::Lru);
+        cache.put_many_with_ttl((0..100).map(|i| (i, i * 2, None)).collect());
+        cache.put_many_with_ttl(vec![(100, 200, Some(Duration::from_millis(10)))]);
+        assert_eq!(cache.len(), 101);
+        thread::sleep(Duration::from_millis(20));
+
+        // the values are in the order of the keys, a key asked twice is found twice
+        let keys = vec![99, 3, 500, 3, 100];
+        let values: Vec<Option<i32>> = cache.get_many_with_ttl(&keys)
+            .into_iter()
+            .map(|found| found.map(|(value, _)| value))
+            .collect();
+        assert_eq!(values, vec![Some(198), Some(6), None, Some(6), None]);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.expired), (3, 2, 1));
+        assert!(cache.get_many_with_ttl(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expiry_sweeper() {
+        let cache = Arc::new(LRUCache::new(100, None));
+        for i in 0..50 {
+            cache.put_with_ttl(i.to_string(), Bytes::from_static(b"short"), Some(Duration::from_millis(50)));
+        }
+        cache.put("50".to_string(), Bytes::from_static(b"long"));
+
+        let sweeper = spawn_expiry_sweeper(Arc::clone(&cache), Duration::from_millis(20));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        sweeper.abort();
+
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.expired(), 50);
+    }
+
+    #[test]
+    fn test_thread_safety() {
+        let cache = Arc::new(LRUCache::new(100, Some(Duration::from_secs(1))));
+        
+        let mut handles = vec![];
+        
+        for i in 0..10 {
+            let cache_clone = Arc::clone(&cache);
+            let handle = thread::spawn(move || {
+                cache_clone.put(i, i*2);
+                assert_eq!(cache_clone.get(&i), Some(i*2));
+            });
+            handles.push(handle);
+        }
+        
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        
+        // check that all values are still correct after all threads have finished
+        for i in 0..10 {
+            assert_eq!(cache.get(&i), Some(i*2));
+        }
+    }
+
+    #[test]
+    fn test_concurrent_segments() {
+        let cache = Arc::new(LRUCache::with_segments(800, None, 8, EvictionPolicyKind::Lru));
+
+        // each thread works on its own keys, without any lock around the cache
+        thread::scope(|scope| {
+            for t in 0..8 {
+                let cache = &cache;
+                scope.spawn(move || {
+                    for i in 0..100 {
+                        let key = t * 100 + i;
+                        cache.put(key, key * 2);
+                        assert_eq!(cache.get(&key), Some(key * 2));
+                    }
+                });
+            }
+        });
+
+        // the capacity is split between the segments, so a segment may evict before the cache is full
+        assert!(cache.len() <= 800);
+        assert_eq!(cache.capacity(), 800);
+        for (key, value) in cache.print() {
+            assert_eq!(value, key * 2);
+        }
+    }
+
+    #[test]
+    fn test_more_segments_than_capacity() {
+        // no segment is left without room, so every put is cached
+        let cache = LRUCache::with_segments(5, None, 64, EvictionPolicyKind::Lru);
+        for i in 0..100 {
+            cache.put(i, i * 2);
+            assert_eq!(cache.get(&i), Some(i * 2));
+        }
+        assert!(cache.len() <= 5);
+    }
+}
@@ -0,0 +1,130 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use pandas_pouch::client::Client;
+    use pandas_pouch::cluster::Cluster;
+    use pandas_pouch::hash_ring::NodeInfo;
+    use pandas_pouch::lru::{CacheStats, LRUCache};
+    use pandas_pouch::metrics::{spawn_metrics_server, Metrics, MetricsLayer};
+    use pandas_pouch::pandas_pouch::pandas_pouch_cache_service_server::PandasPouchCacheServiceServer;
+    use pandas_pouch::server::CacheServiceImpl;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::transport::Server;
+
+    async fn scrape(port: u16, path: &str) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node = NodeInfo::new("127.0.0.1", listener.local_addr().unwrap().port());
+        let cache = Arc::new(LRUCache::new(1000, None));
+        let cluster = Arc::new(Cluster::new(node.clone(), Vec::new(), 10));
+        let metrics = Arc::new(Metrics::new());
+        let service = CacheServiceImpl::new(Arc::clone(&cache), None, Arc::clone(&cluster))
+            .with_metrics(Arc::clone(&metrics));
+
+        let layer = MetricsLayer::new(Arc::clone(&metrics));
+        tokio::spawn(async move {
+            Server::builder()
+                .layer(layer)
+                .add_service(PandasPouchCacheServiceServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+                .unwrap();
+        });
+
+        let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metrics_port = metrics_listener.local_addr().unwrap().port();
+        let render = {
+            let (metrics, cache, cluster) = (Arc::clone(&metrics), Arc::clone(&cache), Arc::clone(&cluster));
+            move || metrics.render(&cache.stats(), cluster.members().len(), None)
+        };
+        let metrics_server = spawn_metrics_server(metrics_listener, render);
+
+        let mut client = Client::new(&node.host, node.port).await.unwrap();
+        client.put("key1".to_string(), "value1".to_string()).await.unwrap();
+        client.get("key1".to_string()).await.unwrap();
+        client.get("key2".to_string()).await.unwrap();
+
+        let response = scrape(metrics_port, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        for line in [
+            "pandas_pouch_rpc_requests_total{method=\"Get\"} 2",
+            "pandas_pouch_rpc_requests_total{method=\"Put\"} 1",
+            "pandas_pouch_rpc_errors_total{method=\"Get\"} 0",
+            "pandas_pouch_rpc_duration_seconds_count{method=\"Get\"} 2",
+            "pandas_pouch_rpc_duration_seconds_bucket{method=\"Put\",le=\"+Inf\"} 1",
+            "pandas_pouch_cache_hits_total 1",
+            "pandas_pouch_cache_misses_total 1",
+            "pandas_pouch_cache_entries 1",
+            "pandas_pouch_cluster_members 1",
+        ] {
+            assert!(response.lines().any(|l| l == line), "missing {} in\n{}", line, response);
+        }
+
+        let response = scrape(metrics_port, "/other").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"), "{}", response);
+        metrics_server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_idle_scrapes_are_closed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let metrics_server = spawn_metrics_server(listener, || "pandas_pouch_cluster_members 1\n".to_string());
+
+        // connections that never send a request, as many as are served at once
+        let mut idle = Vec::new();
+        for _ in 0..32 {
+            idle.push(TcpStream::connect(("127.0.0.1", port)).await.unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // one more is closed right away
+        let mut rejected = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut response = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(1), rejected.read_to_end(&mut response)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "{:?}", read);
+
+        // and the idle ones once they timed out, after which scrapes are answered again
+        for mut stream in idle {
+            let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await;
+            assert!(read.is_ok(), "an idle connection was kept open");
+        }
+        let response = scrape(port, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        metrics_server.abort();
+    }
+
+    #[test]
+    fn test_render_histograms() {
+        let metrics = Metrics::new();
+        metrics.record_db("get", Duration::from_micros(300), false);
+        metrics.record_db("get", Duration::from_millis(20), true);
+        metrics.record_db("get", Duration::from_secs(30), false);
+
+        let rendered = metrics.render(&CacheStats::default(), 3, None);
+        for line in [
+            "pandas_pouch_db_errors_total{operation=\"get\"} 1",
+            "pandas_pouch_db_duration_seconds_bucket{operation=\"get\",le=\"0.0005\"} 1",
+            "pandas_pouch_db_duration_seconds_bucket{operation=\"get\",le=\"0.01\"} 1",
+            "pandas_pouch_db_duration_seconds_bucket{operation=\"get\",le=\"0.025\"} 2",
+            "pandas_pouch_db_duration_seconds_bucket{operation=\"get\",le=\"10\"} 2",
+            "pandas_pouch_db_duration_seconds_bucket{operation=\"get\",le=\"+Inf\"} 3",
+            "pandas_pouch_db_duration_seconds_count{operation=\"get\"} 3",
+            "pandas_pouch_cluster_members 3",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "missing {} in\n{}", line, rendered);
+        }
+    }
+}
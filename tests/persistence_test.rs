@@ -0,0 +1,175 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use bytes::Bytes;
+    use pandas_pouch::client::Client;
+    use pandas_pouch::config::PersistenceMode;
+    use pandas_pouch::db::{MemoryStorage, Storage};
+    use pandas_pouch::error::PouchError;
+    use pandas_pouch::hash_ring::NodeInfo;
+    use pandas_pouch::lru::LRUCache;
+    use pandas_pouch::pandas_pouch::PersistenceMode as RequestedPersistence;
+    use pandas_pouch::server::CacheServiceImpl;
+    use pandas_pouch::write_behind::WriteBehind;
+    use crate::common::{serve, standalone};
+
+    struct TestNode {
+        node: NodeInfo,
+        cache: Arc<LRUCache<String, Bytes>>,
+        storage: Arc<dyn Storage>,
+        write_behind: Arc<WriteBehind>,
+        client: Client,
+    }
+
+    impl TestNode {
+        async fn stored(&self, key: &str) -> Option<String> {
+            self.storage.get(key).await.unwrap().map(|(value, _)| String::from_utf8(value).unwrap())
+        }
+
+        fn cached(&self, key: &str) -> Option<String> {
+            self.cache.get(&key.to_string()).map(|value| String::from_utf8(value.to_vec()).unwrap())
+        }
+
+        async fn client_with(&self, persistence: RequestedPersistence) -> Client {
+            Client::new(&self.node.host, self.node.port).await.unwrap().with_persistence(persistence)
+        }
+    }
+
+    // Serves a node backed by a memory storage, with a write-behind queue for it.
+    async fn start_node(persistence: PersistenceMode) -> TestNode {
+        let cache = Arc::new(LRUCache::new(1000, None));
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let write_behind = Arc::new(WriteBehind::new(Arc::clone(&storage), 100, 10));
+        let node = serve(|node| {
+            CacheServiceImpl::new(Arc::clone(&cache), Some(Arc::clone(&storage)), standalone(node))
+                .with_persistence(persistence)
+                .with_write_behind(Arc::clone(&write_behind))
+        }).await;
+
+        let client = Client::new(&node.host, node.port).await.unwrap();
+        TestNode { node, cache, storage, write_behind, client }
+    }
+
+    #[tokio::test]
+    async fn test_none() {
+        let mut node = start_node(PersistenceMode::None).await;
+        node.storage.put("stored", b"value", None).await.unwrap();
+
+        node.client.put("key1".to_string(), "value1".to_string()).await.unwrap();
+        assert_eq!(node.cached("key1"), Some("value1".to_string()));
+        assert_eq!(node.stored("key1").await, None);
+        assert_eq!(node.client.get("stored".to_string()).await.unwrap(), None, "misses are not loaded");
+
+        node.storage.put("key1", b"value1", None).await.unwrap();
+        assert!(node.client.delete("key1".to_string()).await.unwrap());
+        assert_eq!(node.stored("key1").await, Some("value1".to_string()), "deletes do not reach the storage");
+        assert!(!node.client.delete("stored".to_string()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_read_through() {
+        let mut node = start_node(PersistenceMode::ReadThrough).await;
+        node.storage.put("stored", b"value", None).await.unwrap();
+
+        assert_eq!(node.client.get("stored".to_string()).await.unwrap(), Some("value".to_string()));
+        assert_eq!(node.cached("stored"), Some("value".to_string()));
+
+        node.client.put("key1".to_string(), "value1".to_string()).await.unwrap();
+        assert_eq!(node.cached("key1"), Some("value1".to_string()));
+        assert_eq!(node.stored("key1").await, None);
+
+        // the storage keeps the value, which the next miss loads again
+        assert!(node.client.delete("stored".to_string()).await.unwrap());
+        assert_eq!(node.stored("stored").await, Some("value".to_string()));
+        assert_eq!(node.client.get("stored".to_string()).await.unwrap(), Some("value".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_write_through() {
+        let mut node = start_node(PersistenceMode::WriteThrough).await;
+
+        node.client.put("key1".to_string(), "value1".to_string()).await.unwrap();
+        assert_eq!(node.cached("key1"), Some("value1".to_string()));
+        assert_eq!(node.stored("key1").await, Some("value1".to_string()));
+
+        node.cache.remove("key1".to_string());
+        assert_eq!(node.client.get("key1".to_string()).await.unwrap(), Some("value1".to_string()));
+
+        assert!(node.client.delete("key1".to_string()).await.unwrap());
+        assert_eq!(node.stored("key1").await, None);
+        assert_eq!(node.client.get("key1".to_string()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_write_behind() {
+        let mut node = start_node(PersistenceMode::WriteBehind).await;
+
+        node.client.put("key1".to_string(), "value1".to_string()).await.unwrap();
+        assert_eq!(node.cached("key1"), Some("value1".to_string()));
+        assert_eq!(node.stored("key1").await, None);
+        assert_eq!(node.write_behind.depth(), 1);
+
+        node.write_behind.flush().await.unwrap();
+        assert_eq!(node.stored("key1").await, Some("value1".to_string()));
+
+        assert!(node.client.delete("key1".to_string()).await.unwrap());
+        assert_eq!(node.stored("key1").await, Some("value1".to_string()));
+        assert_eq!(node.client.get("key1".to_string()).await.unwrap(), None, "the queued delete hides the stored value");
+        node.write_behind.flush().await.unwrap();
+        assert_eq!(node.stored("key1").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_write_around() {
+        let mut node = start_node(PersistenceMode::WriteAround).await;
+
+        node.client.put("key1".to_string(), "value1".to_string()).await.unwrap();
+        assert_eq!(node.cached("key1"), None);
+        assert_eq!(node.stored("key1").await, Some("value1".to_string()));
+
+        // a read loads the value into the cache, and the next write evicts it again
+        assert_eq!(node.client.get("key1".to_string()).await.unwrap(), Some("value1".to_string()));
+        assert_eq!(node.cached("key1"), Some("value1".to_string()));
+        node.client.put("key1".to_string(), "value2".to_string()).await.unwrap();
+        assert_eq!(node.cached("key1"), None);
+        assert_eq!(node.client.get("key1".to_string()).await.unwrap(), Some("value2".to_string()));
+
+        assert!(node.client.delete("key1".to_string()).await.unwrap());
+        assert_eq!(node.stored("key1").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_request_override() {
+        let mut node = start_node(PersistenceMode::WriteThrough).await;
+        let mut cache_only = node.client_with(RequestedPersistence::PersistenceNone).await;
+        let mut write_behind = node.client_with(RequestedPersistence::PersistenceWriteBehind).await;
+
+        cache_only.put("key1".to_string(), "value1".to_string()).await.unwrap();
+        assert_eq!(node.stored("key1").await, None);
+
+        write_behind.put("key2".to_string(), "value2".to_string()).await.unwrap();
+        assert_eq!(node.stored("key2").await, None);
+        assert_eq!(node.write_behind.depth(), 1);
+
+        // a write-through put is not overwritten by the older queued write once it is flushed
+        node.client.put("key2".to_string(), "value3".to_string()).await.unwrap();
+        assert_eq!(node.stored("key2").await, Some("value3".to_string()));
+        node.write_behind.flush().await.unwrap();
+        assert_eq!(node.stored("key2").await, Some("value3".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_override_needs_storage() {
+        let node = serve(|node| CacheServiceImpl::new(Arc::new(LRUCache::new(1000, None)), None, standalone(node))).await;
+        let mut client = Client::new(&node.host, node.port).await.unwrap();
+        client.put("key1".to_string(), "value1".to_string()).await.unwrap();
+        assert_eq!(client.get("key1".to_string()).await.unwrap(), Some("value1".to_string()));
+
+        let mut client = client.with_persistence(RequestedPersistence::PersistenceWriteThrough);
+        let error = client.put("key2".to_string(), "value2".to_string()).await.unwrap_err();
+        assert_eq!(error.code(), tonic::Code::FailedPrecondition);
+        assert!(matches!(error, PouchError::NotConfigured(_)));
+    }
+}
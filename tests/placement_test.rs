@@ -0,0 +1,122 @@
+#[cfg(test)]
+mod tests {
+    use pandas_pouch::hash_ring::{HashRing, NodeInfo, XxHash64Hasher};
+    use pandas_pouch::placement::{JumpHash, Maglev, Placement, Rendezvous};
+
+    const KEYS: usize = 50_000;
+
+    fn node(port: u16) -> NodeInfo {
+        NodeInfo::new("localhost", port)
+    }
+
+    fn nodes(count: u16) -> Vec<NodeInfo> {
+        (15324..15324 + count).map(node).collect()
+    }
+
+    fn owners(placement: &dyn Placement<NodeInfo>) -> Vec<NodeInfo> {
+        (0..KEYS).map(|i| placement.get_node(&format!("key{}", i)).unwrap().clone()).collect()
+    }
+
+    // the largest relative distance of a node's share of the keys to an even share
+    fn imbalance(placement: &dyn Placement<NodeInfo>, nodes: &[NodeInfo]) -> f64 {
+        let owners = owners(placement);
+        let even = KEYS as f64 / nodes.len() as f64;
+        nodes.iter()
+            .map(|n| (owners.iter().filter(|owner| *owner == n).count() as f64 - even).abs() / even)
+            .fold(0.0, f64::max)
+    }
+
+    fn moved(before: &[NodeInfo], after: &[NodeInfo]) -> Vec<(NodeInfo, NodeInfo)> {
+        before.iter().zip(after)
+            .filter(|(old, new)| old != new)
+            .map(|(old, new)| (old.clone(), new.clone()))
+            .collect()
+    }
+
+    // the behaviour every placement must have: the balance is within the given imbalance, and adding a node moves
+    // about its share of the keys, all to it when minimal is set, while removing it moves them back
+    fn check_placement(mut placement: Box<dyn Placement<NodeInfo>>, max_imbalance: f64, minimal: bool) {
+        let mut members = nodes(10);
+        assert_eq!(10, placement.node_count());
+        let imbalance = imbalance(placement.as_ref(), &members);
+        assert!(imbalance < max_imbalance, "imbalance {}", imbalance);
+
+        let replicas = placement.get_nodes("hello", 3);
+        assert_eq!(Some(replicas[0]), placement.get_node("hello"));
+        assert!(replicas[0] != replicas[1] && replicas[0] != replicas[2] && replicas[1] != replicas[2]);
+        assert_eq!(10, placement.get_nodes("hello", 20).len());
+
+        let before = owners(placement.as_ref());
+        placement.add_node(&node(15340));
+        placement.add_node(&node(15340));
+        members.push(node(15340));
+        assert_eq!(11, placement.node_count());
+        let after = owners(placement.as_ref());
+        let moved_to_new = moved(&before, &after).iter().filter(|(_, new)| *new == node(15340)).count();
+        let moved_total = moved(&before, &after).len();
+        let share = KEYS as f64 / 11.0;
+        assert!((moved_to_new as f64 - share).abs() < share * max_imbalance, "{} keys moved to the new node", moved_to_new);
+        if minimal {
+            assert_eq!(moved_to_new, moved_total);
+        } else {
+            assert!((moved_total - moved_to_new) < KEYS / 100, "{} keys moved between the other nodes", moved_total - moved_to_new);
+        }
+
+        placement.remove_node(&node(15340));
+        assert_eq!(10, placement.node_count());
+        let removed = owners(placement.as_ref());
+        assert!(moved(&before, &removed).len() < KEYS / 100);
+        if minimal {
+            assert_eq!(before, removed);
+        }
+        placement.remove_node(&node(15399));
+        assert_eq!(10, placement.node_count());
+
+        for n in nodes(10) {
+            placement.remove_node(&n);
+        }
+        assert_eq!(None, placement.get_node("hello"));
+        assert!(placement.get_nodes("hello", 3).is_empty());
+    }
+
+    #[test]
+    fn test_hash_ring() {
+        check_placement(Box::new(HashRing::new(nodes(10), 200)), 0.25, true);
+    }
+
+    #[test]
+    fn test_rendezvous() {
+        check_placement(Box::new(Rendezvous::new(nodes(10))), 0.05, true);
+    }
+
+    #[test]
+    fn test_jump_hash() {
+        check_placement(Box::new(JumpHash::new(nodes(10))), 0.05, true);
+    }
+
+    #[test]
+    fn test_maglev() {
+        check_placement(Box::new(Maglev::new(nodes(10))), 0.05, false);
+    }
+
+    #[test]
+    fn test_maglev_table_size() {
+        // a table size that is not a prime is rounded up to one, so that the table can be filled
+        let maglev = Maglev::with_hasher(nodes(10), 1000, XxHash64Hasher::default());
+        assert_eq!(1009, maglev.table_size());
+        assert_eq!(10, maglev.get_nodes("key1", usize::MAX).len());
+        assert_eq!(2, Maglev::with_hasher(nodes(1), 0, XxHash64Hasher::default()).table_size());
+    }
+
+    #[test]
+    fn test_jump_hash_removes_in_the_middle() {
+        let mut jump = JumpHash::new(nodes(10));
+        let before = owners(&jump);
+        jump.remove_node(&node(15324 + 4));
+
+        // the keys of the removed node move, and so do some of the renumbered nodes after it
+        let moved = moved(&before, &owners(&jump));
+        assert!(moved.iter().all(|(old, _)| old.port >= 15324 + 4));
+        assert!(moved.iter().all(|(_, new)| *new != node(15324 + 4)));
+    }
+}
@@ -0,0 +1,69 @@
+#[cfg(test)]
+mod tests {
+    use pandas_pouch::hash_ring::{HashRing, NodeInfo};
+    use pandas_pouch::rebalance::moved_ranges;
+
+    fn node(port: u16) -> NodeInfo {
+        NodeInfo::new("localhost", port)
+    }
+
+    // Checks the moved ranges against the owner of each key in both rings.
+    fn assert_ranges_match_owners(old: &HashRing<NodeInfo>, new: &HashRing<NodeInfo>) {
+        let ranges = moved_ranges(old, new);
+        for i in 0..5000 {
+            let key = format!("key{}", i);
+            let position = new.position(&key);
+            let old_owner = old.get_node(key.clone()).unwrap();
+            let new_owner = new.get_node(key.clone()).unwrap();
+
+            let containing: Vec<_> = ranges.iter().filter(|range| range.contains(position)).collect();
+            if old_owner == new_owner {
+                assert!(containing.is_empty(), "{} did not move but is in {:?}", key, containing);
+            } else {
+                assert_eq!(1, containing.len(), "{} moved but is in {:?}", key, containing);
+                assert_eq!(old_owner, &containing[0].from);
+                assert_eq!(new_owner, &containing[0].to);
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_ranges_for_same_ring() {
+        let ring = HashRing::new(vec![node(15324), node(15325), node(15326)], 10);
+        assert!(moved_ranges(&ring, &ring.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_ranges_on_added_node() {
+        let old = HashRing::new(vec![node(15324), node(15325), node(15326)], 10);
+        let mut new = old.clone();
+        new.add_node(&node(15327));
+
+        let ranges = moved_ranges(&old, &new);
+        assert!(!ranges.is_empty());
+        assert!(ranges.iter().all(|range| range.to == node(15327)));
+        assert_ranges_match_owners(&old, &new);
+    }
+
+    #[test]
+    fn test_ranges_on_removed_node() {
+        let old = HashRing::new(vec![node(15324), node(15325), node(15326), node(15327)], 10);
+        let mut new = old.clone();
+        new.remove_node(&node(15325));
+
+        let ranges = moved_ranges(&old, &new);
+        assert!(!ranges.is_empty());
+        assert!(ranges.iter().all(|range| range.from == node(15325)));
+        assert_ranges_match_owners(&old, &new);
+    }
+
+    #[test]
+    fn test_single_node_ring_hands_everything_off() {
+        let old = HashRing::new(vec![node(15324)], 10);
+        let new = HashRing::new(vec![node(15325)], 10);
+
+        let ranges = moved_ranges(&old, &new);
+        assert_ranges_match_owners(&old, &new);
+        assert!(ranges.iter().all(|range| range.from == node(15324) && range.to == node(15325)));
+    }
+}
@@ -0,0 +1,216 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use pandas_pouch::config::PersistenceMode;
+    use pandas_pouch::db::{MemoryStorage, Storage};
+    use pandas_pouch::error::PouchError;
+    use pandas_pouch::lru::LRUCache;
+    use pandas_pouch::server::CacheServiceImpl;
+    use pandas_pouch::write_behind::WriteBehind;
+    use tonic::async_trait;
+    use crate::common::{standalone, start_node};
+
+    // a memory storage that rejects every write while it is failing
+    #[derive(Default)]
+    struct FlakyStorage {
+        inner: MemoryStorage,
+        failing: AtomicBool,
+    }
+
+    impl FlakyStorage {
+        fn failure(&self) -> Option<PouchError> {
+            self.failing.load(Ordering::Relaxed).then(|| PouchError::Database("pool timed out".to_string()))
+        }
+    }
+
+    #[async_trait]
+    impl Storage for FlakyStorage {
+        async fn get(&self, key: &str) -> Result<Option<(Vec<u8>, Option<Duration>)>, PouchError> {
+            self.inner.get(key).await
+        }
+
+        async fn put(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<(), PouchError> {
+            if let Some(e) = self.failure() {
+                return Err(e);
+            }
+            self.inner.put(key, value, ttl).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<bool, PouchError> {
+            if let Some(e) = self.failure() {
+                return Err(e);
+            }
+            self.inner.delete(key).await
+        }
+
+        async fn scan(&self, after: Option<&str>, limit: usize) -> Result<Vec<(String, Vec<u8>, Option<Duration>)>, PouchError> {
+            self.inner.scan(after, limit).await
+        }
+    }
+
+    // a value without expiry, as queued or as stored
+    fn value<V: From<&'static str>>(value: &'static str) -> Option<(V, Option<Duration>)> {
+        Some((value.into(), None))
+    }
+
+    #[tokio::test]
+    async fn test_coalescing() {
+        let storage = Arc::new(MemoryStorage::new());
+        let write_behind = WriteBehind::new(storage.clone(), 100, 10);
+
+        for i in 0..5 {
+            write_behind.put("key1".to_string(), format!("value{}", i).into(), None).await;
+        }
+        write_behind.put("key2".to_string(), "value".into(), None).await;
+        write_behind.delete("key2".to_string()).await;
+        assert_eq!(write_behind.depth(), 2);
+        assert_eq!(write_behind.lookup("key1"), Some(value("value4")));
+        assert_eq!(write_behind.lookup("key2"), Some(None));
+        assert_eq!(write_behind.lookup("key3"), None);
+        assert_eq!(storage.get("key1").await.unwrap(), None, "nothing is written before a flush");
+
+        assert_eq!(write_behind.flush().await.unwrap(), 2);
+        assert_eq!(write_behind.depth(), 0);
+        assert_eq!(write_behind.lookup("key1"), None);
+        assert_eq!(storage.get("key1").await.unwrap(), value("value4"));
+        assert_eq!(storage.get("key2").await.unwrap(), None);
+
+        let stats = write_behind.stats();
+        assert_eq!((stats.flushed, stats.coalesced, stats.failed), (2, 5, 0));
+    }
+
+    #[tokio::test]
+    async fn test_batches_keep_order() {
+        let storage = Arc::new(MemoryStorage::new());
+        let write_behind = WriteBehind::new(storage.clone(), 100, 3);
+        for i in 0..7 {
+            write_behind.put(format!("key{}", i), "value".into(), None).await;
+        }
+
+        // the oldest writes are flushed first
+        assert_eq!(write_behind.flush_batch().await.unwrap(), 3);
+        for i in 0..7 {
+            assert_eq!(storage.get(&format!("key{}", i)).await.unwrap().is_some(), i < 3);
+        }
+        assert_eq!(write_behind.depth(), 4);
+        assert_eq!(write_behind.flush().await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let storage = Arc::new(MemoryStorage::new());
+        let write_behind = Arc::new(WriteBehind::new(storage.clone(), 2, 10));
+        write_behind.put("key1".to_string(), "value1".into(), None).await;
+        write_behind.put("key2".to_string(), "value2".into(), None).await;
+
+        // a queued key is coalesced without waiting for room
+        tokio::time::timeout(Duration::from_millis(50), write_behind.put("key1".to_string(), "value3".into(), None))
+            .await
+            .expect("coalesced put waited for room");
+
+        let blocked = {
+            let write_behind = Arc::clone(&write_behind);
+            tokio::spawn(async move { write_behind.put("key3".to_string(), "value3".into(), None).await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished(), "put into a full queue did not wait");
+        assert_eq!(write_behind.depth(), 2);
+
+        write_behind.flush_batch().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), blocked).await.unwrap().unwrap();
+        assert_eq!(write_behind.lookup("key3"), Some(value("value3")));
+        write_behind.flush().await.unwrap();
+        assert_eq!(storage.get("key1").await.unwrap(), value("value3"));
+        assert_eq!(storage.get("key3").await.unwrap(), value("value3"));
+    }
+
+    #[tokio::test]
+    async fn test_failed_flush_is_retried() {
+        let storage = Arc::new(FlakyStorage::default());
+        let write_behind = WriteBehind::new(storage.clone(), 100, 10);
+        write_behind.put("key1".to_string(), "value1".into(), None).await;
+
+        storage.failing.store(true, Ordering::Relaxed);
+        assert!(write_behind.flush().await.is_err());
+        assert_eq!(write_behind.depth(), 1);
+        assert_eq!(write_behind.lookup("key1"), Some(value("value1")));
+
+        // a newer write supersedes the one that failed
+        write_behind.put("key1".to_string(), "value2".into(), None).await;
+        storage.failing.store(false, Ordering::Relaxed);
+        assert_eq!(write_behind.flush().await.unwrap(), 1);
+        assert_eq!(storage.get("key1").await.unwrap(), value("value2"));
+        assert_eq!(write_behind.stats().failed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_expired_put_deletes() {
+        let storage = Arc::new(MemoryStorage::new());
+        storage.put("key1", b"old", None).await.unwrap();
+        let write_behind = WriteBehind::new(storage.clone(), 100, 10);
+
+        write_behind.put("key1".to_string(), "new".into(), Some(Duration::from_millis(20))).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(write_behind.lookup("key1"), Some(None));
+        write_behind.flush().await.unwrap();
+        assert_eq!(storage.get("key1").await.unwrap(), None);
+
+        // a ttl longer than the clock can tell never expires
+        write_behind.put("key2".to_string(), "forever".into(), Some(Duration::MAX)).await;
+        assert_eq!(write_behind.lookup("key2"), Some(value("forever")));
+        write_behind.flush().await.unwrap();
+        assert_eq!(storage.get("key2").await.unwrap(), value("forever"));
+    }
+
+    #[tokio::test]
+    async fn test_flusher() {
+        let storage = Arc::new(MemoryStorage::new());
+        let write_behind = Arc::new(WriteBehind::new(storage.clone(), 100, 10));
+        let flusher = write_behind.spawn(Duration::from_millis(20));
+
+        write_behind.put("key1".to_string(), "value1".into(), None).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(write_behind.depth(), 0);
+        assert_eq!(storage.get("key1").await.unwrap(), value("value1"));
+        flusher.abort();
+    }
+
+    #[tokio::test]
+    async fn test_server_write_behind() {
+        let cache = Arc::new(LRUCache::new(1000, None));
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let write_behind = Arc::new(WriteBehind::new(Arc::clone(&storage), 100, 10));
+        let mut client = start_node(|node| {
+            CacheServiceImpl::new(Arc::clone(&cache), Some(Arc::clone(&storage)), standalone(node))
+                .with_persistence(PersistenceMode::WriteBehind)
+                .with_write_behind(Arc::clone(&write_behind))
+        }).await;
+
+        client.put("key1".to_string(), "value1".to_string()).await.unwrap();
+        assert_eq!(storage.get("key1").await.unwrap(), None);
+        assert_eq!(client.stats().await.unwrap().write_behind_depth, 1);
+
+        // a miss is served from the queue, which is newer than the storage
+        storage.put("key1", b"stale", None).await.unwrap();
+        cache.remove("key1".to_string());
+        assert_eq!(client.get("key1".to_string()).await.unwrap(), Some("value1".to_string()));
+
+        write_behind.flush().await.unwrap();
+        assert_eq!(storage.get("key1").await.unwrap(), value("value1"));
+        assert_eq!(client.stats().await.unwrap().write_behind_depth, 0);
+
+        // a queued delete does not read the storage, so a key only there is not reported as found, but is deleted
+        cache.remove("key1".to_string());
+        assert!(!client.delete("key1".to_string()).await.unwrap());
+        assert_eq!(client.get("key1".to_string()).await.unwrap(), None);
+        client.put("key2".to_string(), "value2".to_string()).await.unwrap();
+        cache.remove("key2".to_string());
+        assert!(client.delete("key2".to_string()).await.unwrap(), "a queued put is found");
+        write_behind.flush().await.unwrap();
+        assert_eq!(storage.get("key1").await.unwrap(), None);
+    }
+}